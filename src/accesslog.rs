@@ -0,0 +1,464 @@
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use config::Config;
+use lazy_static::lazy_static;
+use log::{error, log, warn, Level};
+
+use crate::{LOG_ACCESS, LOG_SERVER};
+
+/// Receives each batch of access log entries and the levels to log them at, oldest first
+pub type Sink = Box<dyn FnMut(&[(Level, String)]) + Send>;
+
+lazy_static!
+{
+	/// The buffer access log entries go through, when buffering is turned on. Workers only ever take the read lock, so they don't wait on each other.
+	static ref ACCESS_LOG: RwLock<Option<AccessLogBuffer>> = RwLock::new(None);
+
+	/// The level to log responses at for each status class, from 1xx at index 0 to 5xx at index 4
+	static ref LEVELS: RwLock<[Level; 5]> = RwLock::new(DEFAULT_LEVELS);
+}
+
+const DEFAULT_LEVELS: [Level; 5] = [Level::Info, Level::Info, Level::Info, Level::Warn, Level::Error];
+
+/// Picks which successful requests make it into the access log
+static SAMPLER: Sampler = Sampler::new(1);
+
+/**
+Keeps one in every N access log entries for responses below 400, and every
+entry for errors, so a busy server's log stays a manageable size without
+losing any failures. Counting is a single atomic add, so workers never wait
+on each other.
+*/
+pub struct Sampler
+{
+	rate: AtomicU64,
+	seen: AtomicU64
+}
+
+impl Sampler
+{
+	/**
+	# Parameters
+	- `rate`: Keep one in this many successful entries. 0 and 1 both keep them all.
+	*/
+	pub const fn new(rate: u64) -> Sampler
+	{
+		Sampler{rate: AtomicU64::new(rate), seen: AtomicU64::new(0)}
+	}
+
+	/**
+	Change how many successful entries are kept.
+
+	# Parameters
+	- `rate`: Keep one in this many successful entries. 0 and 1 both keep them all.
+	*/
+	pub fn set_rate(&self, rate: u64)
+	{
+		self.rate.store(rate, Ordering::Relaxed);
+	}
+
+	/**
+	# Parameters
+	- `status`: The status code of the response the entry is about
+
+	# Returns
+	true if the entry should be logged
+
+	# Examples
+	```
+	use c20web::accesslog::Sampler;
+
+	let sampler = Sampler::new(3);
+	let kept = (0..9).filter(|_| sampler.keep(200)).count();
+	assert_eq!(kept, 3);
+	assert!((0..9).all(|_| sampler.keep(404)));
+	```
+	*/
+	pub fn keep(&self, status: u16) -> bool
+	{
+		let rate = self.rate.load(Ordering::Relaxed);
+		status >= 400 || rate <= 1 || self.seen.fetch_add(1, Ordering::Relaxed) % rate == 0
+	}
+}
+
+/**
+Collects access log entries from the worker threads and hands them to a sink
+in batches from a dedicated thread, so workers never wait on log output.
+*/
+pub struct AccessLogBuffer
+{
+	sender: Sender<(Level, String)>,
+	writer: JoinHandle<()>
+}
+
+impl AccessLogBuffer
+{
+	/**
+	Start the thread that writes out buffered entries.
+
+	# Parameters
+	- `max_entries`: Write out the buffer as soon as it holds this many entries
+	- `flush_interval`: Write out whatever is buffered at least this often
+	- `sink`: Where the entries are written
+
+	# Returns
+	The running buffer, or the error from starting its thread
+	*/
+	pub fn start(max_entries: usize, flush_interval: Duration, mut sink: Sink) -> std::io::Result<AccessLogBuffer>
+	{
+		let (sender, receiver) = channel::<(Level, String)>();
+		let writer = thread::Builder::new().name(String::from("c20web-access-log")).spawn(move || {
+			let mut pending: Vec<(Level, String)> = Vec::new();
+			let mut last_flush = Instant::now();
+			loop
+			{
+				let wait = flush_interval.checked_sub(last_flush.elapsed()).unwrap_or_default();
+				let disconnected = match receiver.recv_timeout(wait)
+				{
+					Ok(entry) => {pending.push(entry); false},
+					Err(RecvTimeoutError::Timeout) => false,
+					Err(RecvTimeoutError::Disconnected) => true
+				};
+				if disconnected || pending.len() >= max_entries || last_flush.elapsed() >= flush_interval
+				{
+					if !pending.is_empty()
+					{
+						sink(&pending);
+						pending.clear();
+					}
+					last_flush = Instant::now();
+				}
+				if disconnected
+				{
+					break;
+				}
+			}
+		})?;
+		Ok(AccessLogBuffer{sender, writer})
+	}
+
+	/**
+	Queue an entry to be written.
+
+	# Parameters
+	- `level`: The level to log the entry at
+	- `line`: The complete access log entry
+	*/
+	pub fn record(&self, level: Level, line: String)
+	{
+		//only fails once the writer thread is gone, which only happens during shutdown
+		let _send_res = self.sender.send((level, line));
+	}
+
+	/**
+	Stop accepting entries and wait until everything already queued has been written.
+	*/
+	pub fn shutdown(self)
+	{
+		drop(self.sender);
+		if self.writer.join().is_err()
+		{
+			error!(target: LOG_SERVER, "Access log writer thread panicked; some entries may be lost");
+		}
+	}
+}
+
+/**
+Set the level each status class is logged at from the `access_log_levels`
+table, how many successful requests are logged from `log_sample_rate`, and
+turn on access log buffering according to the
+`access_log_buffer_entries` and `access_log_flush_ms` settings. A buffer size
+of zero leaves every entry to be written synchronously by the worker that
+produced it.
+
+# Parameters
+- `settings`: The server configuration to apply
+*/
+pub fn configure(settings: &Config)
+{
+	let mut levels = DEFAULT_LEVELS;
+	for (class, level) in settings.get_table("access_log_levels").unwrap_or_default()
+	{
+		let index = match class.as_str()
+		{
+			"1xx" => 0, "2xx" => 1, "3xx" => 2, "4xx" => 3, "5xx" => 4,
+			_ => {warn!(target: LOG_SERVER, "Ignoring access_log_levels entry {}: use a status class like 4xx", class); continue;}
+		};
+		match level.into_str().ok().and_then(|name| Level::from_str(&name).ok())
+		{
+			Some(level) => {levels[index] = level;},
+			None => {warn!(target: LOG_SERVER, "Ignoring access_log_levels entry {}: the level must be error, warn, info, debug or trace", class);}
+		}
+	}
+	*LEVELS.write().unwrap_or_else(|e| e.into_inner()) = levels;
+	SAMPLER.set_rate(settings.get::<u64>("log_sample_rate").unwrap_or(1));
+
+	let max_entries = settings.get::<usize>("access_log_buffer_entries").unwrap_or(0);
+	if max_entries == 0
+	{
+		return;
+	}
+	let flush_interval = Duration::from_millis(settings.get::<u64>("access_log_flush_ms").unwrap_or(1000));
+	let sink: Sink = Box::new(|entries: &[(Level, String)]| {
+		for (level, line) in entries
+		{
+			log!(target: LOG_ACCESS, *level, "{}", line);
+		}
+	});
+	match AccessLogBuffer::start(max_entries, flush_interval, sink)
+	{
+		Ok(buffer) => {
+			let mut installed = ACCESS_LOG.write().unwrap_or_else(|e| e.into_inner());
+			if let Some(previous) = installed.replace(buffer)
+			{
+				previous.shutdown();
+			}
+		},
+		Err(e) => {error!(target: LOG_SERVER, "Writing access log entries synchronously because the buffer thread couldn't start: {}", e);}
+	}
+}
+
+/**
+Write an access log entry, through the buffer if buffering is on, at the
+level configured for the response's status class. Entries for successful
+responses may be skipped, according to `log_sample_rate`.
+
+# Parameters
+- `status`: The status code of the response the entry is about
+- `line`: The complete access log entry
+*/
+pub fn log_access(status: u16, line: String)
+{
+	if !SAMPLER.keep(status)
+	{
+		return;
+	}
+	let level = level_for(status);
+	let installed = ACCESS_LOG.read().unwrap_or_else(|e| e.into_inner());
+	match installed.as_ref()
+	{
+		Some(buffer) => buffer.record(level, line),
+		None => {log!(target: LOG_ACCESS, level, "{}", line);}
+	}
+}
+
+/**
+# Returns
+The level to log a response with the given status at. Codes outside 1xx-5xx
+are logged as errors, since they're not valid HTTP.
+
+# Examples
+```
+use log::Level;
+use c20web::accesslog::level_for;
+
+assert_eq!(level_for(200), Level::Info);
+assert_eq!(level_for(404), Level::Warn);
+assert_eq!(level_for(503), Level::Error);
+```
+*/
+pub fn level_for(status: u16) -> Level
+{
+	match status / 100
+	{
+		class @ 1..=5 => LEVELS.read().unwrap_or_else(|e| e.into_inner())[class as usize - 1],
+		_ => Level::Error
+	}
+}
+
+/**
+Write out any buffered access log entries and go back to writing them
+synchronously. Call this before the server exits so no entries are lost.
+*/
+pub fn flush_and_stop()
+{
+	let buffer = ACCESS_LOG.write().unwrap_or_else(|e| e.into_inner()).take();
+	if let Some(buffer) = buffer
+	{
+		buffer.shutdown();
+	}
+}
+
+/**
+What's known about an answered request, for writing it to the access log in
+the combined log format.
+*/
+pub struct AccessEntry
+{
+	/// Address of the client
+	pub peer: String,
+	/// The request method, or "-" if the request couldn't be parsed
+	pub method: String,
+	pub resource: String,
+	pub http_version: String,
+	pub status: u16,
+	/// Number of bytes sent, headers included
+	pub bytes: usize,
+	pub referer: Option<String>,
+	pub user_agent: Option<String>,
+	/// When the request arrived
+	pub time: SystemTime
+}
+
+impl AccessEntry
+{
+	/**
+	# Returns
+	The entry in the combined log format, with `-` for anything unknown.
+
+	# Examples
+	```
+	use std::time::{Duration, UNIX_EPOCH};
+	use c20web::accesslog::AccessEntry;
+
+	let entry = AccessEntry{
+		peer: String::from("10.0.0.7"), method: String::from("GET"), resource: String::from("/hello.html"),
+		http_version: String::from("HTTP/1.1"), status: 200, bytes: 2326,
+		referer: Some(String::from("https://example.com/")), user_agent: None,
+		time: UNIX_EPOCH + Duration::from_secs(971_186_136)
+	};
+	assert_eq!(entry.combined(), "10.0.0.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /hello.html HTTP/1.1\" 200 2326 \"https://example.com/\" \"-\"");
+	```
+	*/
+	pub fn combined(&self) -> String
+	{
+		let request_line = if self.method == "-" {String::from("-")} else {format!("{} {} {}", self.method, self.resource, self.http_version)};
+		let quoted = |value: Option<&String>| value.map_or_else(|| String::from("-"), |v| escape(v));
+		format!("{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"", self.peer, clf_time(self.time), escape(&request_line), self.status, self.bytes, quoted(self.referer.as_ref()), quoted(self.user_agent.as_ref()))
+	}
+}
+
+/// Keep a client-supplied value from breaking out of its quotes or the log line
+fn escape(value: &str) -> String
+{
+	value.chars().flat_map(|c| match c
+	{
+		'"' => vec!['\\', '"'],
+		'\\' => vec!['\\', '\\'],
+		c if c.is_control() => c.escape_default().collect(),
+		c => vec![c]
+	}).collect()
+}
+
+/**
+# Returns
+The time in the common log format, like `10/Oct/2000:13:55:36 +0000`, in UTC
+*/
+fn clf_time(time: SystemTime) -> String
+{
+	const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	let (days, of_day) = ((secs / 86400) as i64, secs % 86400);
+	//days since 1970 to a civil date, from Howard Hinnant's date algorithms
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let day_of_era = z.rem_euclid(146_097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 {mp + 3} else {mp - 9};
+	let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+	format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[(month - 1) as usize], year, of_day / 3600, of_day / 60 % 60, of_day % 60)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	fn collecting_sink() -> (Sink, Arc<Mutex<Vec<Vec<String>>>>)
+	{
+		let batches = Arc::new(Mutex::new(Vec::new()));
+		let collected = Arc::clone(&batches);
+		let sink: Sink = Box::new(move |entries: &[(Level, String)]| collected.lock().unwrap().push(entries.iter().map(|(_, line)| line.clone()).collect()));
+		(sink, batches)
+	}
+
+	#[test]
+	fn nothing_dropped_on_shutdown()
+	{
+		let (sink, batches) = collecting_sink();
+		let buffer = Arc::new(AccessLogBuffer::start(64, Duration::from_secs(3600), sink).unwrap());
+
+		let producers: Vec<_> = (0..4).map(|worker| {
+			let buffer = Arc::clone(&buffer);
+			thread::spawn(move || {
+				for n in 0..500
+				{
+					buffer.record(Level::Info, format!("{} {}", worker, n));
+				}
+			})
+		}).collect();
+		for producer in producers
+		{
+			producer.join().unwrap();
+		}
+		match Arc::try_unwrap(buffer)
+		{
+			Ok(buffer) => buffer.shutdown(),
+			Err(_) => panic!("Buffer still shared after producers finished")
+		}
+
+		let batches = batches.lock().unwrap();
+		assert!(batches.iter().all(|batch| batch.len() <= 64));
+		let lines: Vec<&String> = batches.iter().flatten().collect();
+		assert_eq!(lines.len(), 2000);
+		for worker in 0..4
+		{
+			let prefix = format!("{} ", worker);
+			let order: Vec<usize> = lines.iter().filter(|l| l.starts_with(&prefix)).map(|l| l[prefix.len()..].parse().unwrap()).collect();
+			assert_eq!(order, (0..500).collect::<Vec<usize>>());
+		}
+	}
+
+	#[test]
+	fn flushes_on_interval()
+	{
+		let (sink, batches) = collecting_sink();
+		let buffer = AccessLogBuffer::start(1000, Duration::from_millis(50), sink).unwrap();
+		buffer.record(Level::Info, String::from("only entry"));
+
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while batches.lock().unwrap().is_empty() && Instant::now() < deadline
+		{
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(*batches.lock().unwrap(), vec![vec![String::from("only entry")]]);
+		buffer.shutdown();
+	}
+
+	#[test]
+	fn sampling_keeps_every_error()
+	{
+		let sampler = Arc::new(Sampler::new(2));
+		let workers: Vec<_> = (0..4).map(|_| {
+			let sampler = sampler.clone();
+			thread::spawn(move || (0..500).map(|i| if i % 5 == 0 {(500, sampler.keep(500))} else {(200, sampler.keep(200))}).collect::<Vec<(u16, bool)>>())
+		}).collect();
+		let decisions: Vec<(u16, bool)> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+
+		let successes: Vec<bool> = decisions.iter().filter(|(status, _)| *status == 200).map(|(_, kept)| *kept).collect();
+		assert_eq!(successes.len(), 1600);
+		assert_eq!(successes.iter().filter(|kept| **kept).count(), 800);
+		assert!(decisions.iter().filter(|(status, _)| *status == 500).all(|(_, kept)| *kept));
+	}
+
+	#[test]
+	fn combined_entries_escape_and_fill_in_dashes()
+	{
+		assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+		assert_eq!(clf_time(UNIX_EPOCH + Duration::from_secs(951_782_400)), "29/Feb/2000:00:00:00 +0000");
+		let entry = AccessEntry{
+			peer: String::from("::1"), method: String::from("-"), resource: String::from("-"), http_version: String::from("-"),
+			status: 400, bytes: 0, referer: None, user_agent: Some(String::from("evil\" agent\n")), time: UNIX_EPOCH
+		};
+		assert_eq!(entry.combined(), "::1 - - [01/Jan/1970:00:00:00 +0000] \"-\" 400 0 \"-\" \"evil\\\" agent\\n\"");
+	}
+}
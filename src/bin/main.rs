@@ -1,15 +1,22 @@
+#![allow(clippy::tabs_in_doc_comments)]
+
 extern crate clap;
 
-use std::net::TcpListener;
 use std::env;
-use std::path::Path;
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use clap::{Arg, App};
-use log::{error, info};
-use log4rs;
-use threadpool::ThreadPool;
+use log::{error, warn, info};
 
-use c20web::handle_connection;
+use c20web::accesslog;
+use c20web::bind_listener;
+use c20web::listener::{accept_until_stopped, wake, ListenOptions};
+use c20web::dispatch_connection;
+use c20web::{capture_webroot, clamp_compression_levels, configure_status_codes, default_working_dirs, fallback_log_config, find_working_dir, merge_includes, unknown_settings, validate_settings};
+use c20web::workers::{try_build_pool, install_panic_logger};
+use c20web::LOG_SERVER;
 use c20web::statics::SETTINGS;
 use c20web::statics::DEFAULT_CONFIG;
 
@@ -27,72 +34,147 @@ fn main()
                           .arg(Arg::with_name("working_dir")
                                .short("d")
                                .long("workingdir")
-							   .help("Working directory. Will look here for the config file (web.toml) and will be the base for all relative paths used elsewhere in the config. Defaults to ./data for easy dev use with `cargo run` but an absolute path is recommended otherwise.")
-							   .default_value("data")
+							   .help("Working directory. Will look here for the config file (web.toml) and will be the base for all relative paths used elsewhere in the config. Defaults to the first of the current directory, ./data (for easy dev use with `cargo run`), the executable's directory and /etc/c20web that has a web.toml, but an absolute path is recommended.")
                                .takes_value(true))
                           .get_matches();
 
-	let working_dir = matches.value_of("working_dir").unwrap();
-	env::set_current_dir(Path::new(working_dir)).expect("Couldn't set cwd");
+	//only known once logging is up, which needs the working directory first
+	let mut discovered = false;
+	let working_dir = match matches.value_of("working_dir")
+	{
+		Some(dir) => PathBuf::from(dir),
+		None => {
+			discovered = true;
+			find_working_dir(&default_working_dirs()).unwrap_or_else(|| PathBuf::from("data"))
+		}
+	};
+	env::set_current_dir(&working_dir).expect("Couldn't set cwd");
+	let working_dir = working_dir.to_string_lossy().into_owned();
 
-	let (threads_max,listen_addr): (usize,String) = {
+	let (threads_max,listen_addr,max_queued,listen_options,bind_retries,bind_retry_delay,validation): (usize,String,usize,ListenOptions,usize,Duration,Result<(),String>) = {
 		let mut settings = SETTINGS.write().expect("Couldn't get config in main");
 		settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).expect("Couldn't merge default config");
 		settings.merge(config::File::with_name("web")).expect("Couldn't merge config from file");
-		settings.set("working_dir",working_dir).expect("Couldn't merge config from commandline");
+		merge_includes(&mut settings).expect("Couldn't merge included config files");
+		settings.set("working_dir",working_dir.as_str()).expect("Couldn't merge config from commandline");
 
 		(
 			settings.get::<usize>("threads_max").expect("threads_max missing from config"),
-			settings.get::<String>("listen_addr").expect("listen_addr missing from config:")
+			settings.get::<String>("listen_addr").expect("listen_addr missing from config:"),
+			settings.get::<usize>("max_queued_connections").unwrap_or(0),
+			ListenOptions{
+				reuse_address: settings.get::<bool>("reuse_address").unwrap_or(true),
+				reuse_port: settings.get::<bool>("reuse_port").unwrap_or_default()
+			},
+			settings.get::<usize>("bind_retries").unwrap_or(0),
+			Duration::from_millis(settings.get::<u64>("bind_retry_delay_ms").unwrap_or(500)),
+			validate_settings(&settings)
 		)
 	};
 
-	log4rs::init_file("log4rs.yml", Default::default()).expect("log4rs.yml not found");
+	let log_config_res = log4rs::init_file("log4rs.yml", Default::default());
+	if log_config_res.is_err()
+	{
+		log4rs::init_config(fallback_log_config().expect("Couldn't build the fallback log config")).expect("Couldn't start logging");
+	}
 	//at this point the loggers are available and any further errors can be logged instead of bring thrown into a panic
+	if let Err(e) = log_config_res
+	{
+		warn!(target: LOG_SERVER, "Logging to the console with default settings because log4rs.yml couldn't be used: {}", e);
+	}
 	
-	info!("Starting up.");
-	start_listening(listen_addr, threads_max);
-	info!("Shutting down.");
+	if discovered
+	{
+		info!(target: LOG_SERVER, "Using working directory {}, the first place web.toml was found", working_dir);
+	}
+
+	if let Err(e) = validation
+	{
+		error!(target: LOG_SERVER, "Refusing to start because of a configuration problem: {}", e);
+		process::exit(1);
+	}
+
+	for key in unknown_settings(&SETTINGS.read().expect("Couldn't get config in main"))
+	{
+		warn!(target: LOG_SERVER, "Ignoring unrecognized setting {} in web.toml; check it for a typo", key);
+	}
+
+	if let Err(e) = capture_webroot(&mut SETTINGS.write().expect("Couldn't get config in main"))
+	{
+		error!(target: LOG_SERVER, "Refusing to start because the webroot couldn't be captured: {}", e);
+		process::exit(1);
+	}
+
+	if let Err(e) = clamp_compression_levels(&mut SETTINGS.write().expect("Couldn't get config in main"))
+	{
+		error!(target: LOG_SERVER, "Refusing to start because of a configuration problem: {}", e);
+		process::exit(1);
+	}
+
+	install_panic_logger();
+	accesslog::configure(&SETTINGS.read().expect("Couldn't get config in main"));
+	configure_status_codes(&SETTINGS.read().expect("Couldn't get config in main"));
+
+	info!(target: LOG_SERVER, "Starting up.");
+	start_listening(listen_addr, threads_max, max_queued, listen_options, bind_retries, bind_retry_delay);
+	//buffered access log entries would otherwise be lost with the process
+	accesslog::flush_and_stop();
+	info!(target: LOG_SERVER, "Shutting down.");
 }
 
+/// Set by SIGINT or SIGTERM to stop accepting connections
+static STOP: AtomicBool = AtomicBool::new(false);
+
 /**
 Listen on an interface and start the main loop which accepts each new
-connection and sends it to its own thread.
+connection and sends it to its own thread. SIGINT and SIGTERM stop the loop,
+and this returns once the connections already accepted have been answered.
 
 # Parameters
 - `listen_addr`: The interface on which to listen
 - `threads_max`: Size of the thread pool
+- `max_queued`: The most connections that may wait for a worker before new ones get 503, zero for no limit
+- `listen_options`: Socket options to apply to the listening socket
+- `bind_retries`: How many more times to try binding if the address is in use
+- `bind_retry_delay`: How long to wait before the first retry, doubling after each one
 
 # Examples
 ```no_run
-start_listening(String::from("127.0.0.1:8000"), 100);
+start_listening(String::from("127.0.0.1:8000"), 100, 0, ListenOptions::default(), 5, Duration::from_millis(500));
 ```
 
 # Aborts
-Will abort when unable to bind to the listen_addr
+Will abort when unable to bind to the listen_addr, once the retries are used up
 */
-fn start_listening(listen_addr: String, threads_max: usize)
+fn start_listening(listen_addr: String, threads_max: usize, max_queued: usize, listen_options: ListenOptions, bind_retries: usize, bind_retry_delay: Duration)
 {
-	let listener = match TcpListener::bind(&listen_addr)
+	let listener = match bind_listener(&listen_addr, listen_options, bind_retries, bind_retry_delay)
 	{
 		Ok(r) => r,
 		Err(e) => {
-			error!("Couldn't bind to addr {}: {}", &listen_addr, e);
+			error!(target: LOG_SERVER, "Couldn't bind to addr {}: {}", &listen_addr, e);
 			process::exit(1);
 		}
 	};
-	let pool = ThreadPool::new(threads_max);
+	let pool = try_build_pool(threads_max);
 
-    for stream in listener.incoming()
-	{
-		let stream = match stream
+	let local_addr = listener.local_addr();
+	let stop_handler = ctrlc::set_handler(move || {
+		STOP.store(true, Ordering::SeqCst);
+		if let Ok(addr) = local_addr
 		{
-			Ok(s) => s,
-			Err(e) =>{
-				error!("Listener gave us an invalid TCPStream!: {}",e);
-				continue;
-			}
-		};
-        pool.execute(move ||{handle_connection(stream);});
-    }
+			wake(addr);
+		}
+	});
+	if let Err(e) = stop_handler
+	{
+		warn!(target: LOG_SERVER, "Stopping on a signal won't be graceful because the handler couldn't be installed: {}", e);
+	}
+
+	accept_until_stopped(&listener, &STOP, |stream| dispatch_connection(pool.as_ref(), stream, max_queued));
+	info!(target: LOG_SERVER, "Stopped accepting connections, finishing the ones already open.");
+	if let Some(pool) = pool
+	{
+		pool.join();
+	}
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+/**
+A request body kept in a temporary file instead of memory, for bodies too big
+to hold comfortably. The file has a random name and only the server's user
+can read it. It's removed when the body is dropped, which is when the
+request it came with has been answered.
+*/
+pub struct SpooledBody
+{
+	file: NamedTempFile,
+	len: u64
+}
+
+impl SpooledBody
+{
+	/**
+	# Parameters
+	- `dir`: Directory to put the temporary file in
+
+	# Returns
+	An empty body, or the error from creating its file
+
+	# Examples
+	```
+	use std::io::Read;
+	use c20web::body::SpooledBody;
+
+	let mut body = SpooledBody::create(&std::env::temp_dir()).unwrap();
+	body.append(b"name=Ada").unwrap();
+	let mut contents = String::new();
+	body.reader().unwrap().read_to_string(&mut contents).unwrap();
+	assert_eq!(contents, "name=Ada");
+	assert_eq!(body.len(), 8);
+	```
+	*/
+	pub fn create(dir: &Path) -> io::Result<SpooledBody>
+	{
+		let file = tempfile::Builder::new().prefix("c20web-body-").tempfile_in(dir)?;
+		Ok(SpooledBody{file, len: 0})
+	}
+
+	/**
+	Add the next part of the body to the end of the file.
+	*/
+	pub fn append(&mut self, bytes: &[u8]) -> io::Result<()>
+	{
+		self.file.write_all(bytes)?;
+		self.len += bytes.len() as u64;
+		Ok(())
+	}
+
+	/**
+	# Returns
+	A reader positioned at the start of the body, independent of any other reader
+	*/
+	pub fn reader(&self) -> io::Result<File>
+	{
+		self.file.reopen()
+	}
+
+	/**
+	# Returns
+	The number of bytes in the body
+	*/
+	pub fn len(&self) -> u64
+	{
+		self.len
+	}
+
+	/**
+	# Returns
+	true if nothing has been appended
+	*/
+	pub fn is_empty(&self) -> bool
+	{
+		self.len == 0
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[cfg(unix)]
+	#[test]
+	fn spool_files_are_private()
+	{
+		use std::os::unix::fs::PermissionsExt;
+
+		let first = SpooledBody::create(&std::env::temp_dir()).unwrap();
+		let second = SpooledBody::create(&std::env::temp_dir()).unwrap();
+		let path = first.file.path().to_path_buf();
+		assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		assert_ne!(path, second.file.path());
+		drop(first);
+		assert!(!path.exists());
+	}
+}
@@ -0,0 +1,132 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/**
+Read buffers kept from connections that have finished, so new connections
+can reuse their capacity instead of allocating their own.
+*/
+#[derive(Default)]
+pub struct BufferPool
+{
+	idle: Mutex<Vec<Vec<u8>>>
+}
+
+impl BufferPool
+{
+	pub fn new() -> BufferPool
+	{
+		BufferPool::default()
+	}
+
+	/**
+	Get an empty buffer, reusing one from the pool if there is one.
+
+	# Parameters
+	- `capacity`: The capacity a new buffer starts with, and the most a buffer keeps when it's given back
+	- `max_pooled`: The most buffers the pool holds on to. Zero means buffers aren't pooled at all.
+
+	# Returns
+	A buffer that goes back into the pool when it's dropped
+
+	# Examples
+	```
+	use c20web::buffers::BufferPool;
+
+	let pool = BufferPool::new();
+	let mut buffer = pool.take(4096, 8);
+	buffer.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+	let address = buffer.as_ptr();
+	drop(buffer);
+
+	let buffer = pool.take(4096, 8);
+	assert!(buffer.is_empty());
+	assert_eq!(buffer.as_ptr(), address);
+	```
+	*/
+	pub fn take(&self, capacity: usize, max_pooled: usize) -> PooledBuffer<'_>
+	{
+		let reused = if max_pooled == 0 {None} else {self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop()};
+		PooledBuffer{buffer: reused.unwrap_or_else(|| Vec::with_capacity(capacity)), pool: self, capacity, max_pooled}
+	}
+
+	/**
+	# Returns
+	The number of buffers waiting to be reused
+	*/
+	pub fn idle(&self) -> usize
+	{
+		self.idle.lock().unwrap_or_else(|e| e.into_inner()).len()
+	}
+}
+
+/**
+A buffer borrowed from a `BufferPool`. It's emptied and given back when dropped.
+*/
+pub struct PooledBuffer<'a>
+{
+	buffer: Vec<u8>,
+	pool: &'a BufferPool,
+	capacity: usize,
+	max_pooled: usize
+}
+
+impl Deref for PooledBuffer<'_>
+{
+	type Target = Vec<u8>;
+
+	fn deref(&self) -> &Vec<u8>
+	{
+		&self.buffer
+	}
+}
+
+impl DerefMut for PooledBuffer<'_>
+{
+	fn deref_mut(&mut self) -> &mut Vec<u8>
+	{
+		&mut self.buffer
+	}
+}
+
+impl Drop for PooledBuffer<'_>
+{
+	fn drop(&mut self)
+	{
+		if self.max_pooled == 0
+		{
+			return;
+		}
+		let mut buffer = std::mem::take(&mut self.buffer);
+		buffer.clear();
+		//one connection that needed a huge buffer shouldn't keep that memory tied up forever
+		buffer.shrink_to(self.capacity);
+		let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+		if idle.len() < self.max_pooled
+		{
+			idle.push(buffer);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn pool_keeps_at_most_max_pooled()
+	{
+		let pool = BufferPool::new();
+		let buffers: Vec<PooledBuffer> = (0..3).map(|_| pool.take(64, 2)).collect();
+		drop(buffers);
+		assert_eq!(pool.idle(), 2);
+
+		let mut grown = pool.take(64, 2);
+		grown.resize(100000, 0);
+		drop(grown);
+		assert!(pool.take(64, 2).capacity() < 100000);
+
+		drop(pool.take(64, 0));
+		assert_eq!(pool.idle(), 2);
+	}
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Reads the complete contents of a file
+pub type Loader = Box<dyn Fn(&Path) -> io::Result<Vec<u8>> + Send + Sync>;
+
+/**
+An in-memory copy of a file, along with what the file looked like when it was read.
+*/
+struct CachedFile
+{
+	body: Arc<Vec<u8>>,
+	modified: Option<SystemTime>,
+	len: u64,
+	/// When the file was read into the cache
+	cached_at: Instant
+}
+
+/**
+Keeps the contents of small, frequently served files in memory. Every lookup
+still checks the file's size and modification time, so a changed file is
+read again instead of serving stale content.
+*/
+pub struct FileCache
+{
+	entries: Mutex<HashMap<PathBuf, CachedFile>>,
+	loader: Loader
+}
+
+impl FileCache
+{
+	/**
+	# Returns
+	An empty cache that reads files from the filesystem.
+	*/
+	pub fn new() -> FileCache
+	{
+		FileCache::with_loader(Box::new(|path: &Path| fs::read(path)))
+	}
+
+	/**
+	# Parameters
+	- `loader`: Function used to read a file's contents whenever the cache misses
+
+	# Returns
+	An empty cache that reads files using the given function.
+	*/
+	pub fn with_loader(loader: Loader) -> FileCache
+	{
+		FileCache{entries: Mutex::new(HashMap::new()), loader}
+	}
+
+	/**
+	Get the contents of a file, from memory if we have an up-to-date copy.
+	Files that fit within the limits are kept for next time.
+
+	# Parameters
+	- `path`: Filesystem path of the file
+	- `max_total_bytes`: The most file data the whole cache may hold
+	- `max_file_bytes`: The largest file the cache will hold
+
+	# Returns
+	The file contents, or the error from reading the file
+
+	# Examples
+	```
+	use c20web::cache::FileCache;
+	use std::path::Path;
+
+	let cache = FileCache::new();
+	let first = cache.get(Path::new("data/webroot/hello.html"), 1000000, 100000).unwrap();
+	let second = cache.get(Path::new("data/webroot/hello.html"), 1000000, 100000).unwrap();
+	assert_eq!(first, second);
+	assert_eq!(cache.size(), first.len());
+	```
+	*/
+	pub fn get(&self, path: &Path, max_total_bytes: usize, max_file_bytes: usize) -> io::Result<Arc<Vec<u8>>>
+	{
+		self.get_with_age(path, max_total_bytes, max_file_bytes).map(|(body, _)| body)
+	}
+
+	/**
+	Same as `get`, but also says how long the contents have been in memory.
+
+	# Returns
+	The file contents and, when they came from memory, how long ago they were read
+
+	# Examples
+	```
+	use c20web::cache::FileCache;
+	use std::path::Path;
+
+	let cache = FileCache::new();
+	assert!(cache.get_with_age(Path::new("data/webroot/hello.html"), 1000000, 100000).unwrap().1.is_none());
+	assert!(cache.get_with_age(Path::new("data/webroot/hello.html"), 1000000, 100000).unwrap().1.is_some());
+	```
+	*/
+	pub fn get_with_age(&self, path: &Path, max_total_bytes: usize, max_file_bytes: usize) -> io::Result<(Arc<Vec<u8>>, Option<Duration>)>
+	{
+		let meta = fs::metadata(path)?;
+		let modified = meta.modified().ok();
+		{
+			let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+			if let Some(cached) = entries.get(path)
+			{
+				if cached.len == meta.len() && cached.modified == modified && modified.is_some()
+				{
+					return Ok((Arc::clone(&cached.body), Some(cached.cached_at.elapsed())));
+				}
+			}
+		}
+
+		//read without holding the lock, so one slow file doesn't hold up every other request
+		let body = Arc::new((self.loader)(path)?);
+		let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+		entries.remove(path);
+		let held: usize = entries.values().map(|cached| cached.body.len()).sum();
+		if body.len() <= max_file_bytes && held + body.len() <= max_total_bytes
+		{
+			entries.insert(path.to_path_buf(), CachedFile{body: Arc::clone(&body), modified, len: meta.len(), cached_at: Instant::now()});
+		}
+		Ok((body, None))
+	}
+
+	/**
+	# Returns
+	The total size of the file contents currently held in memory.
+	*/
+	pub fn size(&self) -> usize
+	{
+		let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+		entries.values().map(|cached| cached.body.len()).sum()
+	}
+}
+
+impl Default for FileCache
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	fn counting_cache() -> (FileCache, Arc<AtomicUsize>)
+	{
+		let reads = Arc::new(AtomicUsize::new(0));
+		let counter = Arc::clone(&reads);
+		let cache = FileCache::with_loader(Box::new(move |path: &Path| {
+			counter.fetch_add(1, Ordering::SeqCst);
+			fs::read(path)
+		}));
+		(cache, reads)
+	}
+
+	#[test]
+	fn limits_decide_what_is_kept()
+	{
+		let (cache, reads) = counting_cache();
+		let path = Path::new("data/webroot/hello.jpg");
+		let len = fs::metadata(path).unwrap().len() as usize;
+
+		cache.get(path, len, len - 1).unwrap();
+		cache.get(path, len, len - 1).unwrap();
+		assert_eq!(reads.load(Ordering::SeqCst), 2);
+		assert_eq!(cache.size(), 0);
+
+		cache.get(path, len, len).unwrap();
+		cache.get(path, len, len).unwrap();
+		assert_eq!(reads.load(Ordering::SeqCst), 3);
+		assert_eq!(cache.size(), len);
+	}
+
+	#[test]
+	fn changed_files_are_read_again()
+	{
+		let (cache, reads) = counting_cache();
+		let dir = std::env::temp_dir().join(format!("c20web-test-{}-cache-changed", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("page.html");
+		fs::write(&path, "first").unwrap();
+
+		assert_eq!(*cache.get(&path, 1000, 1000).unwrap(), b"first".to_vec());
+		fs::write(&path, "second version").unwrap();
+		assert_eq!(*cache.get(&path, 1000, 1000).unwrap(), b"second version".to_vec());
+		assert_eq!(reads.load(Ordering::SeqCst), 2);
+		assert_eq!(cache.size(), 14);
+	}
+}
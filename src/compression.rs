@@ -0,0 +1,303 @@
+use std::io;
+use std::io::prelude::*;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/**
+A content-coding the server can apply to a response body.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding
+{
+	Brotli,
+	Gzip,
+	Identity
+}
+
+impl Encoding
+{
+	/**
+	# Returns
+	The token used for this encoding in the `Accept-Encoding` and `Content-Encoding` headers.
+	*/
+	pub fn token(self) -> &'static str
+	{
+		match self
+		{
+			Encoding::Brotli => "br",
+			Encoding::Gzip => "gzip",
+			Encoding::Identity => "identity"
+		}
+	}
+}
+
+/**
+Choose the best encoding that both we and the client support. Our preference
+is brotli, then gzip, then no encoding at all, regardless of the order or
+weights in the client's header, except that anything the client gives a
+weight of zero is never chosen.
+
+# Parameters
+- `accept_encoding`: Value of the request's `Accept-Encoding` header, or the empty string if there wasn't one
+- `brotli`: Whether brotli is enabled on our side
+- `gzip`: Whether gzip is enabled on our side
+
+# Returns
+The encoding to use for the response body
+
+# Examples
+```
+use c20web::compression::{negotiate, Encoding};
+
+assert_eq!(negotiate("gzip, deflate, br", true, true), Encoding::Brotli);
+assert_eq!(negotiate("gzip, deflate, br", false, true), Encoding::Gzip);
+assert_eq!(negotiate("br;q=0, gzip;q=0.5", true, true), Encoding::Gzip);
+assert_eq!(negotiate("", true, true), Encoding::Identity);
+```
+*/
+pub fn negotiate(accept_encoding: &str, brotli: bool, gzip: bool) -> Encoding
+{
+	let candidates = [(Encoding::Brotli, brotli), (Encoding::Gzip, gzip)];
+	for (encoding, enabled) in candidates.iter()
+	{
+		if *enabled && accept_weight(accept_encoding, encoding.token()) > 0.0
+		{
+			return *encoding;
+		}
+	}
+	Encoding::Identity
+}
+
+/**
+Find the weight a client gave to a coding in its `Accept-Encoding` header,
+falling back to the weight of `*` if the coding isn't listed by name.
+
+# Returns
+The weight from 0 to 1, or 0 if the coding isn't acceptable at all
+*/
+fn accept_weight(accept_encoding: &str, token: &str) -> f32
+{
+	let mut wildcard: f32 = 0.0;
+	for item in accept_encoding.split(',')
+	{
+		let mut parts = item.split(';');
+		let name = parts.next().unwrap_or("").trim();
+		let mut weight: f32 = 1.0;
+		for param in parts
+		{
+			let param = param.trim();
+			if param.starts_with("q=") || param.starts_with("Q=")
+			{
+				weight = param[2..].trim().parse().unwrap_or(0.0);
+			}
+		}
+		if name.eq_ignore_ascii_case(token)
+		{
+			return weight;
+		}else if name == "*"{
+			wildcard = weight;
+		}
+	}
+	wildcard
+}
+
+/**
+Decide whether a type of content is worth compressing. Text-based formats
+shrink a lot; images, video, archives and the like are already compressed
+and would only waste CPU time.
+
+# Parameters
+- `mime`: The MIME type of the response body, with or without parameters
+
+# Returns
+true if the content should be compressed when the client allows it
+
+# Examples
+```
+use c20web::compression::is_compressible;
+
+assert!(is_compressible("text/html"));
+assert!(is_compressible("application/json; charset=utf-8"));
+assert!(is_compressible("image/svg+xml"));
+assert!(!is_compressible("image/jpeg"));
+```
+*/
+pub fn is_compressible(mime: &str) -> bool
+{
+	let mime = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+	mime.starts_with("text/")
+		|| mime.ends_with("+xml")
+		|| mime.ends_with("+json")
+		|| mime == "application/javascript"
+		|| mime == "application/x-javascript"
+		|| mime == "application/json"
+		|| mime == "application/xml"
+		|| mime == "application/wasm"
+		|| mime == "image/x-icon"
+		|| mime == "image/bmp"
+}
+
+/**
+Decide whether a type of content should be compressed, using an explicit list
+of types when one is configured and `is_compressible` otherwise. An entry
+whose subtype is `*` matches every subtype of its top-level type.
+
+# Parameters
+- `mime`: The MIME type of the response body, with or without parameters
+- `allowlist`: The configured `compress_mime_types`, empty to use the built-in heuristic
+
+# Returns
+true if the content should be compressed when the client allows it
+
+# Examples
+```
+use c20web::compression::should_compress;
+
+let list = vec![String::from("image/svg+xml"), String::from("text/css")];
+assert!(should_compress("text/css; charset=utf-8", &list));
+assert!(should_compress("image/svg+xml", &list));
+assert!(!should_compress("text/html", &list));
+assert!(!should_compress("application/json", &list));
+assert!(should_compress("application/json", &[]));
+```
+*/
+pub fn should_compress(mime: &str, allowlist: &[String]) -> bool
+{
+	if allowlist.is_empty()
+	{
+		return is_compressible(mime);
+	}
+	let mime = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+	allowlist.iter().any(|listed| {
+		let listed = listed.trim().to_ascii_lowercase();
+		if listed.ends_with("/*")
+		{
+			mime.starts_with(&listed[..(listed.len()-1)])
+		}else{
+			mime == listed
+		}
+	})
+}
+
+/**
+Bring a configured compression level into the range the encoding supports:
+0 to 9 for gzip and 0 to 11 for brotli. Out-of-range levels are replaced
+with the nearest valid one.
+
+# Parameters
+- `encoding`: The encoding the level is for
+- `level`: The configured level
+
+# Returns
+A level the encoder will accept
+
+# Examples
+```
+use c20web::compression::{clamp_level, Encoding};
+
+assert_eq!(clamp_level(Encoding::Gzip, 4), 4);
+assert_eq!(clamp_level(Encoding::Gzip, 15), 9);
+assert_eq!(clamp_level(Encoding::Brotli, 15), 11);
+assert_eq!(clamp_level(Encoding::Brotli, -1), 0);
+```
+*/
+pub fn clamp_level(encoding: Encoding, level: i64) -> u32
+{
+	let max: i64 = match encoding
+	{
+		Encoding::Brotli => 11,
+		Encoding::Gzip => 9,
+		Encoding::Identity => 0
+	};
+	level.max(0).min(max) as u32
+}
+
+/**
+Apply an encoding to a body.
+
+# Parameters
+- `body`: The bytes to compress
+- `encoding`: The encoding to apply
+- `level`: Compression level, already within the range from `clamp_level`
+
+# Returns
+The encoded body, or the I/O error from the encoder
+*/
+pub fn compress(body: &[u8], encoding: Encoding, level: u32) -> io::Result<Vec<u8>>
+{
+	match encoding
+	{
+		Encoding::Brotli => {
+			let mut out = Vec::<u8>::new();
+			{
+				let mut writer = brotli::CompressorWriter::new(&mut out, 4096, level, 22);
+				writer.write_all(body)?;
+			}
+			Ok(out)
+		},
+		Encoding::Gzip => {
+			let mut encoder = GzEncoder::new(Vec::<u8>::new(), Compression::new(level));
+			encoder.write_all(body)?;
+			encoder.finish()
+		},
+		Encoding::Identity => Ok(body.to_vec())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use flate2::read::GzDecoder;
+
+	#[test]
+	fn wildcard_and_zero_weights()
+	{
+		assert_eq!(negotiate("*", true, true), Encoding::Brotli);
+		assert_eq!(negotiate("*;q=0", true, true), Encoding::Identity);
+		assert_eq!(negotiate("gzip;q=0, *", false, true), Encoding::Identity);
+		assert_eq!(negotiate("GZIP", true, true), Encoding::Gzip);
+	}
+
+	#[test]
+	fn allowlist_wildcard_subtypes()
+	{
+		let list = vec![String::from("Text/*"), String::from("application/json")];
+		assert!(should_compress("text/plain", &list));
+		assert!(should_compress("TEXT/HTML", &list));
+		assert!(!should_compress("textual/plain", &list));
+		assert!(!should_compress("image/svg+xml", &list));
+	}
+
+	#[test]
+	fn compressed_bodies_round_trip()
+	{
+		let body = b"<p>Hello hello hello hello hello hello</p>".to_vec();
+
+		for level in &[0, 1, 6, 9]
+		{
+			let mut gunzipped = Vec::new();
+			GzDecoder::new(&compress(&body, Encoding::Gzip, *level).unwrap()[..]).read_to_end(&mut gunzipped).unwrap();
+			assert_eq!(gunzipped, body);
+		}
+
+		for level in &[0, 1, 6, 11]
+		{
+			let mut unbrotlied = Vec::new();
+			brotli::Decompressor::new(&compress(&body, Encoding::Brotli, *level).unwrap()[..], 4096).read_to_end(&mut unbrotlied).unwrap();
+			assert_eq!(unbrotlied, body);
+		}
+	}
+
+	#[test]
+	fn out_of_range_levels_still_compress()
+	{
+		let body = b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec();
+		let level = clamp_level(Encoding::Gzip, 100);
+		assert_eq!(level, 9);
+		let mut gunzipped = Vec::new();
+		GzDecoder::new(&compress(&body, Encoding::Gzip, level).unwrap()[..]).read_to_end(&mut gunzipped).unwrap();
+		assert_eq!(gunzipped, body);
+	}
+}
@@ -0,0 +1,340 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Request, Response};
+
+/**
+The validators describing the current version of a file, which clients send
+back in conditional request headers.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validators
+{
+	/// Entity tag, including its quotes
+	pub etag: String,
+	/// Modification time, truncated to whole seconds as HTTP dates are
+	pub last_modified: Option<SystemTime>
+}
+
+impl Validators
+{
+	/**
+	Derive validators from a file's size and modification time, so they change
+	whenever the file does without having to hash its contents.
+
+	# Parameters
+	- `meta`: Metadata of the file being served
+
+	# Returns
+	The validators for the file
+	*/
+	pub fn for_file(meta: &Metadata) -> Validators
+	{
+		let modified = meta.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+		let etag = match modified
+		{
+			Some(since_epoch) => format!("\"{:x}-{:x}{:08x}\"", meta.len(), since_epoch.as_secs(), since_epoch.subsec_nanos()),
+			None => format!("\"{:x}\"", meta.len())
+		};
+		let last_modified = modified.map(|since_epoch| UNIX_EPOCH + std::time::Duration::from_secs(since_epoch.as_secs()));
+		Validators{etag, last_modified}
+	}
+
+	/**
+	Fold something that changes the served body, other than the file itself,
+	into the entity tag, so a copy sent before a change of configuration stops
+	matching. Nothing is added when `transform` is empty.
+
+	# Parameters
+	- `transform`: A description of how the body is altered on the way out
+
+	# Returns
+	The validators with the entity tag adjusted
+
+	# Examples
+	```
+	use c20web::conditional::Validators;
+
+	let plain = Validators{etag: String::from("\"1f-5f5e1000\""), last_modified: None};
+	let injected = plain.clone().varied_by(b"<script src=\"/a.js\"></script>");
+	assert_ne!(injected.etag, plain.etag);
+	assert_eq!(injected.etag, plain.clone().varied_by(b"<script src=\"/a.js\"></script>").etag);
+	assert_eq!(plain.clone().varied_by(b"").etag, plain.etag);
+	```
+	*/
+	pub fn varied_by(mut self, transform: &[u8]) -> Validators
+	{
+		if transform.is_empty()
+		{
+			return self;
+		}
+		let mut hasher = DefaultHasher::new();
+		transform.hash(&mut hasher);
+		self.etag.pop();
+		self.etag = format!("{}-{:x}\"", self.etag, hasher.finish());
+		self
+	}
+
+	/**
+	Put the `ETag` and `Last-Modified` headers on a response.
+
+	# Parameters
+	- `response`: The response for the file these validators describe
+	*/
+	pub fn add_to(&self, response: &mut Response)
+	{
+		response.headers.insert(String::from("ETag"), self.etag.clone());
+		if let Some(modified) = self.last_modified
+		{
+			response.headers.insert(String::from("Last-Modified"), httpdate::fmt_http_date(modified));
+		}
+	}
+}
+
+/**
+What to do with a request after checking its preconditions.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precondition
+{
+	/// Serve the request normally
+	Proceed,
+	/// The client's copy is current, answer 304 Not Modified
+	NotModified,
+	/// A precondition the client required doesn't hold, answer 412 Precondition Failed
+	Failed
+}
+
+/**
+Evaluate a request's conditional headers against the current validators,
+in the order given by RFC 7232 section 6: `If-Match`, or `If-Unmodified-Since`
+when there's no `If-Match`; then `If-None-Match`, or `If-Modified-Since` when
+there's no `If-None-Match`. Dates that can't be parsed are ignored. A file
+modified in the future by the server's clock is never called unmodified,
+since a client may have seen it before it was changed again.
+
+# Parameters
+- `request`: The request being served
+- `validators`: Validators of the file that would be sent
+
+# Returns
+How the request should be answered
+
+# Examples
+```
+use c20web::Request;
+use c20web::conditional::{evaluate, Precondition, Validators};
+
+let validators = Validators{etag: String::from("\"abc\""), last_modified: None};
+let request = Request::parse(Box::new(b"GET / HTTP/1.1\r\nIf-Match: \"xyz\"\r\n\r\n".to_owned())).ok().unwrap();
+assert_eq!(evaluate(&request, &validators), Precondition::Failed);
+let request = Request::parse(Box::new(b"GET / HTTP/1.1\r\nIf-None-Match: \"abc\"\r\n\r\n".to_owned())).ok().unwrap();
+assert_eq!(evaluate(&request, &validators), Precondition::NotModified);
+```
+*/
+pub fn evaluate(request: &Request, validators: &Validators) -> Precondition
+{
+	if let Some(if_match) = request.header("if-match")
+	{
+		if !etag_listed(if_match, &validators.etag)
+		{
+			return Precondition::Failed;
+		}
+	}else if let Some(since) = request.header("if-unmodified-since").and_then(|d| httpdate::parse_http_date(d).ok()){
+		if validators.last_modified.is_none_or(|modified| modified > since)
+		{
+			return Precondition::Failed;
+		}
+	}
+
+	if let Some(if_none_match) = request.header("if-none-match")
+	{
+		if etag_listed_weak(if_none_match, &validators.etag)
+		{
+			return Precondition::NotModified;
+		}
+	}else if let Some(since) = request.header("if-modified-since").and_then(|d| httpdate::parse_http_date(d).ok()){
+		if validators.last_modified.is_some_and(|modified| modified <= since && modified <= SystemTime::now())
+		{
+			return Precondition::NotModified;
+		}
+	}
+	Precondition::Proceed
+}
+
+/**
+Check an `If-Match` list using strong comparison, where `*` matches any
+current representation and weak tags never match. A tag the client got with
+a compressed copy matches the file it was compressed from.
+*/
+fn etag_listed(list: &str, etag: &str) -> bool
+{
+	list.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || strong_match(&without_coding(tag), etag))
+}
+
+/**
+Check an `If-None-Match` list using weak comparison, where `*` matches any
+current representation and `W/"abc"` matches `"abc"`. A tag the client got
+with a compressed copy matches the file it was compressed from.
+*/
+fn etag_listed_weak(list: &str, etag: &str) -> bool
+{
+	list.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || weak_match(&without_coding(tag), etag))
+}
+
+/// Content-codings `etag_for_coding` can put in an entity tag
+const CODINGS: [&str; 2] = ["gzip", "br"];
+
+/**
+RFC 7232 section 2.3.3 has each content-coding of a file carry its own
+strong validator, so the coding goes in the entity tag, inside the quotes.
+
+# Parameters
+- `etag`: Entity tag of the unencoded body, including its quotes
+- `coding`: The content-coding applied to the body
+
+# Returns
+The entity tag for the encoded body
+
+# Examples
+```
+use c20web::conditional::etag_for_coding;
+
+assert_eq!(etag_for_coding("\"1f-5f5e1000\"", "gzip"), "\"1f-5f5e1000-gzip\"");
+assert_eq!(etag_for_coding("W/\"1f\"", "br"), "W/\"1f-br\"");
+```
+*/
+pub fn etag_for_coding(etag: &str, coding: &str) -> String
+{
+	match etag.strip_suffix('"')
+	{
+		Some(unquoted) => format!("{}-{}\"", unquoted, coding),
+		None => String::from(etag)
+	}
+}
+
+/// The entity tag a client sent, without any content-coding `etag_for_coding` put in it
+fn without_coding(tag: &str) -> String
+{
+	for coding in &CODINGS
+	{
+		if let Some(unquoted) = tag.strip_suffix('"').and_then(|unquoted| unquoted.strip_suffix(coding)).and_then(|unquoted| unquoted.strip_suffix('-'))
+		{
+			return format!("{}\"", unquoted);
+		}
+	}
+	String::from(tag)
+}
+
+/**
+# Returns
+true if two entity tags are the same and neither is weak, as RFC 7232 section 2.3.2 defines strong comparison
+*/
+pub fn strong_match(a: &str, b: &str) -> bool
+{
+	a == b && !a.starts_with("W/")
+}
+
+/**
+# Returns
+true if two entity tags are the same once any `W/` prefix is ignored, as RFC 7232 section 2.3.2 defines weak comparison
+
+# Examples
+```
+use c20web::conditional::{strong_match, weak_match};
+
+assert!(weak_match("W/\"abc\"", "\"abc\""));
+assert!(!strong_match("W/\"abc\"", "\"abc\""));
+assert!(!weak_match("\"abc\"", "\"abd\""));
+```
+*/
+pub fn weak_match(a: &str, b: &str) -> bool
+{
+	a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::time::Duration;
+
+	fn request_with(header_lines: &str) -> Request
+	{
+		let raw = format!("GET / HTTP/1.1\r\n{}\r\n", header_lines);
+		match Request::parse(raw.into_bytes().into_boxed_slice())
+		{
+			Ok(req) => req,
+			Err(_) => panic!("Request failed to parse")
+		}
+	}
+
+	#[test]
+	fn preconditions_checked_in_rfc_order()
+	{
+		let modified = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+		let validators = Validators{etag: String::from("\"v1\""), last_modified: Some(modified)};
+		let before = httpdate::fmt_http_date(modified - Duration::from_secs(60));
+		let after = httpdate::fmt_http_date(modified + Duration::from_secs(60));
+
+		assert_eq!(evaluate(&request_with("If-Match: *\r\n"), &validators), Precondition::Proceed);
+		assert_eq!(evaluate(&request_with("If-Match: \"v0\", \"v1\"\r\n"), &validators), Precondition::Proceed);
+		assert_eq!(evaluate(&request_with("If-Match: W/\"v1\"\r\n"), &validators), Precondition::Failed);
+		assert_eq!(evaluate(&request_with(&format!("If-Unmodified-Since: {}\r\n", before)), &validators), Precondition::Failed);
+		assert_eq!(evaluate(&request_with(&format!("If-Unmodified-Since: {}\r\n", after)), &validators), Precondition::Proceed);
+		assert_eq!(evaluate(&request_with("If-Unmodified-Since: yesterday\r\n"), &validators), Precondition::Proceed);
+
+		//If-Match takes the place of If-Unmodified-Since when both are sent
+		assert_eq!(evaluate(&request_with(&format!("If-Match: \"v1\"\r\nIf-Unmodified-Since: {}\r\n", before)), &validators), Precondition::Proceed);
+		//and a failed precondition beats a cache hit
+		assert_eq!(evaluate(&request_with("If-Match: \"v0\"\r\nIf-None-Match: \"v1\"\r\n"), &validators), Precondition::Failed);
+
+		assert_eq!(evaluate(&request_with(&format!("If-Modified-Since: {}\r\n", after)), &validators), Precondition::NotModified);
+		assert_eq!(evaluate(&request_with(&format!("If-Modified-Since: {}\r\n", before)), &validators), Precondition::Proceed);
+		assert_eq!(evaluate(&request_with(&format!("If-None-Match: \"v0\"\r\nIf-Modified-Since: {}\r\n", after)), &validators), Precondition::Proceed);
+	}
+
+	#[test]
+	fn if_none_match_compares_weakly()
+	{
+		let strong = Validators{etag: String::from("\"v1\""), last_modified: None};
+		let weak = Validators{etag: String::from("W/\"v1\""), last_modified: None};
+		for validators in &[&strong, &weak]
+		{
+			assert_eq!(evaluate(&request_with("If-None-Match: W/\"v1\"\r\n"), validators), Precondition::NotModified);
+			assert_eq!(evaluate(&request_with("If-None-Match: \"v0\", \"v1\"\r\n"), validators), Precondition::NotModified);
+			assert_eq!(evaluate(&request_with("If-None-Match: W/\"v0\"\r\n"), validators), Precondition::Proceed);
+			assert_eq!(evaluate(&request_with("If-None-Match: *\r\n"), validators), Precondition::NotModified);
+		}
+		//If-Match stays strong
+		assert_eq!(evaluate(&request_with("If-Match: \"v1\"\r\n"), &weak), Precondition::Failed);
+		assert_eq!(evaluate(&request_with("If-Match: *\r\n"), &weak), Precondition::Proceed);
+	}
+
+	#[test]
+	fn compressed_tags_match_their_file()
+	{
+		let validators = Validators{etag: String::from("\"v1\""), last_modified: None};
+		for coded in &["\"v1-gzip\"", "\"v1-br\""]
+		{
+			assert_eq!(evaluate(&request_with(&format!("If-None-Match: {}\r\n", coded)), &validators), Precondition::NotModified);
+			assert_eq!(evaluate(&request_with(&format!("If-Match: {}\r\n", coded)), &validators), Precondition::Proceed);
+		}
+		assert_eq!(evaluate(&request_with("If-None-Match: \"v1-deflate\"\r\n"), &validators), Precondition::Proceed);
+		assert_eq!(evaluate(&request_with("If-Match: W/\"v1-gzip\"\r\n"), &validators), Precondition::Failed);
+	}
+
+	#[test]
+	fn future_dates_and_mtimes()
+	{
+		let now = SystemTime::now();
+		let tomorrow = httpdate::fmt_http_date(now + Duration::from_secs(86400));
+		let past = Validators{etag: String::from("\"v1\""), last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000))};
+		assert_eq!(evaluate(&request_with(&format!("If-Modified-Since: {}\r\n", tomorrow)), &past), Precondition::NotModified);
+
+		let future = Validators{etag: String::from("\"v1\""), last_modified: Some(now + Duration::from_secs(3600))};
+		assert_eq!(evaluate(&request_with(&format!("If-Modified-Since: {}\r\n", tomorrow)), &future), Precondition::Proceed);
+	}
+}
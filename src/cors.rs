@@ -0,0 +1,79 @@
+use config::Config;
+
+use crate::{Request, Response};
+
+/**
+# Returns
+true if the request is a CORS preflight: an OPTIONS request carrying both
+`Origin` and `Access-Control-Request-Method`. Any other OPTIONS request is
+a plain question about which methods the resource allows.
+*/
+pub fn is_preflight(request: &Request) -> bool
+{
+	request.method == "OPTIONS" && request.header("origin").is_some() && request.header("access-control-request-method").is_some()
+}
+
+/**
+Add the CORS headers to the answer to a preflight, if the origin asking is
+one of `cors_allowed_origins`. The methods offered are the ones in the
+response's `Allow` header. Plain OPTIONS requests, and origins that aren't
+allowed, get the response unchanged.
+
+# Parameters
+- `request`: The OPTIONS request
+- `response`: The answer to it, with its `Allow` header set
+- `settings`: The server configuration to apply
+
+# Examples
+```
+use c20web::{Request, Response};
+use c20web::cors::answer_preflight;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str("cors_allowed_origins = [\"https://app.example.com\"]", config::FileFormat::Toml)).unwrap();
+let mut response = Response::new(204, String::new());
+response.headers.insert(String::from("Allow"), String::from("GET, HEAD, OPTIONS"));
+
+let request = Request::parse(Box::new(b"OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: GET\r\n\r\n".to_owned())).ok().unwrap();
+answer_preflight(&request, &mut response, &settings);
+assert_eq!(response.headers["Access-Control-Allow-Origin"], "https://app.example.com");
+assert_eq!(response.headers["Access-Control-Allow-Methods"], "GET, HEAD, OPTIONS");
+```
+*/
+pub fn answer_preflight(request: &Request, response: &mut Response, settings: &Config)
+{
+	if !is_preflight(request) || response.code < 200 || response.code >= 300
+	{
+		return;
+	}
+	let origin = request.header("origin").unwrap_or_default();
+	let allowed_origins = settings.get::<Vec<String>>("cors_allowed_origins").unwrap_or_default();
+	let allow_origin = if allowed_origins.iter().any(|allowed| allowed == origin)
+	{
+		origin
+	}else if allowed_origins.iter().any(|allowed| allowed == "*"){
+		"*"
+	}else{
+		return;
+	};
+
+	response.headers.insert(String::from("Access-Control-Allow-Origin"), String::from(allow_origin));
+	if let Some(methods) = response.headers.get("Allow").cloned()
+	{
+		response.headers.insert(String::from("Access-Control-Allow-Methods"), methods);
+	}
+	if let Some(requested) = request.header("access-control-request-headers")
+	{
+		response.headers.insert(String::from("Access-Control-Allow-Headers"), String::from(requested));
+	}
+	let max_age = settings.get::<u64>("cors_max_age").unwrap_or(0);
+	if max_age > 0
+	{
+		response.headers.insert(String::from("Access-Control-Max-Age"), max_age.to_string());
+	}
+	//the answer depends on who's asking unless every origin gets the same one
+	if allow_origin != "*"
+	{
+		response.add_vary("Origin");
+	}
+}
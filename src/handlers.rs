@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::{Request, Response, ResourcePath};
+use crate::statics::HANDLERS;
+
+/// Code that answers requests for a path, in place of a file from the webroot
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+/// Code that answers requests for a path with a body it produces a chunk at a time
+pub type StreamingHandler = Arc<dyn Fn(&Request) -> Box<dyn Iterator<Item = Vec<u8>> + Send> + Send + Sync>;
+
+/**
+The handlers registered for each path and method. Paths are matched exactly,
+against the decoded path without its query string.
+*/
+#[derive(Default)]
+pub struct HandlerRegistry
+{
+	routes: HashMap<String, BTreeMap<String, Handler>>
+}
+
+impl HandlerRegistry
+{
+	pub fn new() -> HandlerRegistry
+	{
+		HandlerRegistry::default()
+	}
+
+	/**
+	Answer requests with the given method and path using a handler, replacing
+	any handler already registered for them.
+
+	# Parameters
+	- `method`: The request method, like "GET"
+	- `path`: The exact path to answer, like "/api/status"
+	- `handler`: The code that builds the response
+	*/
+	pub fn register(&mut self, method: &str, path: &str, handler: Handler)
+	{
+		self.routes.entry(String::from(path)).or_default().insert(String::from(method), handler);
+	}
+
+	/**
+	Answer requests with the given method and path using a handler that
+	produces the body lazily. The chunks are sent as they're produced, with
+	chunked transfer coding, and the status is always 200.
+
+	# Parameters
+	- `method`: The request method, like "GET"
+	- `path`: The exact path to answer, like "/export.csv"
+	- `mime`: MIME type of the body
+	- `handler`: The code that produces the body's chunks
+
+	# Examples
+	```
+	use std::sync::Arc;
+	use c20web::Request;
+	use c20web::handlers::HandlerRegistry;
+
+	let mut registry = HandlerRegistry::new();
+	registry.register_streaming("GET", "/count", "text/plain", Arc::new(|_: &Request| {
+		Box::new((1..=1000).map(|n| format!("{}\n", n).into_bytes())) as Box<dyn Iterator<Item = Vec<u8>> + Send>
+	}));
+
+	let request = Request::parse(Box::new(b"GET /count HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+	let response = registry.dispatch(&request).unwrap();
+	assert!(response.stream.unwrap().is_chunked());
+	```
+	*/
+	pub fn register_streaming(&mut self, method: &str, path: &str, mime: &str, handler: StreamingHandler)
+	{
+		let mime = String::from(mime);
+		self.register(method, path, Arc::new(move |request: &Request| Response::streamed(200, &mime, handler(request))));
+	}
+
+	/**
+	List the methods a path can be requested with: the registered ones, plus
+	HEAD when there's a GET handler, plus OPTIONS.
+
+	# Parameters
+	- `path`: The decoded request path
+
+	# Returns
+	The methods in alphabetical order, or nothing if the path has no handlers
+	*/
+	pub fn allowed(&self, path: &str) -> Vec<String>
+	{
+		let methods = match self.routes.get(path)
+		{
+			Some(methods) => methods,
+			None => {return Vec::new();}
+		};
+		let mut allowed: Vec<String> = methods.keys().cloned().collect();
+		for (derived, from) in &[("HEAD", "GET"), ("OPTIONS", "")]
+		{
+			if !methods.contains_key(*derived) && (from.is_empty() || methods.contains_key(*from))
+			{
+				allowed.push(String::from(*derived));
+			}
+		}
+		allowed.sort();
+		allowed
+	}
+
+	/**
+	# Returns
+	true if a handler will answer the request itself, rather than with the
+	derived HEAD, OPTIONS or 405 responses, so it may want the request body
+	*/
+	pub fn handles(&self, request: &Request) -> bool
+	{
+		let path = ResourcePath{resource: request.resource.clone()}.get_decoded();
+		self.routes.get(&path).is_some_and(|methods| methods.contains_key(&request.method))
+	}
+
+	/**
+	Find and run the handler for a request. A path with a GET handler also
+	answers HEAD with it, since the body is left out when a HEAD response is
+	sent, and every path with handlers answers OPTIONS with its `Allow` list,
+	unless handlers for those methods were registered explicitly. Other
+	methods on a path with handlers get 405.
+
+	# Parameters
+	- `request`: The request to answer
+
+	# Returns
+	The handler's response, or None if no handlers are registered for the path
+
+	# Examples
+	```
+	use std::sync::Arc;
+	use c20web::{Request, Response};
+	use c20web::handlers::HandlerRegistry;
+
+	let mut registry = HandlerRegistry::new();
+	registry.register("GET", "/status", Arc::new(|_: &Request| Response::new(200, String::from("OK"))));
+
+	let request = Request::parse(Box::new(b"OPTIONS /status HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+	assert_eq!(registry.dispatch(&request).map(|r| r.headers["Allow"].clone()), Some(String::from("GET, HEAD, OPTIONS")));
+	let request = Request::parse(Box::new(b"GET /elsewhere HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+	assert!(registry.dispatch(&request).is_none());
+	```
+	*/
+	pub fn dispatch(&self, request: &Request) -> Option<Response>
+	{
+		let path = ResourcePath{resource: request.resource.clone()}.get_decoded();
+		let methods = self.routes.get(&path)?;
+		if let Some(handler) = methods.get(&request.method)
+		{
+			return Some(handler(request));
+		}
+		if request.method == "HEAD"
+		{
+			if let Some(handler) = methods.get("GET")
+			{
+				return Some(handler(request));
+			}
+		}
+
+		let allowed = self.allowed(&path).join(", ");
+		let mut response = if request.method == "OPTIONS"
+		{
+			Response::new(204, String::new())
+		}else{
+			Response::new(405, format!("This resource only accepts these methods: {}", allowed))
+		};
+		response.headers.insert(String::from("Allow"), allowed);
+		Some(response)
+	}
+}
+
+/**
+Register a handler with the server, as `HandlerRegistry::register` does.
+
+# Parameters
+- `method`: The request method, like "GET"
+- `path`: The exact path to answer, like "/api/status"
+- `handler`: The code that builds the response
+*/
+pub fn register(method: &str, path: &str, handler: Handler)
+{
+	HANDLERS.write().unwrap_or_else(|e| e.into_inner()).register(method, path, handler);
+}
+
+/**
+Register a streaming handler with the server, as `HandlerRegistry::register_streaming` does.
+
+# Parameters
+- `method`: The request method, like "GET"
+- `path`: The exact path to answer, like "/export.csv"
+- `mime`: MIME type of the body
+- `handler`: The code that produces the body's chunks
+*/
+pub fn register_streaming(method: &str, path: &str, mime: &str, handler: StreamingHandler)
+{
+	HANDLERS.write().unwrap_or_else(|e| e.into_inner()).register_streaming(method, path, mime, handler);
+}
+
+/**
+# Returns
+true if one of the server's registered handlers will answer the request, as `HandlerRegistry::handles` says
+*/
+pub fn handles(request: &Request) -> bool
+{
+	HANDLERS.read().unwrap_or_else(|e| e.into_inner()).handles(request)
+}
+
+/**
+Answer a request with the server's registered handlers, as `HandlerRegistry::dispatch` does.
+
+# Parameters
+- `request`: The request to answer
+
+# Returns
+The handler's response, or None if the request should be served from the webroot
+*/
+pub fn dispatch(request: &Request) -> Option<Response>
+{
+	HANDLERS.read().unwrap_or_else(|e| e.into_inner()).dispatch(request)
+}
@@ -0,0 +1,174 @@
+use std::ops::Index;
+
+/**
+Response headers, in the order they were added. Names are matched without
+regard to case, and a name can appear more than once, since some headers,
+like `Set-Cookie`, can't be combined into a single line.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers
+{
+	entries: Vec<(String, String)>
+}
+
+impl Headers
+{
+	pub fn new() -> Headers
+	{
+		Headers::default()
+	}
+
+	/**
+	Set a header, replacing every value it already has.
+
+	# Parameters
+	- `name`: The header name, like "Cache-Control"
+	- `value`: The value to send
+	*/
+	pub fn insert(&mut self, name: String, value: String)
+	{
+		self.remove(&name);
+		self.entries.push((name, value));
+	}
+
+	/**
+	Add another value for a header, keeping the ones it already has. Each
+	value is sent on a line of its own.
+
+	# Parameters
+	- `name`: The header name, like "Set-Cookie"
+	- `value`: The value to send
+
+	# Examples
+	```
+	use c20web::headers::Headers;
+
+	let mut headers = Headers::new();
+	headers.append(String::from("Set-Cookie"), String::from("session=abc"));
+	headers.append(String::from("set-cookie"), String::from("theme=dark"));
+	assert_eq!(headers.get_all("Set-Cookie"), vec!["session=abc", "theme=dark"]);
+	assert_eq!(headers.get("SET-COOKIE").map(|v| v.as_str()), Some("session=abc"));
+	```
+	*/
+	pub fn append(&mut self, name: String, value: String)
+	{
+		self.entries.push((name, value));
+	}
+
+	/**
+	# Returns
+	The first value of a header, or None if it isn't set
+	*/
+	pub fn get(&self, name: &str) -> Option<&String>
+	{
+		self.entries.iter().find(|(existing, _)| existing.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+	}
+
+	/**
+	# Returns
+	The first value of a header for changing in place, or None if it isn't set
+	*/
+	pub fn get_mut(&mut self, name: &str) -> Option<&mut String>
+	{
+		self.entries.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+	}
+
+	/**
+	# Returns
+	Every value of a header, in the order they were added
+	*/
+	pub fn get_all(&self, name: &str) -> Vec<&str>
+	{
+		self.entries.iter().filter(|(existing, _)| existing.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str()).collect()
+	}
+
+	/**
+	# Returns
+	true if the header has at least one value
+	*/
+	pub fn contains_key(&self, name: &str) -> bool
+	{
+		self.get(name).is_some()
+	}
+
+	/**
+	Take a header out entirely.
+
+	# Returns
+	The first value it had, or None if it wasn't set
+	*/
+	pub fn remove(&mut self, name: &str) -> Option<String>
+	{
+		let mut first = None;
+		let mut kept = Vec::with_capacity(self.entries.len());
+		for (existing, value) in self.entries.drain(..)
+		{
+			if !existing.eq_ignore_ascii_case(name)
+			{
+				kept.push((existing, value));
+			}else if first.is_none(){
+				first = Some(value);
+			}
+		}
+		self.entries = kept;
+		first
+	}
+
+	/**
+	# Returns
+	Every name and value, in the order they were added
+	*/
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &String)>
+	{
+		self.entries.iter().map(|(name, value)| (name, value))
+	}
+
+	/**
+	# Returns
+	The number of header lines
+	*/
+	pub fn len(&self) -> usize
+	{
+		self.entries.len()
+	}
+
+	/**
+	# Returns
+	true if there are no headers at all
+	*/
+	pub fn is_empty(&self) -> bool
+	{
+		self.entries.is_empty()
+	}
+}
+
+impl Index<&str> for Headers
+{
+	type Output = String;
+
+	/// The first value of a header. Panics if the header isn't set, as indexing a missing key in a map does.
+	fn index(&self, name: &str) -> &String
+	{
+		self.get(name).unwrap_or_else(|| panic!("header {} isn't set", name))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn insert_replaces_every_value()
+	{
+		let mut headers = Headers::new();
+		headers.append(String::from("Link"), String::from("</a>"));
+		headers.append(String::from("X-Other"), String::from("1"));
+		headers.append(String::from("link"), String::from("</b>"));
+		headers.insert(String::from("LINK"), String::from("</c>"));
+		assert_eq!(headers.get_all("Link"), vec!["</c>"]);
+		assert_eq!(headers.len(), 2);
+		assert_eq!(headers.remove("x-other"), Some(String::from("1")));
+		assert!(!headers.contains_key("X-Other"));
+	}
+}
@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+
+/**
+Find the language a file is written in from its name, for files named like
+`about.fr.html` or `about.pt-BR.html`. Only two-letter primary language
+subtags are recognized, optionally followed by a two-letter region, so names
+like `jquery.min.js` aren't mistaken for a language.
+
+# Parameters
+- `path`: Path or name of the file
+
+# Returns
+The language tag from the name, or None if the name doesn't have one
+
+# Examples
+```
+use c20web::language::from_filename;
+
+assert_eq!(from_filename("about.fr.html"), Some(String::from("fr")));
+assert_eq!(from_filename("webroot/about.pt-BR.html"), Some(String::from("pt-BR")));
+assert_eq!(from_filename("about.html"), None);
+assert_eq!(from_filename("jquery.min.js"), None);
+```
+*/
+pub fn from_filename<P: AsRef<Path>>(path: P) -> Option<String>
+{
+	let name = path.as_ref().file_name()?.to_str()?;
+	let mut parts = name.rsplitn(3, '.');
+	let _extension = parts.next()?;
+	let tag = parts.next()?;
+	//a name like "fr.html" has no base name, so the "fr" is the name itself
+	parts.next().filter(|base| !base.is_empty())?;
+	if is_language_tag(tag) {Some(String::from(tag))} else {None}
+}
+
+/**
+Pick the language variant of a file that best suits the client, by looking
+for files named like `about.fr.html` next to `about.html`. Languages are
+tried from the client's highest quality value down, and a tag with a region
+that has no variant of its own falls back to its primary language.
+
+# Parameters
+- `path`: Path of the base file
+- `accept_language`: Value of the request's `Accept-Language` header
+
+# Returns
+The path of the best variant that exists, or None if the base file should be served
+
+# Examples
+```no_run
+use c20web::language::negotiate;
+use std::path::PathBuf;
+
+//with webroot/about.html and webroot/about.fr.html on disk
+assert_eq!(negotiate("webroot/about.html", "de;q=0.9, fr-CA"), Some(PathBuf::from("webroot/about.fr.html")));
+assert_eq!(negotiate("webroot/about.html", "de"), None);
+```
+*/
+pub fn negotiate<P: AsRef<Path>>(path: P, accept_language: &str) -> Option<PathBuf>
+{
+	let path = path.as_ref();
+	let name = path.file_name()?.to_str()?;
+	let dot = name.rfind('.').filter(|&dot| dot > 0)?;
+	if from_filename(path).is_some()
+	{
+		return None;
+	}
+	for tag in preferences(accept_language)
+	{
+		let primary = String::from(tag.split('-').next().unwrap_or(""));
+		for candidate in &[tag, primary]
+		{
+			if !is_language_tag(candidate)
+			{
+				continue;
+			}
+			let variant = path.with_file_name(format!("{}.{}{}", &name[..dot], candidate, &name[dot..]));
+			if variant.is_file()
+			{
+				return Some(variant);
+			}
+		}
+	}
+	None
+}
+
+/**
+List the languages in an `Accept-Language` header, most preferred first,
+leaving out any the client gave a weight of zero.
+
+# Returns
+The language tags, in order of preference
+*/
+fn preferences(accept_language: &str) -> Vec<String>
+{
+	let mut weighted: Vec<(String, f32)> = accept_language.split(',').filter_map(|item| {
+		let mut parts = item.split(';');
+		let tag = parts.next().unwrap_or("").trim();
+		let mut weight: f32 = 1.0;
+		for param in parts
+		{
+			let param = param.trim();
+			if param.starts_with("q=") || param.starts_with("Q=")
+			{
+				weight = param[2..].trim().parse().unwrap_or(0.0);
+			}
+		}
+		if tag.is_empty() || tag == "*" || weight <= 0.0 {None} else {Some((String::from(tag), weight))}
+	}).collect();
+	//a stable sort keeps the client's order among equal weights
+	weighted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+	weighted.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/**
+# Returns
+true if `tag` is a two-letter language subtag, optionally followed by a two-letter region subtag
+*/
+fn is_language_tag(tag: &str) -> bool
+{
+	let mut subtags = tag.split('-');
+	let primary = subtags.next().unwrap_or("");
+	let region = subtags.next();
+	subtags.next().is_none()
+		&& primary.len() == 2 && primary.chars().all(|c| c.is_ascii_alphabetic())
+		&& region.is_none_or(|r| r.len() == 2 && r.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn only_language_like_segments_count()
+	{
+		assert_eq!(from_filename("fr.html"), None);
+		assert_eq!(from_filename(".fr.html"), None);
+		assert_eq!(from_filename("archive.tar.gz"), None);
+		assert_eq!(from_filename("notes.en-us-x.txt"), None);
+		assert_eq!(from_filename("notes.e1.txt"), None);
+		assert_eq!(from_filename("release.notes.de.txt"), Some(String::from("de")));
+		assert_eq!(from_filename("about.en-GB.html"), Some(String::from("en-GB")));
+	}
+
+	#[test]
+	fn preferences_follow_quality_values()
+	{
+		assert_eq!(preferences("de;q=0.5, fr, en;q=0.8"), vec!["fr", "en", "de"]);
+		assert_eq!(preferences("es, *;q=0.1, it;q=0"), vec!["es"]);
+		assert_eq!(preferences(""), Vec::<String>::new());
+	}
+}
@@ -1,18 +1,166 @@
+#![allow(clippy::tabs_in_doc_comments)]
+
 #[macro_use]
 extern crate lazy_static;
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::net::Shutdown;
+use std::net::TcpListener;
 use std::net::TcpStream;
-use std::path::Path;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use config::Config;
+use threadpool::ThreadPool;
+use log::{error, warn, info, debug, trace};
 
-use log::{error, warn, /*info, debug,*/ trace, log, Level};
+/// Log target for the one-line-per-request access log
+pub const LOG_ACCESS: &str = "c20web::access";
+/// Log target for the operation of the server itself: startup, I/O, configuration problems
+pub const LOG_SERVER: &str = "c20web::server";
+/// Log target for problems with the content being served, like unknown MIME types or a missing error page
+pub const LOG_CONTENT: &str = "c20web::content";
+/// Log target for events that may indicate an attack, like path traversal attempts or refused clients
+pub const LOG_SECURITY: &str = "c20web::security";
+/// Log target for requests that took longer than `slow_request_threshold_ms`
+pub const LOG_SLOW: &str = "c20web::slow";
 
+/// The most read from a connection at once while waiting for a request head
+const READ_CHUNK_BYTES: usize = 4096;
+/// Byte order mark that starts some UTF-8 text files
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+pub mod accesslog;
+pub mod body;
+pub mod buffers;
+pub mod cache;
+pub mod compression;
+pub mod conditional;
+pub mod cors;
+pub mod handlers;
+pub mod headers;
+pub mod language;
+pub mod limits;
+pub mod listener;
+pub mod metrics;
+pub mod proxy;
+pub mod range;
 pub mod statics;
+pub mod streaming;
+pub mod workers;
 use statics::SETTINGS;
+use statics::DEFAULT_CONFIG;
+use statics::CONNECTIONS_PER_IP;
+use statics::FILE_CACHE;
+use statics::OPEN_FILES;
+use statics::KEEPALIVE_CONNECTIONS;
+use statics::READ_BUFFERS;
+#[cfg(target_os = "linux")]
+use statics::CAPTURED_WEBROOTS;
 use statics::HTTP_RESPONSE_TABLE;
+use statics::CUSTOM_STATUS_CODES;
 use statics::MIME_BY_EXTENSION;
+use compression::Encoding;
+use body::SpooledBody;
+use cache::FileCache;
+use range::ByteRange;
+use metrics::MetricsSink;
+use streaming::{BodyStream, ChunkedStream, FileStream};
+use headers::Headers;
+use accesslog::AccessEntry;
+use conditional::{Precondition, Validators};
+
+/**
+The request line, with its parts borrowed from the buffer it was read into
+instead of copied out of it.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestLine<'a>
+{
+	pub method: &'a str,
+	pub resource: &'a str,
+	pub http_version: &'a str,
+	/// Offset of the line break that ends the request line, where the headers start
+	pub end: usize
+}
+
+impl<'a> RequestLine<'a>
+{
+	/**
+	Find the request line at the start of raw HTTP request data, without
+	allocating anything unless it's malformed.
+
+	# Parameters
+	- `buffer`: The raw request data
+
+	# Returns
+	The request line, or a 400 Response describing what's wrong with it
+
+	# Examples
+	```
+	use c20web::RequestLine;
+
+	let raw = b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+	let line = RequestLine::parse(raw).ok().unwrap();
+	assert_eq!((line.method, line.resource, line.http_version), ("GET", "/hello.html", "HTTP/1.1"));
+	assert_eq!(&raw[line.end..(line.end + 2)], b"\r\n");
+	```
+	*/
+	pub fn parse(buffer: &'a [u8]) -> Result<RequestLine<'a>, Response>
+	{
+		//find the necessary parts in the request
+		let mut index_end_method = 0;
+		let mut index_end_resource = 0;
+		let mut index_end_line = 0;
+		for (index, request_byte) in buffer.iter().enumerate()
+		{
+			if *request_byte == b'\r' || *request_byte == b'\n'
+			{
+				index_end_line = index;
+				break;
+			}else if *request_byte == b' '{
+				if index_end_method == 0 {index_end_method = index;}
+				else if index_end_resource == 0 {index_end_resource = index;}
+			}
+		}
+
+		if index_end_line == 0 || index_end_resource == 0 || index_end_method == 0
+		{
+			Err(Response::new(400, String::from("Malformed request line")))
+		}else{
+			let method: &str = match std::str::from_utf8(&(buffer[0..index_end_method]))
+			{
+				Ok(s) => s,
+				Err(e) => {return Err(Response::new(400, format!("Malformed method name: {}",e)));}
+			};
+			let resource: &str = match std::str::from_utf8(&(buffer[(index_end_method+1)..index_end_resource]))
+			{
+				Ok(s) => s,
+				Err(e) => {return Err(Response::new(400, format!("Malformed resource name: {}",e)));}
+			};
+			let http_version: &str = match std::str::from_utf8(&(buffer[(index_end_resource+1)..index_end_line]))
+			{
+				//some clients leave a space or tab before the line break, which isn't part of the version
+				Ok(s) => s.trim_end_matches([' ', '\t']),
+				Err(e) => {return Err(Response::new(400, format!("Malformed http version: {}",e)));}
+			};
+			//a version we don't speak is answered later with 505, but one that isn't a version at all is a bad request
+			if !Request::is_version_token(http_version)
+			{
+				return Err(Response::new(400, format!("Malformed http version: {}",http_version)));
+			}
+
+			Ok(RequestLine{method, resource, http_version, end: index_end_line})
+		}
+	}
+}
 
 /**
 Represents an HTTP Request.
@@ -22,6 +170,12 @@ pub struct Request
 	pub method: String,
 	pub resource: String,
 	pub http_version: String,
+	/// Header values keyed by lowercase header name. Repeated headers are joined with commas.
+	pub headers: HashMap<String, String>,
+	/// The request body, which is only kept for uploads and requests answered by a handler, and is otherwise empty
+	pub body: Vec<u8>,
+	/// The request body instead, when it was over `request_body_spool_bytes` and went to a temporary file
+	pub spooled_body: Option<SpooledBody>,
 }
 
 impl Request
@@ -45,6 +199,7 @@ impl Request
 	```
 	use c20web::Response;
 	use c20web::Request;
+	use c20web::headers::Headers;
 
 	let buffer = Box::new(b"GET /hello.html HTTP/1.1\r\nUser-Agent: Mozilla/4.0 (compatible; MSIE5.01; Windows NT)\r\nHost: 127.0.0.1:8000\r\n\r\n".to_owned());
 	//Determine our response based on what's in the request
@@ -55,52 +210,199 @@ impl Request
 			//Determine "mime" and "body_content" based on the value of request.resource
 			let mime = "text/html";
 			let body_content = b"Body Content".to_vec();
-			Response{code: 200, mime: String::from(mime), body: body_content}
+			Response{code: 200, mime: String::from(mime), body: body_content, headers: Headers::new(), use_error_page: false, stream: None}
 		},
 		Err(res) => res
 	};
 	```
 	*/
+	#[allow(clippy::boxed_local)]
 	pub fn parse(buffer: Box<[u8]>) -> Result<Request,Response>
 	{
-		//find the necessary parts in the request
-		let mut index_end_method = 0;
-		let mut index_end_resource = 0;
-		let mut index_end_line = 0;
-		for (index, request_byte) in buffer.iter().enumerate()
+		Request::parse_bytes(&buffer)
+	}
+
+	/**
+	Generates a Request object from raw HTTP request data without taking
+	ownership of it, so the caller can parse straight out of its read buffer
+	instead of copying the request out first. Errors are the same as `parse`.
+
+	# Parameters
+	- `buffer`: The raw request data
+
+	# Returns
+	The request, or the Response for the error that was encountered
+
+	# Examples
+	```
+	use c20web::Request;
+
+	let request = Request::parse_bytes(b"HEAD /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n").ok().unwrap();
+	assert_eq!(request.method, "HEAD");
+	assert_eq!(request.header("Host"), Some("localhost"));
+	```
+	*/
+	pub fn parse_bytes(buffer: &[u8]) -> Result<Request,Response>
+	{
+		let line = RequestLine::parse(buffer)?;
+		let headers = Request::parse_headers(&buffer[line.end..])?;
+		Ok(Request{method: String::from(line.method), resource: String::from(line.resource), http_version: String::from(line.http_version), headers, body: Vec::new(), spooled_body: None})
+	}
+
+	/**
+	# Returns
+	A reader for the request body, wherever it was kept, or the error from
+	opening the file it was spooled to
+
+	# Examples
+	```
+	use std::io::Read;
+	use c20web::Request;
+
+	let mut request = Request::parse_bytes(b"PUT /notes.txt HTTP/1.1\r\n\r\n").ok().unwrap();
+	request.body = b"remember the milk".to_vec();
+	let mut body = String::new();
+	request.body_reader().unwrap().read_to_string(&mut body).unwrap();
+	assert_eq!(body, "remember the milk");
+	assert_eq!(request.body_len(), 17);
+	```
+	*/
+	pub fn body_reader(&self) -> io::Result<Box<dyn Read + '_>>
+	{
+		match &self.spooled_body
 		{
-			if *request_byte == b'\r' || *request_byte == b'\n'
-			{
-				index_end_line = index;
-				break;
-			}else if *request_byte == b' '{
-				if index_end_method == 0 {index_end_method = index;}
-				else if index_end_resource == 0 {index_end_resource = index;}
-			}
+			Some(spooled) => Ok(Box::new(spooled.reader()?)),
+			None => Ok(Box::new(&self.body[..]))
 		}
+	}
 
-		if index_end_line == 0 || index_end_resource == 0 || index_end_method == 0
+	/**
+	# Returns
+	The number of bytes in the request body, wherever it was kept
+	*/
+	pub fn body_len(&self) -> u64
+	{
+		self.spooled_body.as_ref().map_or(self.body.len() as u64, SpooledBody::len)
+	}
+
+	/**
+	# Returns
+	true if `version` has the form `HTTP/x.y` with single digits x and y, as
+	RFC 7230 requires, whether or not we support that version
+	*/
+	fn is_version_token(version: &str) -> bool
+	{
+		let digits = version.as_bytes();
+		version.starts_with("HTTP/") && digits.len() == 8
+			&& digits[5].is_ascii_digit() && digits[6] == b'.' && digits[7].is_ascii_digit()
+	}
+
+	/**
+	Parse the header lines that follow the request line, up to the blank line
+	that ends them or the end of the data that was read.
+
+	# Parameters
+	- `raw`: The request data starting at the line break that ends the request line
+
+	# Returns
+	- `OK`: map of lowercase header names to their values
+	- `Err`: a 400 Response describing the malformed header
+	*/
+	fn parse_headers(raw: &[u8]) -> Result<HashMap<String, String>, Response>
+	{
+		//the read buffer is zero-filled past the end of what was actually received
+		let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+		let text = match std::str::from_utf8(&raw[..end])
 		{
-			Err(Response::new(400, String::from("Malformed request line")))
-		}else{
-			let method: &str = match std::str::from_utf8(&(buffer[0..index_end_method]))
+			Ok(s) => s,
+			Err(e) => {return Err(Response::new(400, format!("Malformed headers: {}",e)));}
+		};
+
+		//sized up front so the map is allocated once instead of growing header by header
+		let mut headers = HashMap::<String, String>::with_capacity(text.bytes().filter(|b| *b == b'\n').count());
+		let mut content_lengths = 0;
+		for line in text.split('\n').skip(1).map(|l| l.trim_end_matches('\r'))
+		{
+			if line.is_empty() {break;}
+			let (name, value) = match line.find(':')
 			{
-				Ok(s) => s,
-				Err(e) => {return Err(Response::new(400, format!("Malformed method name: {}",e)));}
+				Some(index) => (&line[..index], line[(index+1)..].trim()),
+				None => {return Err(Response::new(400, format!("Malformed header line: {}",line)));}
 			};
-			let resource: &str = match std::str::from_utf8(&(buffer[(index_end_method+1)..index_end_resource]))
+			if name.is_empty() || name.contains(char::is_whitespace)
 			{
-				Ok(s) => s,
-				Err(e) => {return Err(Response::new(400, format!("Malformed resource name: {}",e)));}
-			};
-			let http_version: &str = match std::str::from_utf8(&(buffer[(index_end_resource+1)..index_end_line]))
+				return Err(Response::new(400, format!("Malformed header name: {}",name)));
+			}
+			if name.eq_ignore_ascii_case("content-length")
 			{
-				Ok(s) => s,
-				Err(e) => {return Err(Response::new(400, format!("Malformed http version: {}",e)));}
-			};
+				content_lengths += 1;
+			}
+			headers.entry(name.to_ascii_lowercase())
+				.and_modify(|existing| {existing.push_str(", "); existing.push_str(value);})
+				.or_insert_with(|| String::from(value));
+		}
 
-			Ok(Request{method: String::from(method), resource: String::from(resource), http_version: String::from(http_version)})
+		/* Disagreement about where the body ends is how request smuggling works, so
+		 * per RFC 7230 section 3.3.3 anything ambiguous about the length is rejected.
+		*/
+		if content_lengths > 1
+		{
+			return Err(Response::new(400, String::from("Multiple Content-Length headers")));
+		}
+		if let Some(length) = headers.get("content-length")
+		{
+			if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit())
+			{
+				return Err(Response::new(400, format!("Invalid Content-Length: {}",length)));
+			}
+			if headers.contains_key("transfer-encoding")
+			{
+				return Err(Response::new(400, String::from("Content-Length and Transfer-Encoding can't be used together")));
+			}
 		}
+		Ok(headers)
+	}
+
+	/**
+	# Returns
+	The value of the named header, if the request had it. Names are case-insensitive.
+
+	# Examples
+	```
+	use c20web::Request;
+
+	let buffer = Box::new(b"GET /hello.html HTTP/1.1\r\nHost: 127.0.0.1:8000\r\nAccept-Encoding: gzip\r\n\r\n".to_owned());
+	let request = Request::parse(buffer).ok().unwrap();
+	assert_eq!(request.header("accept-encoding"), Some("gzip"));
+	assert_eq!(request.header("Host"), Some("127.0.0.1:8000"));
+	assert_eq!(request.header("Referer"), None);
+	```
+	*/
+	pub fn header(&self, name: &str) -> Option<&str>
+	{
+		self.headers.get(&name.to_ascii_lowercase()).map(|v| v.as_str())
+	}
+
+	/**
+	# Returns
+	The options in the `Connection` header, lowercased. Besides `close` and
+	`keep-alive`, these name the headers that apply only to this connection.
+
+	# Examples
+	```
+	use c20web::Request;
+
+	let buffer = Box::new(b"GET / HTTP/1.1\r\nConnection: Keep-Alive, X-Trace\r\nConnection: close\r\n\r\n".to_owned());
+	let tokens = Request::parse(buffer).ok().unwrap().connection_tokens();
+	assert!(tokens.contains("keep-alive") && tokens.contains("x-trace") && tokens.contains("close"));
+	```
+	*/
+	pub fn connection_tokens(&self) -> HashSet<String>
+	{
+		self.header("connection").unwrap_or("").split(',')
+			.map(|token| token.trim().to_ascii_lowercase())
+			.filter(|token| !token.is_empty())
+			.collect()
 	}
 }
 
@@ -111,13 +413,20 @@ pub struct Response
 {
 	pub code: u16,
 	pub mime: String,
-	pub body: Vec::<u8>
+	pub body: Vec::<u8>,
+	/// Headers to send besides Content-Type and Content-Length
+	pub headers: Headers,
+	/// When set, a non-2xx body is a message to be placed in the error page rather than the whole body
+	pub use_error_page: bool,
+	/// A body to copy while sending, like an open file, used in place of `body` when set
+	pub stream: Option<Box<dyn BodyStream>>
 }
 
 impl Response
 {
 	/**
-	Generates a Response object with a default MIME type of text/html.
+	Generates a Response object with a default MIME type of text/html. If the code
+	isn't a success code, the body will be shown inside the error page.
 
 	# Parameters
 	- `code`: HTTP Status code
@@ -140,7 +449,32 @@ impl Response
 	*/
 	pub fn new(code: u16, body: String) -> Response
 	{
-		Response{code, mime:String::from("text/html"), body: body.as_bytes().to_vec()}
+		Response{code, mime:String::from("text/html"), body: body.as_bytes().to_vec(), headers: Headers::new(), use_error_page: true, stream: None}
+	}
+
+	/**
+	Generates a Response whose body is produced while it's being sent, one
+	chunk at a time, so a large generated body never has to be held in memory.
+
+	# Parameters
+	- `code`: HTTP Status code
+	- `mime`: MIME type of the body
+	- `chunks`: The pieces of the body, in order
+
+	# Examples
+	```
+	use c20web::Response;
+
+	let lines = (1..=3).map(|n| format!("line {}\n", n).into_bytes());
+	let response = Response::streamed(200, "text/plain", Box::new(lines));
+	let mut out = Vec::new();
+	response.send(&mut out);
+	assert!(String::from_utf8(out).unwrap().ends_with("Transfer-Encoding: chunked\r\n\r\n7\r\nline 1\n\r\n7\r\nline 2\n\r\n7\r\nline 3\n\r\n0\r\n\r\n"));
+	```
+	*/
+	pub fn streamed(code: u16, mime: &str, chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>) -> Response
+	{
+		Response{code, mime: String::from(mime), body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: Some(Box::new(ChunkedStream::new(chunks)))}
 	}
 
 	/**
@@ -165,41 +499,123 @@ impl Response
 	*/
 	pub fn to_vec(&self) -> Vec::<u8>
 	{
-		let status = if let Some(status_str) = HTTP_RESPONSE_TABLE.get(&self.code)
-		{
-			format!("{} {}",self.code,status_str)
-		}else{
-			warn!("Returning HTTP response code with no name: {}", self.code);
-			format!("{} Unknown",self.code)
-		};
-	
-		let mut body_out: Vec::<u8> = if self.code < 200 || self.code >= 300
+		let status = self.status();
+		let mut body_out: Vec::<u8> = if !self.has_body()
 		{
-			let mut error_page = match fs::read_to_string("error.html")
-			{
-				Err(e) => {
-					warn!("Using default error page because we couldn't find error.html - {}",e);
-					String::from("<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>{}</title></head><body><h1>{}</h1><p>{}</p></body></html>")
-				},
-				Ok(body) => body
-			};
-			error_page = error_page.replacen("{}", &status, 2);
-			let error_descr = String::from_utf8_lossy(&self.body);
-			error_page.replacen("{}", &error_descr, 1).as_bytes().to_vec()
+			Vec::new()
+		}else if self.use_error_page && (self.code < 200 || self.code >= 300){
+			self.error_page(&status)
 		}else{
 			self.body.to_owned()
 		};
 
-		let mut out = (format!("HTTP/1.1 {}\r\nContent-Type: {};\r\nContent-Length: {};\r\n\r\n", status, self.mime, body_out.len())).as_bytes().to_vec();
+		//sorted by name, but a repeated header keeps its values in the order they were added
+		let mut header_lines: Vec<(&String, &String)> = self.headers.iter().collect();
+		header_lines.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+		let extra_headers: String = header_lines.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+
+		//a streamed body follows the head separately, once the head is sent
+		let framing = match &self.stream
+		{
+			_ if !self.has_body() => String::new(),
+			Some(stream) if stream.is_chunked() => String::from("Transfer-Encoding: chunked\r\n"),
+			Some(stream) => format!("Content-Length: {};\r\n", stream.len()),
+			None => format!("Content-Length: {};\r\n", body_out.len())
+		};
+		let mut out = (format!("HTTP/1.1 {}\r\nContent-Type: {};\r\n{}{}\r\n", status, self.mime, framing, extra_headers)).as_bytes().to_vec();
 		out.append(&mut body_out);
 		out
 	}
 
+	/**
+	Put the error page around the message in the body now rather than when
+	the response is sent, so the page can be treated like any other body.
+	Does nothing unless the response would be shown in the error page.
+	*/
+	pub fn fill_error_page(&mut self)
+	{
+		if self.use_error_page && (self.code < 200 || self.code >= 300)
+		{
+			self.body = self.error_page(&self.status());
+			self.use_error_page = false;
+		}
+	}
+
+	/**
+	# Returns
+	false for the status codes that never have a body, so the response has
+	neither a body nor Content-Length: 1xx, 204 No Content, and 304 Not Modified
+	*/
+	fn has_body(&self) -> bool
+	{
+		!(self.code < 200 || self.code == 204 || self.code == 304)
+	}
+
+	/// The code and reason phrase for the status line, like "404 Not Found"
+	fn status(&self) -> String
+	{
+		if let Some(status_str) = reason_phrase(self.code)
+		{
+			format!("{} {}",self.code,status_str)
+		}else{
+			warn!(target: LOG_CONTENT, "Returning HTTP response code with no name: {}", self.code);
+			format!("{} Unknown",self.code)
+		}
+	}
+
+	/// The error page with this response's status and message filled in
+	fn error_page(&self, status: &str) -> Vec<u8>
+	{
+		let error_page = match fs::read_to_string("error.html")
+		{
+			Err(e) => {
+				warn!(target: LOG_CONTENT, "Using default error page because we couldn't find error.html - {}",e);
+				String::from("<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>{{status}}</title></head><body><h1>{{status}}</h1><p>{{message}}</p></body></html>")
+			},
+			Ok(body) => body
+		};
+		let error_descr = String::from_utf8_lossy(&self.body);
+		render_error_page(&error_page, self.code, status, &error_descr).into_bytes()
+	}
+
+	/**
+	Record that this response was chosen based on the given request header, so
+	caches know not to serve it to clients that send a different value.
+
+	# Parameters
+	- `header`: Name of the request header the response depends on
+
+	# Examples
+	```
+	use c20web::Response;
+
+	let mut resp = Response::new(200, String::from("Bonjour"));
+	resp.add_vary("Accept-Encoding");
+	resp.add_vary("Accept-Language");
+	resp.add_vary("accept-encoding");
+	assert_eq!(resp.headers["Vary"], "Accept-Encoding, Accept-Language");
+	```
+	*/
+	pub fn add_vary(&mut self, header: &str)
+	{
+		let mut vary = self.headers.get("Vary").cloned().unwrap_or_default();
+		if vary.split(',').any(|existing| existing.trim().eq_ignore_ascii_case(header))
+		{
+			return;
+		}
+		if !vary.is_empty()
+		{
+			vary.push_str(", ");
+		}
+		vary.push_str(header);
+		self.headers.insert(String::from("Vary"), vary);
+	}
+
 	/**
 	Send this response out over the given stream.
 
 	# Parameters
-	- `stream`: The stream to which we write the response
+	- `stream`: The stream to which we write the response, or a mutable reference to it
 
 	# Examples
 	```no_run
@@ -216,27 +632,65 @@ impl Response
     }
 	```
 	*/
-	pub fn send(&self, mut stream: TcpStream)
+	pub fn send<W: Write>(&self, mut stream: W) -> usize
 	{
-		let write_res = stream.write(&(self.to_vec()));
-		match write_res
-		{
-			Ok(_) => {},
-			Err(em) => {error!("Write error: {}",em);}
-		}
-		
-		let flush_res = stream.flush();
-		match flush_res
+		let mut written = Response::write_out(&self.to_vec(), &mut stream);
+		if let Some(body) = self.stream.as_ref().filter(|_| self.has_body())
 		{
-			Ok(_) => {},
-			Err(em) => {error!("Flush error: {}",em);}
+			let sent = if body.is_chunked() {streaming::write_chunked(body.as_ref(), &mut stream)} else {body.write_to(&mut stream)};
+			match sent.and_then(|n| stream.flush().map(|_| n))
+			{
+				Ok(n) => {written += n as usize;},
+				Err(em) => {log_write_error("Error streaming file", &em);}
+			}
 		}
+		written
 	}
-}
 
-/**
-Represents the "resource" portion of the first line of an HTTP Request.
-*/
+	/**
+	Write the response to a stream like `send`, but leave out the body, as
+	the answer to a HEAD request. The headers still describe the body that
+	would have been sent.
+
+	# Parameters
+	- `stream`: Where to write the response
+
+	# Returns
+	Number of bytes written
+	*/
+	pub fn send_head<W: Write>(&self, stream: W) -> usize
+	{
+		let mut out = self.to_vec();
+		if let Some(end) = out.windows(4).position(|window| window == b"\r\n\r\n")
+		{
+			out.truncate(end + 4);
+		}
+		Response::write_out(&out, stream)
+	}
+
+	/// Write out a complete response, logging any failure, and return how many bytes were written
+	fn write_out<W: Write>(out: &[u8], mut stream: W) -> usize
+	{
+		let write_res = stream.write_all(out);
+		let written = match write_res
+		{
+			Ok(_) => out.len(),
+			Err(em) => {log_write_error("Write error", &em); 0}
+		};
+		
+		let flush_res = stream.flush();
+		match flush_res
+		{
+			Ok(_) => {},
+			Err(em) => {log_write_error("Flush error", &em);}
+		}
+		written
+	}
+}
+
+/**
+Represents the "resource" portion of the first line of an HTTP Request.
+*/
 pub struct ResourcePath
 {
 	pub resource: String
@@ -267,10 +721,176 @@ impl ResourcePath
 	*/
 	pub fn get_path(&self, webroot: String) -> String
 	{
-		let path = self.resource.replacen(&"/",&"",1);
+		let path = self.get_decoded().replacen("/","",1);
 		format!("{}/{}", webroot, path)
 	}
 
+	/**
+	Get the path portion of the resource as it would appear on the filesystem:
+	without any query string, and with percent-encoding decoded. Invalid escape
+	sequences are left as they are. Decoding happens exactly once, so an escape
+	that was itself encoded comes out as an escape, not the character it names.
+
+	# Returns
+	The decoded path
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	let res = ResourcePath{resource: String::from("/my%20file.html?version=2")};
+	assert_eq!(res.get_decoded(), String::from("/my file.html"));
+	let res = ResourcePath{resource: String::from("/%252e%252e%252fetc")};
+	assert_eq!(res.get_decoded(), String::from("/%2e%2e%2fetc"));
+	```
+	*/
+	pub fn get_decoded(&self) -> String
+	{
+		let path = match self.resource.find('?')
+		{
+			Some(index) => &self.resource[..index],
+			None => &self.resource
+		};
+		let raw = path.as_bytes();
+		let mut decoded = Vec::<u8>::with_capacity(raw.len());
+		let mut index = 0;
+		while index < raw.len()
+		{
+			if raw[index] == b'%' && index + 2 < raw.len()
+			{
+				if let (Some(high), Some(low)) = ((raw[index+1] as char).to_digit(16), (raw[index+2] as char).to_digit(16))
+				{
+					decoded.push((high * 16 + low) as u8);
+					index += 3;
+					continue;
+				}
+			}
+			decoded.push(raw[index]);
+			index += 1;
+		}
+		String::from_utf8_lossy(&decoded).into_owned()
+	}
+
+	/**
+	Check whether the resource tries to climb out of the directory it's
+	resolved against, by way of `..` segments, encoded or not. Backslashes
+	count as separators, as they do on Windows.
+
+	# Returns
+	true if the resource contains a parent-directory segment
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	assert!(ResourcePath{resource: String::from("/../etc/passwd")}.is_traversal());
+	assert!(ResourcePath{resource: String::from("/images/%2e%2e/%2E%2E/etc/passwd")}.is_traversal());
+	assert!(!ResourcePath{resource: String::from("/images/cat..jpg")}.is_traversal());
+	```
+	*/
+	pub fn is_traversal(&self) -> bool
+	{
+		self.get_decoded().split(['/', '\\']).any(|segment| segment == "..")
+	}
+
+	/**
+	Check whether the decoded path has anything Windows would read as a way
+	out of the webroot: a backslash, which also covers UNC paths like
+	`\\server\share`, or a drive letter like `C:`. These are harmless on
+	other systems, but are refused everywhere so the same requests are
+	answered the same way whatever the server runs on.
+
+	# Returns
+	true if the path has a backslash or a drive letter
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	assert!(ResourcePath{resource: String::from("/C:/secret")}.has_windows_path_components());
+	assert!(ResourcePath{resource: String::from("/files/d%3A/secret")}.has_windows_path_components());
+	assert!(ResourcePath{resource: String::from("/%5C%5Cserver/share")}.has_windows_path_components());
+	assert!(!ResourcePath{resource: String::from("/notes/10:30.txt")}.has_windows_path_components());
+	```
+	*/
+	pub fn has_windows_path_components(&self) -> bool
+	{
+		let decoded = self.get_decoded();
+		let is_drive = |segment: &str| {
+			let bytes = segment.as_bytes();
+			bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+		};
+		decoded.contains('\\') || decoded.split('/').any(is_drive)
+	}
+
+	/**
+	Check whether the path was encoded more than once around a dot or a path
+	separator, like `%252e%252e%252f`, which decodes to `%2e%2e%2f`. Decoding
+	that again would give `../`, so a filter or proxy in front of the server
+	that decodes repeatedly could be fooled into letting a traversal through.
+
+	# Returns
+	true if the decoded path still has an escape for `.`, `/` or `\`
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	assert!(ResourcePath{resource: String::from("/%252e%252e%252fetc/passwd")}.has_double_encoding());
+	assert!(ResourcePath{resource: String::from("/a%255Cb")}.has_double_encoding());
+	assert!(!ResourcePath{resource: String::from("/100%2525.html")}.has_double_encoding());
+	```
+	*/
+	pub fn has_double_encoding(&self) -> bool
+	{
+		let decoded = self.get_decoded().to_ascii_lowercase();
+		["%2e", "%2f", "%5c"].iter().any(|escape| decoded.contains(escape))
+	}
+
+	/**
+	Check whether the decoded path contains a null byte or another ASCII
+	control character, which no real file name needs and which can make the
+	filesystem see a different name than the server checked.
+
+	# Returns
+	true if the path has a control character, encoded or not
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	assert!(ResourcePath{resource: String::from("/foo%00bar")}.has_control_characters());
+	assert!(ResourcePath{resource: String::from("/foo%0Abar")}.has_control_characters());
+	assert!(!ResourcePath{resource: String::from("/foo%20bar?x=%00")}.has_control_characters());
+	```
+	*/
+	pub fn has_control_characters(&self) -> bool
+	{
+		self.get_decoded().chars().any(|c| c.is_ascii_control())
+	}
+
+	/**
+	Count the segments of the decoded path, ignoring the empty and `.`
+	segments that repeated or trailing slashes and `/./` produce, since those
+	don't lead any deeper into the filesystem.
+
+	# Returns
+	The number of path segments
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	assert_eq!(ResourcePath{resource: String::from("/")}.segment_count(), 0);
+	assert_eq!(ResourcePath{resource: String::from("/a/b/c.html?x=/y/z")}.segment_count(), 3);
+	assert_eq!(ResourcePath{resource: String::from("//a/./b%2Fc/")}.segment_count(), 3);
+	```
+	*/
+	pub fn segment_count(&self) -> usize
+	{
+		self.get_decoded().split('/').filter(|segment| !segment.is_empty() && *segment != ".").count()
+	}
+
 	/**
 	Get the extension of the file indicated by this resource string. This is
 	mainly for later determination of the MIME type, so if there is any
@@ -290,7 +910,7 @@ impl ResourcePath
 	*/
 	pub fn get_extension(&self) -> String
 	{
-		match Path::new(&self.resource).extension(){
+		match Path::new(&self.get_decoded()).extension(){
 			Some(x) => match x.to_str(){
 					Some(xs) => String::from(xs),
 					None => String::from("")
@@ -299,6 +919,24 @@ impl ResourcePath
 		}
 	}
 
+	/**
+	# Returns
+	The name of the file indicated by this resource, without any directories.
+
+	# Examples
+	```
+	use c20web::ResourcePath;
+
+	let res = ResourcePath{resource: String::from("/downloads/annual%20report.pdf")};
+	assert_eq!(res.get_filename(), String::from("annual report.pdf"));
+	```
+	*/
+	pub fn get_filename(&self) -> String
+	{
+		let path = self.get_decoded();
+		String::from(path.rsplit('/').next().unwrap_or(""))
+	}
+
 	/**
 	# Returns
 	The MIME type associated with the extension of the file indicated by
@@ -313,14 +951,14 @@ impl ResourcePath
 	assert_eq!(mime, String::from("image/jpeg"));
 	```
 	*/
-	pub fn get_mime(&self) -> &str
+	pub fn get_mime(&self) -> &'static str
 	{
 		let extension = self.get_extension();
 		if let Some(found_mime) = MIME_BY_EXTENSION.get(&extension)
 		{
 			found_mime
 		}else{
-			warn!("Could not find MIME type for file extension: {}", extension);
+			warn!(target: LOG_CONTENT, "Could not find MIME type for file extension: {}", extension);
 			"text/plain"
 		}
 	}
@@ -349,121 +987,4803 @@ for stream in listener.incoming()
 }
 ```
 */
-pub fn handle_connection(mut stream: TcpStream)
+pub fn handle_connection(stream: TcpStream)
 {
-	trace!("Starting to process request.");
+	trace!(target: LOG_SERVER, "Starting to process request.");
 	let settings = match SETTINGS.read(){
 		Ok(r) => r,
-		Err(e) => {error!("Couldn't get config in request thread: {}",e); return;}
+		Err(e) => {error!(target: LOG_SERVER, "Couldn't get config in request thread: {}",e); return;}
 	};
-	let webroot = match settings.get::<String>("webroot"){
+	serve_connection(stream, &settings);
+}
+
+/**
+Hand a new connection to the worker pool, or turn it away with a 503 if
+there's no usable pool or `max_queued` connections are already waiting for
+a worker. Turning connections away is quick, so an overloaded server keeps
+answering instead of piling up work it can't get to.
+
+# Parameters
+- `pool`: The worker pool, or None if it couldn't be started
+- `stream`: The TCP Stream of the new connection
+- `max_queued`: The most connections that may wait for a worker, zero for no limit
+*/
+pub fn dispatch_connection(pool: Option<&ThreadPool>, stream: TcpStream, max_queued: usize)
+{
+	match pool
+	{
+		Some(pool) if max_queued == 0 || pool.queued_count() < max_queued => pool.execute(move ||{handle_connection(stream);}),
+		Some(_) => {
+			warn!(target: LOG_SERVER, "Refusing connection: {} connections are already waiting for a worker", max_queued);
+			shed_connection(stream);
+		},
+		None => shed_connection(stream)
+	}
+}
+
+/**
+Answer a connection with a 503 without reading its request, from whichever
+thread accepted it.
+
+# Parameters
+- `stream`: The TCP Stream of the connection to turn away
+*/
+fn shed_connection(stream: TcpStream)
+{
+	let peer_ip = stream.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|_| String::from("unknown"));
+	//the accepting thread can't afford to wait on a client that isn't reading
+	let _timeout_res = stream.set_write_timeout(Some(Duration::from_secs(1)));
+	let _shutdown_res = stream.shutdown(Shutdown::Read);
+	let mut response = Response::new(503, String::from("The server is too busy to handle your request right now."));
+	response.headers.insert(String::from("Connection"), String::from("close"));
+	if let Ok(settings) = SETTINGS.read()
+	{
+		add_retry_after(&mut response, &settings);
+	}
+	accesslog::log_access(response.code, format!("From: {} Response code: {}", peer_ip, response.code));
+	response.send(&stream);
+}
+
+/**
+Start listening on an address, trying again with exponential backoff if the
+address is in use, as it can be briefly while an old instance shuts down.
+
+# Parameters
+- `listen_addr`: The address to listen on
+- `options`: Socket options to apply before binding
+- `retries`: How many more times to try after the first failure
+- `retry_delay`: How long to wait before the first retry, doubling after each one
+
+# Returns
+The listener, or the error from the last attempt
+
+# Examples
+```
+use std::time::Duration;
+use c20web::bind_listener;
+use c20web::listener::ListenOptions;
+
+let listener = bind_listener("127.0.0.1:0", ListenOptions::default(), 3, Duration::from_millis(100)).unwrap();
+assert!(listener.local_addr().unwrap().port() != 0);
+```
+*/
+pub fn bind_listener(listen_addr: &str, options: listener::ListenOptions, retries: usize, retry_delay: Duration) -> io::Result<TcpListener>
+{
+	let mut delay = retry_delay;
+	let mut attempt = 0;
+	loop
+	{
+		match listener::bind(listen_addr, options)
+		{
+			Ok(listener) => {return Ok(listener);},
+			Err(e) if attempt < retries => {
+				warn!(target: LOG_SERVER, "Couldn't bind to {}, trying again in {} ms: {}", listen_addr, delay.as_millis(), e);
+				std::thread::sleep(delay);
+				delay *= 2;
+				attempt += 1;
+			},
+			Err(e) => {return Err(e);}
+		}
+	}
+}
+
+/**
+Serve every request that arrives on a connection, using the given settings,
+until either side closes it. Requests are answered in order; the connection
+stays open between them unless the client asks otherwise, an error makes the
+rest of the input unusable, or one of the keep-alive limits is reached.
+
+# Parameters
+- `stream`: The TCP Stream of the connection we are to handle
+- `settings`: The server configuration to apply
+
+# Examples
+```no_run
+use std::net::TcpListener;
+use c20web::serve_connection;
+use c20web::statics::DEFAULT_CONFIG;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+
+let listener = TcpListener::bind("127.0.0.1:8000").unwrap();
+for stream in listener.incoming()
+{
+	serve_connection(stream.unwrap(), &settings);
+}
+```
+*/
+pub fn serve_connection(stream: TcpStream, settings: &Config)
+{
+	serve_connection_with_metrics(stream, settings, &*metrics::sink());
+}
+
+/**
+Serve a connection like `serve_connection` does, reporting each request to
+the given metrics sink instead of the installed one.
+
+# Parameters
+- `stream`: The TCP Stream of the connection we are to handle
+- `settings`: The server configuration to apply
+- `metrics`: Where to report each request that gets answered
+*/
+pub fn serve_connection_with_metrics(mut stream: TcpStream, settings: &Config, metrics: &dyn MetricsSink)
+{
+	let request_max_bytes = match settings.get::<usize>("request_max_bytes"){
 		Ok(r) => r,
-		Err(e) => {error!("webroot missing from config: {}",e); return;}
+		Err(e) => {error!(target: LOG_SERVER, "request_max_bytes missing from config: {}",e); return;}
 	};
-	let request_max_bytes = match settings.get::<usize>("request_max_bytes"){
+	let slow_request_threshold = match settings.get::<u64>("slow_request_threshold_ms").unwrap_or(0)
+	{
+		0 => None,
+		ms => Some(Duration::from_millis(ms))
+	};
+	let request_body_drain_max_bytes = settings.get::<usize>("request_body_drain_max_bytes").unwrap_or(0);
+	let max_body_bytes = settings.get::<usize>("max_body_bytes").unwrap_or(0);
+	let reject_get_bodies = settings.get::<bool>("reject_get_bodies").unwrap_or(false);
+	let reject_trailing_bytes = settings.get::<bool>("reject_trailing_bytes").unwrap_or(false);
+	let upload_max_bytes = settings.get::<usize>("upload_max_bytes").unwrap_or(0);
+	let handler_body_max_bytes = settings.get::<usize>("handler_body_max_bytes").unwrap_or(0);
+	let request_body_spool_bytes = settings.get::<usize>("request_body_spool_bytes").unwrap_or(0);
+	let request_body_spool_dir = match settings.get::<String>("request_body_spool_dir").unwrap_or_default()
+	{
+		dir if dir.is_empty() => std::env::temp_dir(),
+		dir => PathBuf::from(dir)
+	};
+	let expect_continue_max_bytes = settings.get::<usize>("expect_continue_max_bytes").unwrap_or(0);
+	let combined_access_log = settings.get::<String>("access_log_format").unwrap_or_default() == "combined";
+	let read_buffer_pool_size = settings.get::<usize>("read_buffer_pool_size").unwrap_or(0);
+	let read_buffer_bytes = settings.get::<usize>("read_buffer_bytes").unwrap_or(READ_CHUNK_BYTES);
+	let max_connections_per_ip = match settings.get::<usize>("max_connections_per_ip"){
+		Ok(r) => r,
+		Err(e) => {error!(target: LOG_SERVER, "max_connections_per_ip missing from config: {}",e); return;}
+	};
+	let keepalive_timeout_ms = match settings.get::<u64>("keepalive_timeout_ms"){
+		Ok(r) => r,
+		Err(e) => {error!(target: LOG_SERVER, "keepalive_timeout_ms missing from config: {}",e); return;}
+	};
+	let keepalive_max_requests = match settings.get::<usize>("keepalive_max_requests"){
 		Ok(r) => r,
-		Err(e) => {error!("request_max_bytes missing from config: {}",e); return;}
+		Err(e) => {error!(target: LOG_SERVER, "keepalive_max_requests missing from config: {}",e); return;}
+	};
+	let max_keepalive_connections = settings.get::<usize>("max_keepalive_connections").unwrap_or(0);
+	let request_timeout = match settings.get::<u64>("request_timeout_ms"){
+		Ok(0) => None,
+		Ok(r) => Some(Duration::from_millis(r)),
+		Err(e) => {error!(target: LOG_SERVER, "request_timeout_ms missing from config: {}",e); return;}
+	};
+	let header_timeout = match settings.get::<u64>("header_timeout_ms").unwrap_or(0)
+	{
+		0 => None,
+		ms => Some(Duration::from_millis(ms))
 	};
 
-	trace!("Creating buffer");
-	let mut buffer = vec![0u8; request_max_bytes+1].into_boxed_slice();
-	trace!("Buffer created. Reading input");
-	let request_result = stream.read(&mut buffer);
+	let peer_addr = stream.peer_addr();
+	let peer_ip = match &peer_addr
+	{
+		Ok(r) => r.to_string(),
+		Err(e)=> {warn!(target: LOG_SERVER, "Couldn't get peer IP: {}",e); String::from("Unknown")}
+	};
 
-	/* Any output won't make it to the browser if there is still input left to be read.
-	 * In order to avoid DoS attacks by enforcing max request size, and still
-	 * send the appropriate error message back, we need to discard the rest of
-	 * the input without actually reading it in. Even calling shutdown on Read doesn't
-	 * always do this but there doesn't seem to be any better way.
-	*/
-	let _shutdown_res = stream.shutdown(Shutdown::Read);
+	//hold a slot for this peer until the connection is finished, or turn it away if it already has too many
+	let _ip_slot = match &peer_addr
+	{
+		Ok(addr) => match CONNECTIONS_PER_IP.acquire(addr.ip(), max_connections_per_ip, Duration::ZERO)
+		{
+			Some(slot) => Some(slot),
+			None => {
+				warn!(target: LOG_SECURITY, "Refusing connection from {}: already at the limit of {} simultaneous connections", addr.ip(), max_connections_per_ip);
+				let _shutdown_res = stream.shutdown(Shutdown::Read);
+				let mut response = Response::new(503, String::from("Too many simultaneous connections from your address."));
+				add_retry_after(&mut response, settings);
+				accesslog::log_access(response.code, format!("From: {} Response code: {}", peer_ip, response.code));
+				let started = Instant::now();
+				let written = response.send(stream);
+				metrics.record_request("-", response.code, written, started.elapsed());
+				return;
+			}
+		},
+		Err(_) => None
+	};
 
-	trace!("Request read. Starting analysis");
-	let response: Response = match request_result
+	//anything the client sent beyond the end of the request we're working on
+	let mut pending = READ_BUFFERS.take(read_buffer_bytes, read_buffer_pool_size);
+	let mut served: usize = 0;
+	//held while the connection waits for its next request
+	let mut idle_slot = None;
+	loop
 	{
-		Ok(num_bytes) => {
-			if num_bytes >= request_max_bytes
-			{
-				Response::new(413, String::from(""))
-			}else{
-				match Request::parse(buffer)
+		//a new connection gets request_timeout_ms to start its first request, so a silent client can't hold a worker forever;
+		//after that an idle connection only gets the keep-alive timeout
+		let idle_timeout = if served > 0 {Some(Duration::from_millis(keepalive_timeout_ms))} else {request_timeout};
+		//once a request starts arriving, reading, processing and answering it all have to fit in request_timeout_ms
+		let mut deadline: Option<Instant> = None;
+		let mut arrived: Option<Instant> = None;
+
+		trace!(target: LOG_SERVER, "Reading input");
+		let timeouts = HeadTimeouts{idle: idle_timeout, request: request_timeout, header: header_timeout, first_request: served == 0};
+		let head = read_request_head(&mut stream, &mut pending, request_max_bytes, timeouts, &mut deadline, &mut arrived);
+		//a request arrived or the connection is done, so it's not idle anymore either way
+		drop(idle_slot.take());
+
+		//checkpoints for the phase timings in the trace log
+		let read_done = Instant::now();
+		let started = arrived.unwrap_or(read_done);
+		trace!(target: LOG_SERVER, "Request read in {} µs. Starting analysis", read_done.duration_since(started).as_micros());
+		let mut method = String::from("-");
+		let mut resource = String::from("-");
+		let mut http_version = String::from("-");
+		let mut referer: Option<String> = None;
+		let mut user_agent: Option<String> = None;
+		let (mut response, mut keep_alive) = match head
+		{
+			RequestHead::Complete(head_len) => {
+				//parsed in place, then dropped from `pending`, which keeps its capacity for the rest of the connection
+				let parsed = Request::parse_bytes(&pending[..head_len]);
+				pending.drain(..head_len);
+				trace!(target: LOG_SERVER, "Request parsed in {} µs", read_done.elapsed().as_micros());
+				match parsed
 				{
-					Ok(request) => {
-						//determine whether we currently support the features necessary to fulfill the request
-						if request.method != "GET"
+					Ok(mut request) => {
+						workers::set_request_context(format!("{} {} {} from {}", request.method, request.resource, request.http_version, peer_ip));
+						method = request.method.clone();
+						resource = request.resource.clone();
+						http_version = request.http_version.clone();
+						referer = request.header("referer").map(String::from);
+						user_agent = request.header("user-agent").map(String::from);
+						let mut keep_alive = wants_keep_alive(&request);
+						let body_len = request.header("content-length").and_then(|l| l.trim().parse::<usize>().ok()).unwrap_or(0);
+						let uploading = request.method == "PUT" && accepts_upload(&request, settings);
+						//handlers get the body whatever the method, so APIs can take POST, PATCH and the like
+						let handled = !uploading && handlers::handles(&request);
+						let body_max_bytes = if uploading {upload_max_bytes} else {handler_body_max_bytes};
+						let mut body_incomplete = false;
+						let mut body_unwritable = false;
+						//a client waiting for 100 Continue hasn't sent its body yet, so one that's too big can be refused before it's sent
+						let expects_continue = body_len > 0 && request.header("expect").is_some_and(|expect| expect.split(',').any(|e| e.trim().eq_ignore_ascii_case("100-continue")));
+						let unmet_expectation = unmet_expectation(&request).is_some();
+						let refused_before_body = expects_continue && (unmet_expectation || (expect_continue_max_bytes != 0 && body_len > expect_continue_max_bytes));
+						if refused_before_body
 						{
-							Response::new(501, String::from("This server only accepts GET requests."))
-						}else if request.http_version != "HTTP/1.1"{
-							Response::new(505, String::from("This server only speaks HTTP/1.1"))
+							trace!(target: LOG_SERVER, "Refusing a {} byte request body before it's sent", body_len);
 						}else{
-							//attempt to load the requested file
-							let res = ResourcePath{resource: request.resource};
-							let path = res.get_path(webroot);
-							trace!("Requesting page: {}",&path);
-							let mime = res.get_mime();
-							match std::fs::read(&path)
+							if expects_continue && pending.is_empty()
+							{
+								let _continue_res = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+							}
+							if (uploading || handled) && body_len > 0 && body_len <= body_max_bytes
 							{
-								Ok(bytes) => Response{code: 200, mime: String::from(mime), body: bytes},
-								Err(e) => Response::new(404, format!("{}",e))
+								//big bodies go to disk, so a few large uploads at once can't use up the memory
+								let read = if request_body_spool_bytes != 0 && body_len > request_body_spool_bytes
+								{
+									spool_body(&mut stream, &mut pending, body_len, deadline, &request_body_spool_dir).map(|spooled| match spooled
+									{
+										Ok(spooled) => {request.spooled_body = Some(spooled);},
+										Err(e) => {
+											error!(target: LOG_SERVER, "Couldn't spool a {} byte request body to {}: {}", body_len, request_body_spool_dir.display(), e);
+											body_unwritable = true;
+										}
+									})
+								}else{
+									read_body(&mut stream, &mut pending, body_len, deadline).map(|body| {request.body = body;})
+								};
+								match read
+								{
+									Some(()) => {},
+									None => {
+										trace!(target: LOG_SERVER, "Couldn't read a {} byte request body, closing the connection after responding", body_len);
+										let _shutdown_res = stream.shutdown(Shutdown::Read);
+										keep_alive = false;
+										body_incomplete = true;
+									}
+								}
+							}else if body_len > 0 && !drain_body(&mut stream, &mut pending, body_len, request_body_drain_max_bytes, deadline){
+								trace!(target: LOG_SERVER, "Couldn't drain a {} byte request body, closing the connection after responding", body_len);
+								let _shutdown_res = stream.shutdown(Shutdown::Read);
+								keep_alive = false;
 							}
 						}
+						//the body was still drained if it could be, so the client is listening for the answer
+						if refused_before_body && unmet_expectation
+						{
+							(respond(request, settings), false)
+						}else if refused_before_body{
+							(Response::new(413, format!("Request bodies may be at most {} bytes.", expect_continue_max_bytes)), false)
+						}else if max_body_bytes != 0 && body_len > max_body_bytes{
+							//chunked bodies have no body_len to check here, but they're refused with 411 below
+							trace!(target: LOG_SERVER, "Refusing a {} byte request body, over max_body_bytes", body_len);
+							(Response::new(413, format!("Request bodies may be at most {} bytes.", max_body_bytes)), false)
+						}else if reject_get_bodies && (request.method == "GET" || request.method == "HEAD") && (body_len > 0 || request.header("transfer-encoding").is_some()){
+							trace!(target: LOG_SERVER, "Refusing a {} request with a body", request.method);
+							(Response::new(400, format!("{} requests can't have a body.", request.method)), keep_alive)
+						}else if reject_trailing_bytes && !keep_alive && body_len == 0 && request.header("transfer-encoding").is_none() && !pending.is_empty(){
+							//nothing more was coming on this connection, so whatever follows is likely meant for something behind us
+							warn!(target: LOG_SECURITY, "Rejected {} {} from {} with {} unexpected bytes after it", request.method, request.resource.escape_debug(), peer_ip, pending.len());
+							(Response::new(400, String::from("Unexpected data followed the request.")), false)
+						}else if uploading && body_len > upload_max_bytes{
+							(Response::new(413, format!("Uploads may be at most {} bytes.", upload_max_bytes)), false)
+						}else if uploading && request.header("transfer-encoding").is_some(){
+							(Response::new(411, String::from("Uploads need a Content-Length.")), false)
+						}else if request.header("transfer-encoding").is_some(){
+							//a chunked body can't be measured against the limits or drained, so the connection ends here
+							trace!(target: LOG_SERVER, "Refusing a {} request with a chunked body", request.method);
+							let _shutdown_res = stream.shutdown(Shutdown::Read);
+							(Response::new(411, String::from("Request bodies need a Content-Length.")), false)
+						}else if handled && body_len > handler_body_max_bytes{
+							(Response::new(413, format!("Request bodies for this resource may be at most {} bytes.", handler_body_max_bytes)), false)
+						}else if body_incomplete{
+							(Response::new(400, String::from("The request body ended before its Content-Length.")), false)
+						}else if body_unwritable{
+							(Response::new(500, String::from("The request body couldn't be stored.")), keep_alive)
+						}else{
+							let respond_started = Instant::now();
+							let response = respond(request, settings);
+							trace!(target: LOG_SERVER, "Response built in {} µs", respond_started.elapsed().as_micros());
+							(response, keep_alive)
+						}
 					},
-					Err(res) => res
+					Err(res) => (res, false)
+				}
+			},
+			RequestHead::TooLarge => {
+				/* Any output won't make it to the browser if there is still input left to be read.
+				 * In order to avoid DoS attacks by enforcing max request size, and still
+				 * send the appropriate error message back, we need to discard the rest of
+				 * the input without actually reading it in. Even calling shutdown on Read doesn't
+				 * always do this but there doesn't seem to be any better way.
+				*/
+				let _shutdown_res = stream.shutdown(Shutdown::Read);
+				(Response::new(413, format!("Request exceeded the maximum allowed size of {} bytes.", request_max_bytes)), false)
+			},
+			RequestHead::Closed => {
+				//a zero-byte read means there's nothing to answer, so go quietly rather than sending a 400
+				if served == 0
+				{
+					trace!(target: LOG_SERVER, "Client closed the connection without sending a request.");
+				}else{
+					trace!(target: LOG_SERVER, "Client closed the connection.");
 				}
+				return;
+			},
+			RequestHead::TimedOut => {
+				trace!(target: LOG_SERVER, "Closing idle connection.");
+				return;
+			},
+			RequestHead::Expired => {
+				warn!(target: LOG_SERVER, "Abandoning connection from {}: the request didn't arrive within request_timeout_ms", peer_ip);
+				return;
+			},
+			RequestHead::HeaderTimeout => {
+				warn!(target: LOG_SECURITY, "Closing connection from {}: the request headers didn't arrive within header_timeout_ms", peer_ip);
+				(Response::new(408, String::from("The request headers took too long to arrive.")), false)
+			},
+			RequestHead::Failed(e) => (Response::new(400, format!("The network stream didn't stay valid long enough for the server to read it: {}",e)), false)
+		};
+		served += 1;
+
+		if keepalive_timeout_ms == 0 || served >= keepalive_max_requests
+		{
+			keep_alive = false;
+		}
+		if keep_alive
+		{
+			idle_slot = KEEPALIVE_CONNECTIONS.acquire((), max_keepalive_connections, Duration::ZERO);
+			if idle_slot.is_none()
+			{
+				trace!(target: LOG_SERVER, "Closing after this response: already keeping {} connections alive", max_keepalive_connections);
+				keep_alive = false;
 			}
-		},
-		Err(err_str) => Response::new(400, format!("The network stream didn't stay valid long enough for the server to read it: {}",err_str))
-	};
-	trace!("Request analyzed. Starting output.");
+		}
+		if !keep_alive
+		{
+			response.headers.insert(String::from("Connection"), String::from("close"));
+		}
+		add_retry_after(&mut response, settings);
+		trace!(target: LOG_SERVER, "Request analyzed in {} µs. Starting output.", read_done.elapsed().as_micros());
 
-	//write to request log
-	let peer_ip = match stream.peer_addr()
-	{
-		Ok(r) => r.to_string(),
-		Err(e)=> {warn!("Couldn't get peer IP: {}",e); String::from("Unknown")}
-	};
-	let request_line = format!("From: {} Response code: {}", peer_ip, response.code);
-	log!(target: "requests", Level::Info, "{}", request_line);
+		//write to request log, unless the entry needs the size of the response
+		if !combined_access_log
+		{
+			let request_line = format!("From: {} Response code: {}", peer_ip, response.code);
+			accesslog::log_access(response.code, request_line);
+		}
 
-	//send output
-	response.send(stream);
+		if let Some(deadline) = deadline
+		{
+			let now = Instant::now();
+			if now >= deadline
+			{
+				warn!(target: LOG_SERVER, "Abandoning connection from {}: the response wasn't ready within request_timeout_ms", peer_ip);
+				return;
+			}
+			if let Err(e) = stream.set_write_timeout(Some(deadline - now))
+			{
+				error!(target: LOG_SERVER, "Couldn't set write timeout: {}",e);
+				return;
+			}
+		}
+
+		//send output
+		let send_started = Instant::now();
+		let written = if method == "HEAD" {response.send_head(&mut stream)} else {response.send(&mut stream)};
+		let duration = started.elapsed();
+		trace!(target: LOG_SERVER, "Response sent in {} µs, {} µs after the request arrived", send_started.elapsed().as_micros(), duration.as_micros());
+		metrics.record_request(&method, response.code, written, duration);
+		if combined_access_log
+		{
+			let entry = AccessEntry{
+				peer: peer_ip.to_string(), method: method.clone(), resource: resource.clone(), http_version, status: response.code,
+				bytes: written, referer, user_agent, time: SystemTime::now() - duration
+			};
+			accesslog::log_access(response.code, entry.combined());
+		}
+		if slow_request_threshold.is_some_and(|threshold| duration > threshold)
+		{
+			warn!(target: LOG_SLOW, "Slow request from {}: {} {} answered {} after {} ms", peer_ip, method, resource, response.code, duration.as_millis());
+		}
+		workers::clear_request_context();
+		if !keep_alive
+		{
+			return;
+		}
+	}
 }
 
-/*
-Test those functions which weren't able to have good tests as part of their
-example usage in the docs, but are still possible to unit-test
+/**
+The outcome of trying to read the next request off a connection.
 */
-#[cfg(test)]
-mod tests
+enum RequestHead
 {
-	use super::*;
+	/// The request line and headers are this many bytes at the front of `pending`, up to and including the blank line that ends them
+	Complete(usize),
+	/// The client sent more than `request_max_bytes` without finishing the headers
+	TooLarge,
+	/// The client closed the connection without starting another request
+	Closed,
+	/// The read timeout passed without the client starting another request
+	TimedOut,
+	/// The client started a request but didn't finish it before the request deadline
+	Expired,
+	/// The request line and headers didn't all arrive within `header_timeout_ms`
+	HeaderTimeout,
+	/// The connection failed partway through
+	Failed(std::io::Error)
+}
+
+/**
+The limits on how long reading a request head may take.
+*/
+#[derive(Clone, Copy)]
+struct HeadTimeouts
+{
+	/// How long to wait for a request to start, or None to wait indefinitely
+	idle: Option<Duration>,
+	/// How long the whole request may take once it starts, or None for no limit
+	request: Option<Duration>,
+	/// How long the request line and headers may take to arrive, or None for no limit
+	header: Option<Duration>,
+	/// true for a connection's first request, whose header timeout starts when the connection does instead of at the request's first byte
+	first_request: bool
+}
+
+/**
+Read from the stream until a complete request head is available at the front
+of `pending`. The caller removes the head once it's parsed; data the client
+sent beyond it stays in `pending` for the next call.
+
+# Parameters
+- `stream`: Where to read the request from
+- `pending`: Data already read from the stream but not yet used
+- `max_bytes`: The head must be smaller than this
+- `timeouts`: The limits on how long the head may take
+- `deadline`: Set to the time the request has to be finished by, once it starts arriving
+- `arrived`: Set to the time the request started arriving
+
+# Returns
+The head, or the reason there isn't one
+*/
+fn read_request_head(stream: &mut TcpStream, pending: &mut Vec<u8>, max_bytes: usize, timeouts: HeadTimeouts, deadline: &mut Option<Instant>, arrived: &mut Option<Instant>) -> RequestHead
+{
+	let mut header_deadline = if timeouts.first_request {timeouts.header.map(|timeout| Instant::now() + timeout)} else {None};
+	loop
+	{
+		if arrived.is_none() && !pending.is_empty()
+		{
+			let now = Instant::now();
+			*arrived = Some(now);
+			*deadline = timeouts.request.map(|timeout| now + timeout);
+			header_deadline = header_deadline.or_else(|| timeouts.header.map(|timeout| now + timeout));
+		}
+		let now = Instant::now();
+		if header_deadline.is_some_and(|header_deadline| now >= header_deadline)
+		{
+			return RequestHead::HeaderTimeout;
+		}
+		let read_timeout = match deadline
+		{
+			Some(deadline) => {
+				if now >= *deadline
+				{
+					return RequestHead::Expired;
+				}
+				Some(*deadline - now)
+			},
+			None => timeouts.idle
+		};
+		//whichever limit comes first decides how long this read can wait
+		let read_timeout = match (read_timeout, header_deadline)
+		{
+			(Some(timeout), Some(header_deadline)) => Some(timeout.min(header_deadline - now)),
+			(None, Some(header_deadline)) => Some(header_deadline - now),
+			(timeout, None) => timeout
+		};
+		if let Err(e) = stream.set_read_timeout(read_timeout)
+		{
+			return RequestHead::Failed(e);
+		}
+
+		if let Some(end) = find_head_end(pending)
+		{
+			if end >= max_bytes
+			{
+				return RequestHead::TooLarge;
+			}
+			return RequestHead::Complete(end);
+		}
+		if pending.len() >= max_bytes
+		{
+			return RequestHead::TooLarge;
+		}
+
+		//read straight into the spare end of `pending`, then cut it back to what arrived
+		let filled = pending.len();
+		pending.resize(filled + READ_CHUNK_BYTES, 0);
+		let result = stream.read(&mut pending[filled..]);
+		pending.truncate(filled + result.as_ref().map_or(0, |num_bytes| *num_bytes));
+		match result
+		{
+			//a client that closes right after sending a request without the final blank line still gets an answer
+			Ok(0) => {
+				if pending.is_empty()
+				{
+					return RequestHead::Closed;
+				}
+				return RequestHead::Complete(pending.len());
+			},
+			Ok(_) => {},
+			Err(e) => match e.kind()
+			{
+				ErrorKind::Interrupted => {},
+				ErrorKind::WouldBlock | ErrorKind::TimedOut if header_deadline.is_some_and(|header_deadline| Instant::now() >= header_deadline) => {return RequestHead::HeaderTimeout;},
+				ErrorKind::WouldBlock | ErrorKind::TimedOut if pending.is_empty() => {return RequestHead::TimedOut;},
+				ErrorKind::WouldBlock | ErrorKind::TimedOut if deadline.is_some() => {return RequestHead::Expired;},
+				_ => {return RequestHead::Failed(e);}
+			}
+		}
+	}
+}
+
+/**
+# Returns
+The index just past the blank line that ends the request head, if the data contains one
+*/
+fn find_head_end(data: &[u8]) -> Option<usize>
+{
+	for index in 0..data.len()
+	{
+		if data[index..].starts_with(b"\r\n\r\n")
+		{
+			return Some(index + 4);
+		}else if data[index..].starts_with(b"\n\n"){
+			return Some(index + 2);
+		}
+	}
+	None
+}
+
+/**
+Decide whether the client is willing to send another request on the same
+connection after this one.
+
+# Parameters
+- `request`: The request just received
+
+# Returns
+true if the connection can be kept open
+*/
+fn wants_keep_alive(request: &Request) -> bool
+{
+	if request.http_version != "HTTP/1.1"
+	{
+		return false;
+	}
+	if request.connection_tokens().contains("close")
+	{
+		return false;
+	}
+	//a body with a declared length gets drained, but a chunked one is refused and would leave the stream somewhere unknown
+	request.header("transfer-encoding").is_none()
+}
+
+/**
+100-continue is the only expectation there is, so anything else can't be met.
+
+# Returns
+The request's Expect header, if it asks for something other than 100-continue
+*/
+fn unmet_expectation(request: &Request) -> Option<&str>
+{
+	request.header("expect").filter(|expect| expect.split(',').any(|expectation| !expectation.trim().eq_ignore_ascii_case("100-continue")))
+}
+
+/**
+Read and throw away a request body we aren't going to use, so the client
+isn't still sending it when we answer and close the connection, which can
+make the client's network stack discard our response. Any part of the body
+that was already read along with the head is taken from `pending`.
+
+# Parameters
+- `stream`: Where the body is arriving
+- `pending`: Data already read from the stream but not yet used
+- `len`: The body's declared Content-Length
+- `max_bytes`: The most we're willing to drain
+- `deadline`: The time the request has to be finished by, if any
+
+# Returns
+true if the whole body was drained, false if the connection can't be reused
+*/
+fn drain_body(stream: &mut TcpStream, pending: &mut Vec<u8>, len: usize, max_bytes: usize, deadline: Option<Instant>) -> bool
+{
+	if len > max_bytes
+	{
+		return false;
+	}
+	consume_body(stream, pending, len, deadline, |_| {})
+}
+
+/**
+Read a request body into memory, taking any part of it that was already read
+along with the head from `pending`.
+
+# Parameters
+- `stream`: The connection the body is arriving on
+- `pending`: Data already read from the stream but not yet used
+- `len`: Length of the body, from its Content-Length
+- `deadline`: When the request has to be finished by, if it has a limit
+
+# Returns
+The body, or None if the connection failed or timed out before all of it arrived
+*/
+fn read_body(stream: &mut TcpStream, pending: &mut Vec<u8>, len: usize, deadline: Option<Instant>) -> Option<Vec<u8>>
+{
+	let mut body = Vec::with_capacity(len);
+	if consume_body(stream, pending, len, deadline, |bytes| body.extend_from_slice(bytes))
+	{
+		Some(body)
+	}else{
+		None
+	}
+}
+
+/**
+Read a request body into a temporary file in `dir`, taking any part of it
+that was already read along with the head from `pending`. The whole body is
+consumed even if the file can't be written, so the connection stays usable.
+
+# Parameters
+- `stream`: The connection the body is arriving on
+- `pending`: Data already read from the stream but not yet used
+- `len`: Length of the body, from its Content-Length
+- `deadline`: When the request has to be finished by, if it has a limit
+- `dir`: Directory for the temporary file
+
+# Returns
+The body if all of it arrived, with the error from writing it if that failed,
+or None if the connection failed or timed out first
+*/
+fn spool_body(stream: &mut TcpStream, pending: &mut Vec<u8>, len: usize, deadline: Option<Instant>, dir: &Path) -> Option<io::Result<SpooledBody>>
+{
+	let mut spooled = SpooledBody::create(dir);
+	let arrived = consume_body(stream, pending, len, deadline, |bytes| {
+		if let Ok(body) = &mut spooled
+		{
+			if let Err(e) = body.append(bytes)
+			{
+				spooled = Err(e);
+			}
+		}
+	});
+	if arrived {Some(spooled)} else {None}
+}
+
+/**
+Take `len` bytes of body from `pending` and then the stream, passing each
+piece to `keep` as it arrives.
+
+# Returns
+true if the whole body arrived, false if the connection can't be reused
+*/
+fn consume_body<F: FnMut(&[u8])>(stream: &mut TcpStream, pending: &mut Vec<u8>, len: usize, deadline: Option<Instant>, mut keep: F) -> bool
+{
+	let from_pending = len.min(pending.len());
+	keep(&pending[..from_pending]);
+	pending.drain(..from_pending);
+	let mut remaining = len - from_pending;
+
+	let mut chunk = vec![0u8; 4096];
+	while remaining > 0
+	{
+		if let Some(deadline) = deadline
+		{
+			let now = Instant::now();
+			if now >= deadline || stream.set_read_timeout(Some(deadline - now)).is_err()
+			{
+				return false;
+			}
+		}
+		let want = remaining.min(chunk.len());
+		match stream.read(&mut chunk[..want])
+		{
+			Ok(0) => {return false;},
+			Ok(num_bytes) => {
+				keep(&chunk[..num_bytes]);
+				remaining -= num_bytes;
+			},
+			Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+			Err(_) => {return false;}
+		}
+	}
+	true
+}
+
+/**
+Tell clients when to try again after a 503 or 429 response, using the
+`retry_after_503` or `retry_after_429` setting. Each may be a number of
+seconds or an HTTP-date; an empty value sends no header.
+
+# Parameters
+- `response`: The response to add the header to, if its status calls for it
+- `settings`: The server configuration to apply
+*/
+fn add_retry_after(response: &mut Response, settings: &Config)
+{
+	let key = match response.code
+	{
+		503 => "retry_after_503",
+		429 => "retry_after_429",
+		_ => {return;}
+	};
+	let value = settings.get::<String>(key).unwrap_or_default();
+	let value = value.trim();
+	if value.is_empty()
+	{
+		return;
+	}
+	if value.parse::<u64>().is_ok() || httpdate::parse_http_date(value).is_ok()
+	{
+		response.headers.insert(String::from("Retry-After"), String::from(value));
+	}else{
+		warn!(target: LOG_SERVER, "Not sending Retry-After because {} is neither a number of seconds nor an HTTP-date: {}", key, value);
+	}
+}
+
+/**
+Check the configuration for problems that would otherwise only show up once
+requests start arriving, so the server can refuse to start with a clear
+explanation instead.
+
+# Parameters
+- `settings`: The fully merged server configuration
+
+# Returns
+- `Ok`: the configuration looks usable
+- `Err`: description of the first problem found
+
+# Examples
+```
+use c20web::validate_settings;
+use c20web::statics::DEFAULT_CONFIG;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+settings.set("webroot", "data/webroot").unwrap();
+assert!(validate_settings(&settings).is_ok());
+
+settings.set("listen_addr", "not an address").unwrap();
+assert!(validate_settings(&settings).is_err());
+```
+*/
+pub fn validate_settings(settings: &Config) -> Result<(), String>
+{
+	let webroot = settings.get::<String>("webroot").map_err(|e| format!("webroot missing from config: {}",e))?;
+	match fs::metadata(&webroot)
+	{
+		Ok(meta) => if !meta.is_dir() {return Err(format!("webroot {} is not a directory", webroot));},
+		Err(e) => {return Err(format!("webroot {} can't be used: {}", webroot, e));}
+	}
+
+	let listen_addr = settings.get::<String>("listen_addr").map_err(|e| format!("listen_addr missing from config: {}",e))?;
+	match listen_addr.to_socket_addrs()
+	{
+		Ok(mut addrs) => if addrs.next().is_none() {return Err(format!("listen_addr {} doesn't resolve to any address", listen_addr));},
+		Err(e) => {return Err(format!("listen_addr {} is not a valid address: {}", listen_addr, e));}
+	}
+
+	let threads_max = settings.get::<usize>("threads_max").map_err(|e| format!("threads_max missing from config: {}",e))?;
+	if threads_max == 0
+	{
+		return Err(String::from("threads_max must be at least 1"));
+	}
+
+	//the error page is optional, but if it's there it has to be usable
+	if Path::new("error.html").exists()
+	{
+		fs::read_to_string("error.html").map_err(|e| format!("error.html exists but can't be read: {}",e))?;
+	}
+	let not_found_page = settings.get::<String>("not_found_page").unwrap_or_default();
+	if !not_found_page.is_empty()
+	{
+		fs::read(&not_found_page).map_err(|e| format!("not_found_page {} can't be read: {}", not_found_page, e))?;
+	}
+
+	if let Some(cycle) = redirect_cycle(settings)
+	{
+		return Err(format!("redirects loop back on themselves: {}", cycle.join(" -> ")));
+	}
+
+	let unknown = unknown_settings(settings);
+	if !unknown.is_empty() && settings.get::<bool>("strict_config").unwrap_or(false)
+	{
+		return Err(format!("unrecognized settings, which may be typos: {}", unknown.join(", ")));
+	}
+
+	Ok(())
+}
+
+/**
+Logging configuration to use when `log4rs.yml` is missing or can't be used:
+everything goes to the console, at the same levels the bundled `log4rs.yml`
+uses.
+
+# Returns
+The configuration, ready for `log4rs::init_config`, or the problems building it
+
+# Examples
+```
+use log::LevelFilter;
+use c20web::fallback_log_config;
+
+let config = fallback_log_config().unwrap();
+assert_eq!(config.root().level(), LevelFilter::Info);
+assert_eq!(config.appenders().len(), 1);
+```
+*/
+pub fn fallback_log_config() -> Result<log4rs::config::Config, log4rs::config::Errors>
+{
+	use log::LevelFilter;
+	use log4rs::append::console::ConsoleAppender;
+	use log4rs::config::{Appender, Logger, Root};
+	use log4rs::encode::pattern::PatternEncoder;
+
+	let console = ConsoleAppender::builder().encoder(Box::new(PatternEncoder::new("{d} {l} {t} - {m}{n}"))).build();
+	log4rs::config::Config::builder()
+		.appender(Appender::builder().build("stdout", Box::new(console)))
+		.logger(Logger::builder().build(LOG_CONTENT, LevelFilter::Warn))
+		.logger(Logger::builder().build(LOG_SLOW, LevelFilter::Warn))
+		.build(Root::builder().appender("stdout").build(LevelFilter::Info))
+}
+
+/**
+The places to look for `web.toml` when no working directory is given: the
+current directory, its `data` directory as used with `cargo run`, the
+directory the executable is in, and `/etc/c20web`.
+
+# Returns
+The candidate directories, in the order they should be tried
+*/
+pub fn default_working_dirs() -> Vec<PathBuf>
+{
+	let mut candidates = vec![PathBuf::from("."), PathBuf::from("data")];
+	if let Some(exe_dir) = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf))
+	{
+		candidates.push(exe_dir);
+	}
+	candidates.push(PathBuf::from("/etc/c20web"));
+	candidates
+}
+
+/**
+# Parameters
+- `candidates`: Directories to look in, in order of preference
+
+# Returns
+The first of the directories that has a `web.toml` in it, or None if none do
+
+# Examples
+```
+use std::path::PathBuf;
+use c20web::find_working_dir;
+
+let candidates = vec![PathBuf::from("src"), PathBuf::from("data")];
+assert_eq!(find_working_dir(&candidates), Some(PathBuf::from("data")));
+```
+*/
+pub fn find_working_dir(candidates: &[PathBuf]) -> Option<PathBuf>
+{
+	candidates.iter().find(|dir| dir.join("web.toml").is_file()).cloned()
+}
+
+/**
+Merge in the files listed in the `include` setting, in order, so that each
+one overrides the settings before it. Relative paths are resolved against the
+working directory, and the extension can be left off as with the main config
+file. An `include` setting inside an included file is ignored.
+
+# Parameters
+- `settings`: The configuration loaded so far, which the included files are merged into
+
+# Returns
+Nothing, or a description of the file that couldn't be merged
+*/
+pub fn merge_includes(settings: &mut Config) -> Result<(), String>
+{
+	let includes = settings.get::<Vec<String>>("include").map_err(|e| format!("include must be a list of file names: {}",e))?;
+	for include in &includes
+	{
+		settings.merge(config::File::with_name(include)).map_err(|e| format!("Couldn't merge included config {}: {}", include, e))?;
+	}
+	settings.set("include", includes).map_err(|e| format!("Couldn't restore the include list: {}",e))?;
+	Ok(())
+}
+
+/**
+When `webroot_snapshot` is on, open the webroot directory and point the
+`webroot` setting at the open directory instead of its path. Files are then
+served from the directory as it was captured, even if a deploy later swaps
+something else in at the path, so no request ever sees half of one
+deployment and half of another. Capture again to start serving the new one.
+
+Only Linux can do this, since it's reached through `/proc/self/fd`. The
+directories stay open for as long as the server runs, so requests that
+started with an earlier one can finish.
+
+# Parameters
+- `settings`: The configuration whose webroot is captured
+
+# Returns
+Nothing, or why the webroot couldn't be captured
+*/
+pub fn capture_webroot(settings: &mut Config) -> Result<(), String>
+{
+	if !settings.get::<bool>("webroot_snapshot").unwrap_or(false)
+	{
+		return Ok(());
+	}
+	let webroot = settings.get::<String>("webroot").map_err(|e| format!("webroot missing from config: {}",e))?;
+	let captured = open_webroot(&webroot).map_err(|e| format!("Couldn't capture webroot {}: {}", webroot, e))?;
+	settings.set("webroot", captured).map_err(|e| format!("Couldn't point webroot at the captured directory: {}",e))?;
+	Ok(())
+}
+
+/**
+Replace `gzip_level` and `brotli_level` values the encoders can't use with
+the nearest level they can, warning about each one, so it's done once at
+startup instead of on every compressed response.
+
+# Parameters
+- `settings`: The configuration whose compression levels are checked
+
+# Returns
+Nothing, or why a corrected level couldn't be stored
+
+# Examples
+```
+use c20web::clamp_compression_levels;
+
+let mut settings = config::Config::default();
+settings.set("gzip_level", 15).unwrap();
+settings.set("brotli_level", 4).unwrap();
+clamp_compression_levels(&mut settings).unwrap();
+assert_eq!(settings.get::<i64>("gzip_level").unwrap(), 9);
+assert_eq!(settings.get::<i64>("brotli_level").unwrap(), 4);
+```
+*/
+pub fn clamp_compression_levels(settings: &mut Config) -> Result<(), String>
+{
+	for (key, encoding, max) in &[("gzip_level", Encoding::Gzip, 9), ("brotli_level", Encoding::Brotli, 11)]
+	{
+		let level = settings.get::<i64>(key).unwrap_or(6);
+		let clamped = compression::clamp_level(*encoding, level);
+		if i64::from(clamped) != level
+		{
+			warn!(target: LOG_SERVER, "{} {} is outside 0-{}, using {}", key, level, max, clamped);
+			settings.set(key, i64::from(clamped)).map_err(|e| format!("Couldn't store the corrected {}: {}", key, e))?;
+		}
+	}
+	Ok(())
+}
+
+/// Open a directory and keep it open, returning the path it can be read through
+#[cfg(target_os = "linux")]
+fn open_webroot(webroot: &str) -> io::Result<String>
+{
+	use std::os::unix::io::AsRawFd;
+
+	let dir = fs::File::open(webroot)?;
+	if !dir.metadata()?.is_dir()
+	{
+		return Err(io::Error::new(ErrorKind::InvalidInput, "not a directory"));
+	}
+	let path = format!("/proc/self/fd/{}", dir.as_raw_fd());
+	CAPTURED_WEBROOTS.lock().unwrap_or_else(|e| e.into_inner()).push(dir);
+	Ok(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_webroot(_webroot: &str) -> io::Result<String>
+{
+	Err(io::Error::new(ErrorKind::Other, "webroot_snapshot is only supported on Linux"))
+}
+
+/**
+Find the settings that the server doesn't know about, which are usually
+misspellings of ones it does. Only top-level keys are checked, since tables
+like `extension_roots` have keys of the user's choosing.
+
+# Parameters
+- `settings`: The server configuration to check
+
+# Returns
+The unrecognized keys, sorted
+
+# Examples
+```
+use c20web::unknown_settings;
+use c20web::statics::DEFAULT_CONFIG;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+assert!(unknown_settings(&settings).is_empty());
+
+settings.merge(config::File::from_str("keepalive_timout_ms = 100", config::FileFormat::Toml)).unwrap();
+assert_eq!(unknown_settings(&settings), vec![String::from("keepalive_timout_ms")]);
+```
+*/
+pub fn unknown_settings(settings: &Config) -> Vec<String>
+{
+	let mut defaults = Config::default();
+	if let Err(e) = defaults.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml))
+	{
+		error!(target: LOG_SERVER, "Couldn't parse the default config to check for unrecognized settings: {}", e);
+		return Vec::new();
+	}
+	let known = defaults.cache.into_table().unwrap_or_default();
+	let mut unknown: Vec<String> = settings.cache.clone().into_table().unwrap_or_default().into_keys()
+		.filter(|key| !known.contains_key(key))
+		.collect();
+	unknown.sort();
+	unknown
+}
+
+/**
+Decide how to answer a successfully parsed request. This covers everything
+between parsing and output, so it neither reads from nor writes to the network.
+
+# Parameters
+- `request`: The parsed request
+- `settings`: The server configuration to apply
+
+# Returns
+The Response that should be sent back to the client
+
+# Examples
+```
+use c20web::{respond, Request};
+use c20web::statics::DEFAULT_CONFIG;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+settings.set("webroot", "data/webroot").unwrap();
+
+let request = Request::parse(Box::new(b"GET /hello.html HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+let response = respond(request, &settings);
+assert_eq!(response.code, 200);
+assert_eq!(response.mime, "text/html");
+```
+*/
+pub fn respond(request: Request, settings: &Config) -> Response
+{
+	respond_using_cache(request, settings, &FILE_CACHE)
+}
+
+/**
+Same as `respond`, but reading files through the given cache instead of the
+server-wide one.
+
+# Parameters
+- `request`: The parsed request
+- `settings`: The server configuration to apply
+- `cache`: The file cache to use when `file_cache_max_bytes` is nonzero
+
+# Returns
+The Response that should be sent back to the client
+*/
+pub fn respond_using_cache(request: Request, settings: &Config, cache: &FileCache) -> Response
+{
+	let mut response = route_request(&request, settings, cache);
+	if response.use_error_page && !(200..300).contains(&response.code) && settings.get::<bool>("compress_error_pages").unwrap_or(false)
+	{
+		response.fill_error_page();
+		encode_body(&request, &mut response, settings);
+	}
+	add_configured_headers(&mut response, settings);
+	response
+}
+
+/**
+Add the headers in the `headers` setting, a table of names and values like
+`X-Frame-Options = "DENY"`, to a response. Entries with an empty value are
+left out, and so are headers the response already has in any case, since
+those were set for this response in particular.
+
+# Parameters
+- `response`: The response to add the headers to
+- `settings`: The server configuration to apply
+*/
+fn add_configured_headers(response: &mut Response, settings: &Config)
+{
+	for (name, value) in settings.get_table("headers").unwrap_or_default()
+	{
+		let value = match value.into_str()
+		{
+			Ok(value) if !value.contains(|c: char| c.is_control()) => value,
+			_ => {warn!(target: LOG_SERVER, "Ignoring headers entry {}: the value must be a single line of text", name); continue;}
+		};
+		if value.is_empty() || response.headers.contains_key(&name)
+		{
+			continue;
+		}
+		response.headers.insert(name, value);
+	}
+}
+
+/**
+Work out the response to a request, as `respond_using_cache` does, but
+without compressing error pages.
+
+# Parameters
+- `request`: The parsed request
+- `settings`: The server configuration to apply
+- `cache`: The file cache to use when `file_cache_max_bytes` is nonzero
+
+# Returns
+The Response that should be sent back to the client
+*/
+fn route_request(request: &Request, settings: &Config, cache: &FileCache) -> Response
+{
+	let webroot = match settings.get::<String>("webroot"){
+		Ok(r) => r,
+		Err(e) => {error!(target: LOG_SERVER, "webroot missing from config: {}",e); return Response::new(500, String::from(""));}
+	};
+
+	if let Some(expect) = unmet_expectation(request)
+	{
+		return Response::new(417, format!("This server can't meet the expectation: {}", expect));
+	}
+
+	//challenges have to get through whatever else would turn the request away, or the certificate never arrives
+	if let Some(challenge) = acme_challenge(request, settings)
+	{
+		return challenge;
+	}
+
+	//health checks come first, so an orchestrator keeps the server around through maintenance
+	let health_check_path = settings.get::<String>("health_check_path").unwrap_or_default();
+	if !health_check_path.is_empty() && (request.method == "GET" || request.method == "HEAD")
+		&& (ResourcePath{resource: request.resource.clone()}).get_decoded() == health_check_path
+	{
+		let mut response = Response::new(200, String::from("OK"));
+		response.mime = String::from("text/plain");
+		return response;
+	}
+
+	if settings.get::<bool>("maintenance_mode").unwrap_or(false)
+	{
+		return Response::new(503, String::from("The server is down for maintenance."));
+	}
+
+	//every listener is plain HTTP, so when HTTPS is required nothing gets served here
+	if settings.get::<bool>("force_https").unwrap_or(false)
+	{
+		return https_redirect(request, settings);
+	}
+
+	if request.method == "GET" || request.method == "HEAD"
+	{
+		if let Some(redirect) = configured_redirect(request, settings)
+		{
+			return redirect;
+		}
+	}
+
+	if request.http_version == "HTTP/1.1" && accepts_upload(request, settings)
+	{
+		match request.method.as_str()
+		{
+			"PUT" => {return upload(request, &webroot, settings);},
+			"DELETE" => {return delete(request, &webroot, settings);},
+			_ => {}
+		}
+	}
+
+	let allowed_methods = settings.get::<Vec<String>>("allowed_methods").unwrap_or_else(|_| vec![String::from("GET")]);
+
+	if request.http_version == "HTTP/1.1"
+	{
+		if let Some(mut response) = handlers::dispatch(request)
+		{
+			cors::answer_preflight(request, &mut response, settings);
+			return response;
+		}
+	}
+
+	//determine whether we currently support the features necessary to fulfill the request
+	if !allowed_methods.contains(&request.method)
+	{
+		//methods we know but don't allow on static files are 405, anything else we simply don't implement
+		let code = if ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "TRACE", "CONNECT"].contains(&request.method.as_str()) {405} else {501};
+		let mut response = Response::new(code, format!("This server only accepts these methods: {}", allowed_methods.join(", ")));
+		if code == 405
+		{
+			response.headers.insert(String::from("Allow"), allowed_methods.join(", "));
+		}
+		response
+	}else if request.http_version != "HTTP/1.1"{
+		Response::new(505, String::from("This server only speaks HTTP/1.1"))
+	}else{
+		let res = ResourcePath{resource: request.resource.clone()};
+		if res.is_traversal()
+		{
+			warn!(target: LOG_SECURITY, "Rejected path traversal attempt: {}", res.resource);
+			return Response::new(403, String::from("Paths may not refer to parent directories."));
+		}
+		if res.has_double_encoding() && settings.get::<bool>("block_double_encoding").unwrap_or(true)
+		{
+			warn!(target: LOG_SECURITY, "Rejected path with an encoded escape for a dot or separator: {}", res.resource.escape_debug());
+			return Response::new(400, String::from("Paths may not encode dots or separators more than once."));
+		}
+		if res.has_windows_path_components() && settings.get::<bool>("block_windows_paths").unwrap_or(true)
+		{
+			warn!(target: LOG_SECURITY, "Rejected path with a backslash or drive letter: {}", res.resource.escape_debug());
+			return Response::new(403, String::from("Paths may not contain backslashes or drive letters."));
+		}
+		if res.has_control_characters() && settings.get::<bool>("block_control_characters").unwrap_or(true)
+		{
+			warn!(target: LOG_SECURITY, "Rejected path with control characters: {}", res.resource.escape_debug());
+			return Response::new(400, String::from("Paths may not contain control characters."));
+		}
+		let max_path_segments = settings.get::<usize>("max_path_segments").unwrap_or(0);
+		if max_path_segments != 0 && res.segment_count() > max_path_segments
+		{
+			info!(target: LOG_SECURITY, "Rejected path with more than {} segments: {}", max_path_segments, res.resource);
+			return Response::new(400, String::from("The requested path is too deep."));
+		}
+
+		if let Some(blocked) = legal_block(&res, settings)
+		{
+			return blocked;
+		}
+		if let Some(well_known) = well_known_response(&res, settings)
+		{
+			return well_known;
+		}
+		if settings.get::<bool>("block_dotfiles").unwrap_or(false) && is_dotfile_path(&res.get_decoded())
+		{
+			info!(target: LOG_SECURITY, "Refused request for a hidden file: {}", res.resource);
+			return not_found(settings, String::from("No such file or directory"));
+		}
+
+		//attempt to load the requested file
+		let root = root_for_extension(&res, settings).unwrap_or(webroot);
+		let mut path = res.get_path(root.clone());
+		let mut mime = res.get_mime();
+		if settings.get::<bool>("case_insensitive_paths").unwrap_or(false) && !Path::new(&path).exists()
+		{
+			if let Some(resolved) = resolve_ignoring_case(&root, &res.get_decoded())
+			{
+				trace!(target: LOG_SERVER, "Resolved {} to {} ignoring case", res.resource, resolved);
+				let name = Path::new(&resolved).file_name().and_then(|n| n.to_str()).unwrap_or("");
+				mime = ResourcePath{resource: format!("/{}", name)}.get_mime();
+				path = resolved;
+			}
+		}
+		if Path::new(&path).is_dir()
+		{
+			match find_index(&path, &res, settings)
+			{
+				Some((index_path, index_name)) => {
+					path = index_path;
+					mime = ResourcePath{resource: index_name}.get_mime();
+				},
+				None => if res.get_decoded() == "/"
+				{
+					if let Some(welcome) = root_response(settings)
+					{
+						return welcome;
+					}
+				}
+			}
+		}
+		let negotiate_language = settings.get::<bool>("language_negotiation").unwrap_or(false);
+		if negotiate_language
+		{
+			let variant = language::negotiate(&path, request.header("accept-language").unwrap_or(""));
+			if let Some(variant_path) = variant.as_ref().and_then(|v| v.to_str())
+			{
+				path = String::from(variant_path);
+			}
+		}
+		if has_blocked_extension(&path, settings)
+		{
+			info!(target: LOG_SECURITY, "Refused request for a file with a blocked extension: {}", res.resource);
+			return not_found(settings, String::from("No such file or directory"));
+		}
+		trace!(target: LOG_SERVER, "Requesting page: {}",&path);
+		let mut mime = directory_setting(&res, settings, "directory_mime_types", "mime").unwrap_or_else(|| String::from(mime));
+		if mime.starts_with("text/")
+		{
+			if let Some(charset) = directory_setting(&res, settings, "directory_charsets", "charset")
+			{
+				mime = with_charset(&mime, &charset);
+			}
+		}
+		let meta = fs::metadata(&path).ok().filter(|meta| meta.is_file());
+		if meta.is_none()
+		{
+			let upstream = settings.get::<String>("proxy_upstream").unwrap_or_default();
+			if !upstream.is_empty()
+			{
+				trace!(target: LOG_SERVER, "No file at {}, forwarding to {}", &path, upstream);
+				return proxy::forward(request, &upstream, settings);
+			}
+		}
+		//streamed files are sent exactly as they are on disk, never transformed on the way
+		let streamed = stream_threshold(&mime, settings).is_some_and(|threshold| meta.as_ref().is_some_and(|meta| meta.len() > threshold));
+		//the file's own validators don't change when the configuration alters what's served from it
+		let strips_bom = !streamed && mime.starts_with("text/") && settings.get::<bool>("detect_utf8_bom").unwrap_or(false)
+			&& settings.get::<bool>("strip_utf8_bom").unwrap_or(false);
+		let snippet = if streamed {Vec::new()} else {html_snippet_marker(&mime, settings)};
+		let validators = meta.as_ref().map(|meta| Validators::for_file(meta)
+			.varied_by(&snippet)
+			.varied_by(if strips_bom {b"strip_utf8_bom"} else {b""}));
+		if let Some(validators) = &validators
+		{
+			match conditional::evaluate(request, validators)
+			{
+				Precondition::Failed => {return Response::new(412, String::from("The resource doesn't match the version required by the request."));},
+				Precondition::NotModified => {
+					let mut response = Response{code: 304, mime: mime.clone(), body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: None};
+					validators.add_to(&mut response);
+					if negotiate_language
+					{
+						response.add_vary("Accept-Language");
+					}
+					//the tag has to be the one the full response would have carried, coding and all
+					let encoding = if streamed {Encoding::Identity} else {negotiate_encoding(request, &mut response, settings)};
+					if encoding != Encoding::Identity
+					{
+						if let Some(etag) = response.headers.get_mut("ETag")
+						{
+							*etag = conditional::etag_for_coding(etag, encoding.token());
+						}
+					}
+					return response;
+				},
+				Precondition::Proceed => {}
+			}
+		}
+		let mut response = Response{code: 200, mime, body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: None};
+		if let Some(validators) = &validators
+		{
+			validators.add_to(&mut response);
+		}
+		if negotiate_language
+		{
+			response.add_vary("Accept-Language");
+		}
+		if settings.get::<bool>("content_language_from_filename").unwrap_or(false)
+		{
+			if let Some(lang) = language::from_filename(&path)
+			{
+				response.headers.insert(String::from("Content-Language"), lang);
+			}
+		}
+		//lets a client probing with HEAD learn whether it can resume or split the download
+		let accept_ranges = if settings.get::<bool>("ranges").unwrap_or(false) {"bytes"} else {"none"};
+		response.headers.insert(String::from("Accept-Ranges"), String::from(accept_ranges));
+		if streamed
+		{
+			if let Err(refused) = stream_file(&path, request, &mut response, settings)
+			{
+				return refused;
+			}
+		}else{
+			match load_file(&path, settings, cache)
+			{
+				Ok((contents, age)) => {
+					//lets caches further along count the time the copy has already spent here
+					if let Some(age) = age
+					{
+						response.headers.insert(String::from("Age"), age.as_secs().to_string());
+					}
+					//the snippet goes in first, so ranges are taken from the page as it's actually served
+					let contents = inject_html_snippet(&contents, &response.mime, settings).map(Arc::new).unwrap_or(contents);
+					if let Some((start, end)) = select_range(request, contents.len(), &mut response, settings)
+					{
+						response.body = contents[start..end].to_vec();
+					}
+				},
+				Err(e) => {return not_found(settings, format!("{}",e));}
+			}
+		}
+		//a byte order mark says for certain how the text is encoded, whatever the directory's default
+		if response.code == 200 && response.stream.is_none() && response.mime.starts_with("text/")
+			&& response.body.starts_with(UTF8_BOM) && settings.get::<bool>("detect_utf8_bom").unwrap_or(false)
+		{
+			response.mime = with_charset(&response.mime, "utf-8");
+			if settings.get::<bool>("strip_utf8_bom").unwrap_or(false)
+			{
+				response.body.drain(..UTF8_BOM.len());
+			}
+		}
+		if is_attachment(&res, settings)
+		{
+			//the name comes from the request, so keep it from breaking out of the quotes or the header line
+			let filename: String = res.get_filename().chars().filter(|c| !c.is_control()).collect();
+			let filename = filename.replace('\\', "\\\\").replace('"', "\\\"");
+			response.headers.insert(String::from("Content-Disposition"), format!("attachment; filename=\"{}\"", filename));
+		}
+		//a partial body is a slice of the original and a streamed one is never in memory, so neither can be compressed
+		if response.code == 200 && response.stream.is_none()
+		{
+			encode_body(request, &mut response, settings);
+		}
+		response
+	}
+}
+
+/**
+Find the file a path names when the case of its letters doesn't match, like
+`/About.HTML` for `about.html`, one directory at a time. Segments that exist
+as written are used as they are. A segment that matches more than one entry
+in its directory, like `readme` when both `README` and `Readme` exist, finds
+nothing, so which file gets served never depends on directory order.
+
+# Parameters
+- `root`: The directory the path is relative to
+- `decoded`: The decoded request path
+
+# Returns
+The filesystem path of the file or directory, or None if there's no unambiguous match
+*/
+fn resolve_ignoring_case(root: &str, decoded: &str) -> Option<String>
+{
+	let mut resolved = PathBuf::from(root);
+	for segment in decoded.split('/').filter(|s| !s.is_empty())
+	{
+		if segment == "." || segment == ".."
+		{
+			return None;
+		}
+		let exact = resolved.join(segment);
+		if exact.exists()
+		{
+			resolved = exact;
+			continue;
+		}
+		let mut matches = fs::read_dir(&resolved).ok()?
+			.filter_map(|entry| entry.ok())
+			.map(|entry| entry.file_name())
+			.filter(|name| name.to_str().is_some_and(|name| name.eq_ignore_ascii_case(segment)));
+		let found = matches.next()?;
+		if matches.next().is_some()
+		{
+			return None;
+		}
+		resolved.push(found);
+	}
+	let mut path = resolved.to_str()?.to_owned();
+	if decoded.ends_with('/')
+	{
+		path.push('/');
+	}
+	Some(path)
+}
+
+/**
+Describe the snippet `inject_html_snippet` would put in pages of a type, so
+the entity tag of a page changes along with the snippet.
+
+# Parameters
+- `mime`: MIME type the page is being served as
+- `settings`: The server configuration to apply
+
+# Returns
+The snippet and where it goes, or nothing if pages of this type are left alone
+*/
+fn html_snippet_marker(mime: &str, settings: &Config) -> Vec<u8>
+{
+	let snippet = settings.get::<String>("html_snippet").unwrap_or_default();
+	if snippet.is_empty() || !mime.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("text/html")
+	{
+		return Vec::new();
+	}
+	let before = settings.get::<String>("html_snippet_before").unwrap_or_else(|_| String::from("</head>"));
+	format!("{}\n{}", before, snippet).into_bytes()
+}
+
+/**
+Insert the `html_snippet` setting into an HTML page, just before the last
+occurrence of the tag in `html_snippet_before`, like `</head>`, which is
+matched without regard to case. Pages without that tag, and everything that
+isn't HTML, are left alone. Streamed pages never pass through here, since
+they're not held in memory.
+
+# Parameters
+- `contents`: The page as read from the file
+- `mime`: MIME type the page is being served as
+- `settings`: The server configuration to apply
+
+# Returns
+The page with the snippet in it, or None if it's unchanged
+*/
+fn inject_html_snippet(contents: &[u8], mime: &str, settings: &Config) -> Option<Vec<u8>>
+{
+	let snippet = settings.get::<String>("html_snippet").unwrap_or_default();
+	if snippet.is_empty() || !mime.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("text/html")
+	{
+		return None;
+	}
+	let before = settings.get::<String>("html_snippet_before").unwrap_or_else(|_| String::from("</head>"));
+	if before.is_empty()
+	{
+		return None;
+	}
+	let at = contents.windows(before.len()).rposition(|window| window.eq_ignore_ascii_case(before.as_bytes()))?;
+	let mut injected = Vec::with_capacity(contents.len() + snippet.len());
+	injected.extend_from_slice(&contents[..at]);
+	injected.extend_from_slice(snippet.as_bytes());
+	injected.extend_from_slice(&contents[at..]);
+	Some(injected)
+}
+
+/**
+Add the reason phrases in the `status_codes` setting, a table of status
+codes and phrases like `418 = "I'm a teapot"`, to the ones the server
+already knows. Configured phrases replace built-in ones for the same code.
+
+# Parameters
+- `settings`: The server configuration to apply
+*/
+pub fn configure_status_codes(settings: &Config)
+{
+	let configured = settings.get_table("status_codes").unwrap_or_default();
+	let mut custom = CUSTOM_STATUS_CODES.write().unwrap_or_else(|e| e.into_inner());
+	for (code, phrase) in configured
+	{
+		let code = match code.parse::<u16>()
+		{
+			Ok(code) if (100..1000).contains(&code) => code,
+			_ => {warn!(target: LOG_SERVER, "Ignoring status_codes entry {}: status codes are three digits", code); continue;}
+		};
+		match phrase.into_str()
+		{
+			Ok(phrase) if !phrase.contains(|c: char| c.is_control()) => {custom.insert(code, phrase);},
+			_ => {warn!(target: LOG_SERVER, "Ignoring status_codes entry {}: the reason phrase must be a single line of text", code);}
+		}
+	}
+}
+
+/**
+# Returns
+The reason phrase to send with a status code, from the `status_codes`
+setting or the standard ones, or None if the code doesn't have one
+
+# Examples
+```
+use c20web::reason_phrase;
+
+assert_eq!(reason_phrase(404), Some(String::from("Not Found")));
+assert_eq!(reason_phrase(299), None);
+```
+*/
+pub fn reason_phrase(code: u16) -> Option<String>
+{
+	if let Some(phrase) = CUSTOM_STATUS_CODES.read().unwrap_or_else(|e| e.into_inner()).get(&code)
+	{
+		return Some(phrase.clone());
+	}
+	HTTP_RESPONSE_TABLE.get(&code).cloned()
+}
+
+/**
+Fill in an error page template. Templates can use the named placeholders
+`{{status}}` (code and reason phrase, like "404 Not Found"), `{{code}}` and
+`{{message}}` anywhere and any number of times. Templates without any named
+placeholders are treated the old way: the first two `{}` become the status
+and the third becomes the message.
+
+# Parameters
+- `template`: Contents of the error page template
+- `code`: The HTTP response code
+- `status`: The code and reason phrase
+- `message`: Description of the error
+
+# Returns
+The finished error page
+
+# Examples
+```
+use c20web::render_error_page;
+
+assert_eq!(render_error_page("<p>{{message}}</p><h1>{{status}}</h1>", 404, "404 Not Found", "No such file"), "<p>No such file</p><h1>404 Not Found</h1>");
+assert_eq!(render_error_page("<title>{}</title><h1>{}</h1><p>{}</p>", 500, "500 Internal Server Error", "Oops"), "<title>500 Internal Server Error</title><h1>500 Internal Server Error</h1><p>Oops</p>");
+```
+*/
+pub fn render_error_page(template: &str, code: u16, status: &str, message: &str) -> String
+{
+	const NAMED: [&str; 3] = ["{{status}}", "{{code}}", "{{message}}"];
+	if NAMED.iter().any(|placeholder| template.contains(placeholder))
+	{
+		//fill in the message last so placeholder-like text inside it is left alone
+		template.replace("{{status}}", status)
+			.replace("{{code}}", &code.to_string())
+			.replace("{{message}}", message)
+	}else{
+		template.replacen("{}", status, 2).replacen("{}", message, 1)
+	}
+}
+
+/**
+Check the resource against the `legal_blocks` setting, a list of tables each
+with a `path`, an optional `reason` shown to the visitor, and an optional
+`authority` URL identifying who demanded the block. A path matches itself and
+everything beneath it.
+
+# Parameters
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+
+# Returns
+A 451 Response if the resource is blocked, otherwise None
+*/
+fn legal_block(res: &ResourcePath, settings: &Config) -> Option<Response>
+{
+	let blocks = settings.get_array("legal_blocks").unwrap_or_default();
+	let path = res.get_decoded();
+	for block in blocks
+	{
+		let block = match block.into_table()
+		{
+			Ok(t) => t,
+			Err(e) => {warn!(target: LOG_SERVER, "Ignoring legal_blocks entry that isn't a table: {}",e); continue;}
+		};
+		let blocked_path = match block.get("path").map(|v| v.clone().into_str())
+		{
+			Some(Ok(p)) => p,
+			_ => {warn!(target: LOG_SERVER, "Ignoring legal_blocks entry without a path"); continue;}
+		};
+		if !path_is_within(&path, &blocked_path)
+		{
+			continue;
+		}
+
+		let reason = block.get("reason").and_then(|v| v.clone().into_str().ok())
+			.unwrap_or_else(|| String::from("This resource is unavailable for legal reasons."));
+		let mut response = Response::new(451, reason);
+		if let Some(authority) = block.get("authority").and_then(|v| v.clone().into_str().ok())
+		{
+			response.headers.insert(String::from("Link"), format!("<{}>; rel=\"blocked-by\"", authority));
+		}
+		return Some(response);
+	}
+	None
+}
+
+/**
+# Returns
+The MIME type with its charset parameter set to `charset`, replacing any it already had
+*/
+fn with_charset(mime: &str, charset: &str) -> String
+{
+	let essence = mime.split(';').next().unwrap_or("").trim();
+	format!("{}; charset={}", essence, charset)
+}
+
+/**
+Log a failed write to a client. A client that goes away partway through a
+response, which happens all the time when someone stops a video or leaves
+the page, is only worth a debug message; anything else is an error.
+
+# Parameters
+- `context`: What was being written, like "Write error"
+- `e`: The error the write failed with
+*/
+fn log_write_error(context: &str, e: &io::Error)
+{
+	match e.kind()
+	{
+		ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {debug!(target: LOG_SERVER, "{}, client disconnected: {}", context, e);},
+		_ => {error!(target: LOG_SERVER, "{}: {}", context, e);}
+	}
+}
+
+/**
+Find the size above which a file of the given type is streamed rather than
+read into memory. An entry in the `stream_threshold_mime_types` table for
+the exact type, like `"video/mp4" = 0`, wins over one for its family, with
+`*` for the subtype, which wins over `stream_threshold_bytes`. A threshold of 0
+in the table streams every file of that type, but `stream_threshold_bytes`
+of 0 streams none.
+
+# Parameters
+- `mime`: MIME type of the file, parameters and all
+- `settings`: The server configuration to apply
+
+# Returns
+The threshold in bytes, or None if files of this type are never streamed
+*/
+fn stream_threshold(mime: &str, settings: &Config) -> Option<u64>
+{
+	let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+	let family = format!("{}/*", essence.split('/').next().unwrap_or(""));
+	let overrides = settings.get_table("stream_threshold_mime_types").unwrap_or_default();
+	for key in &[essence, family]
+	{
+		if let Some(threshold) = overrides.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.clone().into_int())
+		{
+			match threshold
+			{
+				Ok(threshold) => {return Some(threshold.max(0) as u64);},
+				Err(e) => {warn!(target: LOG_SERVER, "Ignoring stream_threshold_mime_types entry {}: {}", key, e);}
+			}
+		}
+	}
+	match settings.get::<u64>("stream_threshold_bytes").unwrap_or(0)
+	{
+		0 => None,
+		threshold => Some(threshold)
+	}
+}
+
+/**
+Look up a per-directory setting: a list of tables each with a `path` and a
+value in `field` that applies to everything beneath that path, like the
+`mime` of a `directory_mime_types` entry. When several paths match, the
+longest one wins.
+
+# Parameters
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+- `key`: Name of the setting holding the list
+- `field`: Name of the value in each entry
+
+# Returns
+The value for the resource's directory, or None if no entry covers it
+*/
+fn directory_setting(res: &ResourcePath, settings: &Config, key: &str, field: &str) -> Option<String>
+{
+	let entries = settings.get_array(key).unwrap_or_default();
+	let path = res.get_decoded();
+	let mut best: Option<(usize, String)> = None;
+	for entry in entries
+	{
+		let entry = match entry.into_table()
+		{
+			Ok(t) => t,
+			Err(e) => {warn!(target: LOG_SERVER, "Ignoring {} entry that isn't a table: {}", key, e); continue;}
+		};
+		let (dir, value) = match (entry.get("path").map(|v| v.clone().into_str()), entry.get(field).map(|v| v.clone().into_str()))
+		{
+			(Some(Ok(dir)), Some(Ok(value))) => (dir, value),
+			_ => {warn!(target: LOG_SERVER, "Ignoring {} entry without a path and {}", key, field); continue;}
+		};
+		if path_is_within(&path, &dir) && best.as_ref().is_none_or(|(len, _)| dir.len() > *len)
+		{
+			best = Some((dir.len(), value));
+		}
+	}
+	best.map(|(_, value)| value)
+}
+
+/**
+# Parameters
+- `path`: A decoded request path
+- `base`: A configured path
+
+# Returns
+true if `path` is `base` itself or somewhere beneath it
+
+# Examples
+```
+use c20web::path_is_within;
+
+assert!(path_is_within("/docs", "/docs"));
+assert!(path_is_within("/docs/a.html", "/docs"));
+assert!(path_is_within("/docs/a.html", "/docs/"));
+assert!(!path_is_within("/docsearch.html", "/docs"));
+```
+*/
+pub fn path_is_within(path: &str, base: &str) -> bool
+{
+	if base.ends_with('/')
+	{
+		path.starts_with(base) || path == base.trim_end_matches('/')
+	}else{
+		path == base || (path.starts_with(base) && path[base.len()..].starts_with('/'))
+	}
+}
+
+/**
+Read a file to be served, going through the cache when `file_cache_max_bytes`
+is nonzero.
+
+# Parameters
+- `path`: Filesystem path of the file
+- `settings`: The server configuration to apply
+- `cache`: The cache to use
+
+# Returns
+The file contents and, if they came from the cache, how long they've been
+there, or the error from reading the file
+*/
+fn load_file(path: &str, settings: &Config, cache: &FileCache) -> io::Result<(Arc<Vec<u8>>, Option<Duration>)>
+{
+	let max_total = settings.get::<usize>("file_cache_max_bytes").unwrap_or(0);
+	if max_total == 0
+	{
+		return fs::read(path).map(|contents| (Arc::new(contents), None));
+	}
+	let max_file = settings.get::<usize>("file_cache_max_file_bytes").unwrap_or(0);
+	cache.get_with_age(Path::new(path), max_total, max_file)
+}
+
+/**
+Decide which part of a file a response carries, honoring the request's Range
+header if range requests are enabled. A satisfiable range turns the response
+into a 206 for only that part of the file, and an unsatisfiable one replaces
+it with a 416.
+
+# Parameters
+- `request`: The request being answered
+- `len`: Length of the complete file
+- `response`: The response to fill in
+- `settings`: The server configuration to apply
+
+# Returns
+The start and end (exclusive) of the bytes to send, or None if there's nothing to send
+*/
+fn select_range(request: &Request, len: usize, response: &mut Response, settings: &Config) -> Option<(usize, usize)>
+{
+	let range = match request.header("range")
+	{
+		Some(header) if settings.get::<bool>("ranges").unwrap_or(false) => ByteRange::parse(header, len),
+		_ => ByteRange::Full
+	};
+	match range
+	{
+		ByteRange::Full => Some((0, len)),
+		ByteRange::Partial(start, end) => {
+			response.code = 206;
+			response.headers.insert(String::from("Content-Range"), format!("bytes {}-{}/{}", start, end, len));
+			Some((start, end + 1))
+		},
+		ByteRange::Unsatisfiable => {
+			*response = Response::new(416, format!("The file is only {} bytes long.", len));
+			response.headers.insert(String::from("Content-Range"), format!("bytes */{}", len));
+			None
+		}
+	}
+}
+
+/**
+Set up a response to stream a file from disk as it's sent, rather than
+reading it all into memory. Only `max_open_files` streams may be open at
+once; beyond that a request waits up to `open_file_wait_ms` for one to
+finish before getting a 503.
+
+# Parameters
+- `path`: Filesystem path of the file
+- `request`: The request being answered
+- `response`: The response to fill in
+- `settings`: The server configuration to apply
+
+# Returns
+Nothing if the response is ready to send, or the response to send instead
+*/
+fn stream_file(path: &str, request: &Request, response: &mut Response, settings: &Config) -> Result<(), Response>
+{
+	let max_open_files = settings.get::<usize>("max_open_files").unwrap_or(0);
+	let wait = Duration::from_millis(settings.get::<u64>("open_file_wait_ms").unwrap_or(0));
+	let slot = match OPEN_FILES.acquire((), max_open_files, wait)
+	{
+		Some(slot) => slot,
+		None => {
+			warn!(target: LOG_SERVER, "Refusing to stream {}: already streaming {} files", path, max_open_files);
+			return Err(Response::new(503, String::from("The server is too busy to send this file right now.")));
+		}
+	};
+	let file = match fs::File::open(path)
+	{
+		Ok(file) => file,
+		Err(e) => {return Err(not_found(settings, format!("{}",e)));}
+	};
+	let len = match file.metadata()
+	{
+		Ok(meta) => meta.len() as usize,
+		Err(e) => {return Err(not_found(settings, format!("{}",e)));}
+	};
+	if let Some((start, end)) = select_range(request, len, response, settings)
+	{
+		let chunk_bytes = settings.get::<usize>("stream_chunk_bytes").unwrap_or(streaming::STREAM_CHUNK_BYTES);
+		response.stream = Some(Box::new(FileStream::new(file, start as u64, (end - start) as u64, Some(slot)).with_chunk_bytes(chunk_bytes)));
+	}
+	Ok(())
+}
+
+/**
+Look for an index file to serve in place of a directory. The `index` of a
+`directory_index_files` entry covering the directory is tried first, then
+each name in the `index_files` setting in order.
+
+# Parameters
+- `dir`: Filesystem path of the requested directory
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+
+# Returns
+The filesystem path and file name of the first index file that exists, if any
+*/
+fn find_index(dir: &str, res: &ResourcePath, settings: &Config) -> Option<(String, String)>
+{
+	let mut index_files = settings.get::<Vec<String>>("index_files").unwrap_or_default();
+	if let Some(index) = directory_setting(res, settings, "directory_index_files", "index")
+	{
+		index_files.insert(0, index);
+	}
+	index_files.into_iter()
+		.map(|name| (format!("{}/{}", dir.trim_end_matches('/'), name), name))
+		.find(|(path, _)| Path::new(path).is_file())
+}
+
+/**
+Check a resolved file against the `blocked_extensions` setting. The whole name
+of a dotfile like `.env` counts as its extension, and trailing dots and spaces
+are ignored since some filesystems ignore them too.
+
+# Parameters
+- `path`: Decoded filesystem path of the file about to be served
+- `settings`: The server configuration to apply
+
+# Returns
+true if the file must not be served
+*/
+fn has_blocked_extension(path: &str, settings: &Config) -> bool
+{
+	let blocked = settings.get::<Vec<String>>("blocked_extensions").unwrap_or_default();
+	if blocked.is_empty()
+	{
+		return false;
+	}
+	let name = path.rsplit('/').next().unwrap_or("").trim_end_matches(&['.', ' '][..]);
+	let extension = match name.rfind('.')
+	{
+		Some(index) => &name[(index+1)..],
+		None => {return false;}
+	};
+	blocked.iter().any(|b| b.trim_start_matches('.').eq_ignore_ascii_case(extension))
+}
+
+/**
+Find the root a resource should be served from according to the
+`extension_roots` setting, a table from file extension to the directory
+that files with that extension are kept in instead of the webroot.
+
+# Parameters
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+
+# Returns
+The root configured for the resource's extension, or None to use the webroot
+*/
+fn root_for_extension(res: &ResourcePath, settings: &Config) -> Option<String>
+{
+	let extension = res.get_extension();
+	if extension.is_empty()
+	{
+		return None;
+	}
+	let roots = settings.get_table("extension_roots").unwrap_or_default();
+	roots.into_iter()
+		.find(|(configured, _)| configured.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+		.and_then(|(configured, root)| match root.into_str()
+		{
+			Ok(root) => Some(root),
+			Err(e) => {warn!(target: LOG_SERVER, "Ignoring extension_roots entry for {}: {}", configured, e); None}
+		})
+}
+
+/**
+Check whether a path leads to or through a hidden file or directory, one
+whose name starts with a dot. The `/.well-known/` directory from RFC 8615 is
+meant to be public, so it doesn't count.
+
+# Parameters
+- `path`: The decoded request path
+
+# Returns
+true if some segment of the path is hidden
+
+# Examples
+```
+use c20web::is_dotfile_path;
+
+assert!(is_dotfile_path("/.git/config"));
+assert!(is_dotfile_path("/app/.env"));
+assert!(!is_dotfile_path("/.well-known/acme-challenge/token"));
+assert!(is_dotfile_path("/docs/.well-known/x"));
+assert!(!is_dotfile_path("/site.css"));
+```
+*/
+pub fn is_dotfile_path(path: &str) -> bool
+{
+	path.split('/')
+		.filter(|segment| !segment.is_empty() && *segment != ".")
+		.enumerate()
+		.any(|(index, segment)| segment.starts_with('.') && !(index == 0 && segment == ".well-known"))
+}
+
+/**
+Build the absolute URL a client would use to reach a path on this server.
+The scheme, host and port come from `public_scheme`, `public_host` and
+`public_port` when they're set, since behind a proxy they can differ from
+what the server itself listens on. Otherwise the scheme is http, as every
+listener is plain HTTP, and the host and port are the ones in the request's
+`Host` header. The port is left out when it's the scheme's default.
+
+# Parameters
+- `request`: The request being answered
+- `settings`: The server configuration to apply
+- `path`: The path the URL should lead to, like "/docs/"
+
+# Returns
+The URL, or None if there's no public_host and the request has no valid Host
+
+# Examples
+```
+use c20web::{absolute_url, Request};
+use c20web::statics::DEFAULT_CONFIG;
+
+let mut settings = config::Config::default();
+settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+let request = Request::parse(Box::new(b"GET / HTTP/1.1\r\nHost: 10.0.0.5:8000\r\n\r\n".to_owned())).ok().unwrap();
+assert_eq!(absolute_url(&request, &settings, "/docs/"), Some(String::from("http://10.0.0.5:8000/docs/")));
+
+settings.set("public_scheme", "https").unwrap();
+settings.set("public_host", "www.example.com").unwrap();
+assert_eq!(absolute_url(&request, &settings, "/docs/"), Some(String::from("https://www.example.com/docs/")));
+settings.set("public_port", 8443).unwrap();
+assert_eq!(absolute_url(&request, &settings, "/docs/"), Some(String::from("https://www.example.com:8443/docs/")));
+```
+*/
+pub fn absolute_url(request: &Request, settings: &Config, path: &str) -> Option<String>
+{
+	let scheme = settings.get::<String>("public_scheme").unwrap_or_default();
+	let scheme = if scheme.is_empty() {String::from("http")} else {scheme.to_ascii_lowercase()};
+	let port = match settings.get::<u16>("public_port").unwrap_or(0)
+	{
+		0 => None,
+		port => Some(port)
+	};
+	url_for(request, settings, &scheme, port, path)
+}
+
+/**
+Build an absolute URL for a path, as `absolute_url` does, but with the
+scheme and port given.
+
+# Parameters
+- `request`: The request being answered
+- `settings`: The server configuration to apply
+- `scheme`: The scheme of the URL, in lower case
+- `port`: The port to use, or None for the one in `Host`, or the default if `public_host` is set
+- `path`: The path the URL should lead to
+
+# Returns
+The URL, or None if there's no public_host and the request has no valid Host
+*/
+fn url_for(request: &Request, settings: &Config, scheme: &str, port: Option<u16>, path: &str) -> Option<String>
+{
+	let public_host = settings.get::<String>("public_host").unwrap_or_default();
+	let (hostname, host_port) = if public_host.is_empty()
+	{
+		let host = request.header("host").unwrap_or("").trim();
+		//the port is whatever follows the last colon, unless that's inside an IPv6 literal
+		match host.rfind(':')
+		{
+			Some(colon) if !host[colon..].contains(']') => (String::from(&host[..colon]), host[(colon+1)..].parse::<u16>().ok()),
+			_ => (String::from(host), None)
+		}
+	}else{
+		(public_host, None)
+	};
+	if hostname.is_empty() || hostname.contains(|c: char| c == '/' || c == '@' || c.is_whitespace())
+	{
+		return None;
+	}
+	let default_port = match scheme
+	{
+		"https" => Some(443),
+		"http" => Some(80),
+		_ => None
+	};
+	let path = if path.starts_with('/') {String::from(path)} else {format!("/{}", path)};
+	match port.or(host_port).filter(|port| Some(*port) != default_port)
+	{
+		Some(port) => Some(format!("{}://{}:{}{}", scheme, hostname, port, path)),
+		None => Some(format!("{}://{}{}", scheme, hostname, path))
+	}
+}
+
+/**
+# Returns
+true if the request is for a path beneath `upload_path`, where PUT writes files and DELETE removes them
+*/
+fn accepts_upload(request: &Request, settings: &Config) -> bool
+{
+	let upload_path = settings.get::<String>("upload_path").unwrap_or_default();
+	!upload_path.is_empty() && path_is_within(&(ResourcePath{resource: request.resource.clone()}).get_decoded(), &upload_path)
+}
+
+/**
+Check that a PUT or DELETE request may change the file it names. Changes
+must carry `upload_token` as a bearer token, so they stay disabled while no
+token is configured, and the path has to pass the same checks a GET does, so
+nothing can be written that the server would refuse to serve.
+
+# Parameters
+- `request`: The PUT or DELETE request
+- `webroot`: Directory the file is in
+
+# Returns
+The decoded path and the file's location on disk, or the response refusing the change
+*/
+fn writable_target(request: &Request, webroot: &str, settings: &Config) -> Result<(String, String), Response>
+{
+	let token = settings.get::<String>("upload_token").unwrap_or_default();
+	let presented = request.header("authorization").and_then(|auth| auth.strip_prefix("Bearer ")).map(|t| t.trim());
+	if token.is_empty() || !presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+	{
+		warn!(target: LOG_SECURITY, "Refused {} without a valid token: {}", request.method, request.resource);
+		let mut response = Response::new(401, String::from("Changing files needs a valid token."));
+		response.headers.insert(String::from("WWW-Authenticate"), String::from("Bearer"));
+		return Err(response);
+	}
+
+	let res = ResourcePath{resource: request.resource.clone()};
+	let decoded = res.get_decoded();
+	let path = res.get_path(String::from(webroot));
+	if res.is_traversal() || res.has_control_characters() || res.has_windows_path_components()
+		|| (res.has_double_encoding() && settings.get::<bool>("block_double_encoding").unwrap_or(true))
+		|| (is_dotfile_path(&decoded) && settings.get::<bool>("block_dotfiles").unwrap_or(false))
+		|| has_blocked_extension(&path, settings)
+	{
+		warn!(target: LOG_SECURITY, "Refused {} of a disallowed path: {}", request.method, res.resource.escape_debug());
+		return Err(Response::new(403, String::from("Files at that path can't be changed.")));
+	}
+	Ok((decoded, path))
+}
+
+/**
+Compare two secrets in time that depends only on their lengths, so a client
+can't find a token one byte at a time by timing the refusals.
+
+# Returns
+true if the two are identical
+*/
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool
+{
+	if a.len() != b.len()
+	{
+		return false;
+	}
+	a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/**
+Write the body of a PUT request to the file it names in the webroot,
+creating any directories it needs.
+
+# Parameters
+- `request`: The PUT request, with its body
+- `webroot`: Directory the file is written beneath
+- `settings`: The server configuration to apply
+
+# Returns
+201 if the file was created, 204 if it replaced one, or the reason it wasn't written
+*/
+fn upload(request: &Request, webroot: &str, settings: &Config) -> Response
+{
+	let (decoded, path) = match writable_target(request, webroot, settings)
+	{
+		Ok(target) => target,
+		Err(refused) => {return refused;}
+	};
+	if decoded.ends_with('/')
+	{
+		return Response::new(409, String::from("Uploads need a file name."));
+	}
+	let path = Path::new(&path);
+	//writing through a link could put the file anywhere, outside the upload directory included
+	if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+	{
+		warn!(target: LOG_SECURITY, "Refused upload over a symbolic link: {}", request.resource);
+		return Response::new(403, String::from("A symbolic link already has that name."));
+	}
+	if path.is_dir()
+	{
+		return Response::new(409, String::from("A directory already has that name."));
+	}
+	let existed = path.exists();
+	let atomic = settings.get::<bool>("atomic_uploads").unwrap_or(true);
+	let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+		.and_then(|_| request.body_reader())
+		.and_then(|mut body| if atomic {write_atomically(path, &mut body)} else {fs::File::create(path).and_then(|mut file| io::copy(&mut body, &mut file)).map(|_| ())});
+	if let Err(e) = written
+	{
+		error!(target: LOG_SERVER, "Couldn't write upload {}: {}", path.display(), e);
+		return Response::new(500, String::from("The file couldn't be written."));
+	}
+	info!(target: LOG_SERVER, "Uploaded {} bytes to {}", request.body_len(), decoded);
+	no_content(if existed {204} else {201})
+}
+
+/**
+Write a file by writing a temporary file next to it and renaming that into
+place, so anyone reading the file sees either the old contents or the new,
+never part of each. The temporary file is removed if anything goes wrong.
+
+# Parameters
+- `path`: The file to write, whose directory already exists
+- `contents`: What the file should hold
+*/
+fn write_atomically(path: &Path, contents: &mut dyn Read) -> io::Result<()>
+{
+	static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+	let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+	//hidden, and unique to this upload, so two uploads of the same file don't share it
+	let temp = path.with_file_name(format!(".{}.upload-{}-{}", name, std::process::id(), NEXT_TEMP.fetch_add(1, Ordering::Relaxed)));
+	let written = fs::File::create(&temp).and_then(|mut file| io::copy(contents, &mut file)).and_then(|_| fs::rename(&temp, path));
+	if written.is_err()
+	{
+		let _ = fs::remove_file(&temp);
+	}
+	written
+}
+
+/**
+Remove the file a DELETE request names from the webroot. Directories are
+only removed, with everything in them, when `delete_directories` is on.
+
+# Parameters
+- `request`: The DELETE request
+- `webroot`: Directory the file is in
+- `settings`: The server configuration to apply
+
+# Returns
+204 if the file was removed, 404 if there was no such file, or the reason it wasn't removed
+*/
+fn delete(request: &Request, webroot: &str, settings: &Config) -> Response
+{
+	let (decoded, path) = match writable_target(request, webroot, settings)
+	{
+		Ok(target) => target,
+		Err(refused) => {return refused;}
+	};
+	//the upload directory itself holds everything that was ever uploaded
+	let segments = |p: &str| p.split('/').filter(|segment| !segment.is_empty() && *segment != ".").map(String::from).collect::<Vec<String>>();
+	if segments(&decoded) == segments(&settings.get::<String>("upload_path").unwrap_or_default())
+	{
+		warn!(target: LOG_SECURITY, "Refused DELETE of the upload directory: {}", request.resource);
+		return Response::new(403, String::from("The upload directory can't be deleted."));
+	}
+	let meta = match fs::symlink_metadata(&path)
+	{
+		Ok(meta) => meta,
+		Err(_) => {return Response::new(404, String::from("No such file or directory"));}
+	};
+	let removed = if meta.is_dir()
+	{
+		if !settings.get::<bool>("delete_directories").unwrap_or(false)
+		{
+			return Response::new(403, String::from("Directories can't be deleted."));
+		}
+		fs::remove_dir_all(&path)
+	}else{
+		fs::remove_file(&path)
+	};
+	match removed
+	{
+		Ok(()) => {
+			info!(target: LOG_SERVER, "Deleted {}", decoded);
+			no_content(204)
+		},
+		Err(e) => {
+			error!(target: LOG_SERVER, "Couldn't delete {}: {}", path, e);
+			Response::new(500, String::from("The file couldn't be deleted."))
+		}
+	}
+}
+
+/// A response with the given code and no body
+fn no_content(code: u16) -> Response
+{
+	Response{code, mime: String::from("text/plain"), body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: None}
+}
+
+/**
+Send the client to the HTTPS version of the URL it asked for, on the same
+host, or `public_host` if it's set, with any port replaced by `https_port`.
+
+# Parameters
+- `request`: The request to redirect
+- `settings`: The server configuration to apply
+
+# Returns
+A 301 Response pointing at the https URL, or 400 if the request has no Host to redirect to
+*/
+fn https_redirect(request: &Request, settings: &Config) -> Response
+{
+	let https_port = settings.get::<u16>("https_port").unwrap_or(443);
+	let location = match url_for(request, settings, "https", Some(https_port), &request.resource)
+	{
+		Some(location) => location,
+		None => {return Response::new(400, String::from("A valid Host header is required."));}
+	};
+
+	let mut response = Response::new(301, format!("This site is only available over HTTPS, at {}", location));
+	response.headers.insert(String::from("Location"), location);
+	response
+}
+
+/**
+Send the client elsewhere if its path is in the `redirects` table, which maps
+request paths to where they've moved, like `"/old.html" = "/new.html"`.
+
+# Parameters
+- `request`: The request to redirect
+- `settings`: The server configuration to apply
+
+# Returns
+A 301 Response, or None if the path isn't redirected
+*/
+fn configured_redirect(request: &Request, settings: &Config) -> Option<Response>
+{
+	let path = (ResourcePath{resource: request.resource.clone()}).get_decoded();
+	let target = settings.get_table("redirects").ok()?.remove(&path)?.into_str().ok()?;
+	let location = redirect_location(request, settings, &target);
+	let mut response = Response::new(301, format!("This page has moved to {}", location));
+	response.headers.insert(String::from("Location"), location);
+	Some(response)
+}
+
+/**
+Look for redirects that would send a client around in circles, like `/a`
+going to `/b` and `/b` back to `/a`. Only targets that are paths on this
+site are followed, since a full URL can't be told apart from another site.
+
+# Parameters
+- `settings`: The server configuration to check
+
+# Returns
+The paths in the first loop found, starting and ending with the same one, or None if there are no loops
+*/
+fn redirect_cycle(settings: &Config) -> Option<Vec<String>>
+{
+	let table = settings.get_table("redirects").ok()?;
+	let mut next: HashMap<String, String> = HashMap::new();
+	for (path, target) in table
+	{
+		if let Ok(target) = target.into_str()
+		{
+			if target.starts_with('/') && !target.starts_with("//")
+			{
+				next.insert(path, (ResourcePath{resource: target}).get_decoded());
+			}
+		}
+	}
+	let mut starts: Vec<&String> = next.keys().collect();
+	starts.sort();
+	for start in starts
+	{
+		let mut chain = vec![start.clone()];
+		let mut current = start;
+		while let Some(target) = next.get(current)
+		{
+			if let Some(seen) = chain.iter().position(|path| path == target)
+			{
+				let mut cycle = chain.split_off(seen);
+				cycle.push(target.clone());
+				return Some(cycle);
+			}
+			chain.push(target.clone());
+			current = target;
+		}
+	}
+	None
+}
+
+/**
+The `Location` to send for a redirect to a path on this site: the path
+itself, which every current client resolves against the URL it asked for,
+or the full URL built from `public_scheme`, `public_host` and `public_port`
+when `absolute_redirects` is on, for older clients that need one. Targets
+that are already full URLs are left alone.
+
+# Parameters
+- `request`: The request being redirected
+- `settings`: The server configuration to apply
+- `target`: Where the redirect leads, like "/docs/"
+
+# Returns
+The value for the `Location` header
+*/
+fn redirect_location(request: &Request, settings: &Config, target: &str) -> String
+{
+	if target.starts_with('/') && !target.starts_with("//") && settings.get::<bool>("absolute_redirects").unwrap_or(false)
+	{
+		if let Some(url) = absolute_url(request, settings, target)
+		{
+			return url;
+		}
+	}
+	String::from(target)
+}
+
+/**
+Build the response for a well-known file configured in the settings rather
+than kept in the webroot: `/robots.txt` from `robots_txt_path` or
+`robots_txt`, and `/.well-known/security.txt` from `security_txt_path` or
+`security_txt`. A configured file takes precedence over inline contents.
+
+# Parameters
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+
+# Returns
+The Response for the well-known file, or None if the resource isn't one that's configured
+*/
+fn well_known_response(res: &ResourcePath, settings: &Config) -> Option<Response>
+{
+	let key = match res.get_decoded().as_str()
+	{
+		"/robots.txt" => "robots_txt",
+		"/.well-known/security.txt" => "security_txt",
+		_ => {return None;}
+	};
+	let text_response = |body: Vec<u8>| Response{code: 200, mime: String::from("text/plain"), body, headers: Headers::new(), use_error_page: false, stream: None};
+
+	let path = settings.get::<String>(&format!("{}_path", key)).unwrap_or_default();
+	if !path.is_empty()
+	{
+		match fs::read(&path)
+		{
+			Ok(bytes) => {return Some(text_response(bytes));},
+			Err(e) => {warn!(target: LOG_CONTENT, "Couldn't read {}_path {} - {}", key, path, e);}
+		}
+	}
+	let inline = settings.get::<String>(key).unwrap_or_default();
+	if !inline.is_empty()
+	{
+		return Some(text_response(inline.into_bytes()));
+	}
+	None
+}
+
+/**
+Answer an ACME HTTP-01 challenge from the files in `acme_challenge_dir`, so a
+certificate authority can verify the server even while it's redirecting to
+HTTPS, hiding dotfiles or down for maintenance. Tokens are base64url, so
+anything else under the challenge path is simply not found.
+
+# Parameters
+- `request`: The request to answer
+- `settings`: The server configuration to apply
+
+# Returns
+The challenge Response, or None if the request isn't for a challenge or no directory is configured
+*/
+fn acme_challenge(request: &Request, settings: &Config) -> Option<Response>
+{
+	let dir = settings.get::<String>("acme_challenge_dir").unwrap_or_default();
+	if dir.is_empty() || (request.method != "GET" && request.method != "HEAD")
+	{
+		return None;
+	}
+	let decoded = (ResourcePath{resource: request.resource.clone()}).get_decoded();
+	let token = decoded.strip_prefix("/.well-known/acme-challenge/")?;
+	if token.is_empty() || !token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+	{
+		return Some(Response::new(404, String::from("No such challenge")));
+	}
+	match fs::read(Path::new(&dir).join(token))
+	{
+		Ok(body) => Some(Response{code: 200, mime: String::from("text/plain"), body, headers: Headers::new(), use_error_page: false, stream: None}),
+		Err(e) => {
+			info!(target: LOG_CONTENT, "No ACME challenge {} in {} - {}", token, dir, e);
+			Some(Response::new(404, String::from("No such challenge")))
+		}
+	}
+}
+
+/**
+Build the welcome response for the root path of a site that has no index
+file, from either the `root_page` file or the inline `root_html` setting.
+
+# Parameters
+- `settings`: The server configuration to apply
+
+# Returns
+The welcome Response, or None if neither setting is configured
+*/
+fn root_response(settings: &Config) -> Option<Response>
+{
+	let page = settings.get::<String>("root_page").unwrap_or_default();
+	if !page.is_empty()
+	{
+		match fs::read(&page)
+		{
+			Ok(bytes) => {
+				let mime = ResourcePath{resource: page}.get_mime().to_owned();
+				return Some(Response{code: 200, mime, body: bytes, headers: Headers::new(), use_error_page: false, stream: None});
+			},
+			Err(e) => {warn!(target: LOG_CONTENT, "Couldn't read root_page {} - {}", page, e);}
+		}
+	}
+	let html = settings.get::<String>("root_html").unwrap_or_default();
+	if !html.is_empty()
+	{
+		return Some(Response::new(200, html));
+	}
+	None
+}
+
+/**
+Decide whether a resource should be downloaded rather than displayed, based on
+the `attachment_extensions` and `attachment_paths` settings.
+
+# Parameters
+- `res`: The requested resource
+- `settings`: The server configuration to apply
+
+# Returns
+true if the response should carry an attachment Content-Disposition
+*/
+fn is_attachment(res: &ResourcePath, settings: &Config) -> bool
+{
+	let extensions = settings.get::<Vec<String>>("attachment_extensions").unwrap_or_default();
+	let extension = res.get_extension();
+	if !extension.is_empty() && extensions.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+	{
+		return true;
+	}
+	let prefixes = settings.get::<Vec<String>>("attachment_paths").unwrap_or_default();
+	let path = res.get_decoded();
+	prefixes.iter().any(|prefix| path_is_within(&path, prefix))
+}
+
+/**
+Pick the encoding for a response's body, from the ones enabled in the config
+and accepted by the client, and mark the response as depending on
+Accept-Encoding if its type is one that gets compressed.
+
+# Parameters
+- `request`: The request being answered, whose Accept-Encoding header is consulted
+- `response`: The response being encoded, or a 304 standing in for it
+- `settings`: The server configuration to apply
+
+# Returns
+The encoding to apply, which is Identity when the body is sent as it is
+*/
+fn negotiate_encoding(request: &Request, response: &mut Response, settings: &Config) -> Encoding
+{
+	let allowlist = settings.get::<Vec<String>>("compress_mime_types").unwrap_or_default();
+	if !compression::should_compress(&response.mime, &allowlist)
+	{
+		return Encoding::Identity;
+	}
+	let brotli = settings.get::<bool>("brotli").unwrap_or(false);
+	let gzip = settings.get::<bool>("gzip").unwrap_or(false);
+	if !brotli && !gzip
+	{
+		return Encoding::Identity;
+	}
+
+	//whatever we pick, a cache has to know the body depends on what the client accepts
+	response.add_vary("Accept-Encoding");
+	compression::negotiate(request.header("accept-encoding").unwrap_or(""), brotli, gzip)
+}
+
+/**
+Compress the body of a response if its type benefits from it, using the best
+encoding that's both enabled in the config and accepted by the client.
+
+# Parameters
+- `request`: The request being answered, whose Accept-Encoding header is consulted
+- `response`: The response whose body will be replaced with the encoded version
+- `settings`: The server configuration to apply
+*/
+fn encode_body(request: &Request, response: &mut Response, settings: &Config)
+{
+	let encoding = negotiate_encoding(request, response, settings);
+	if encoding == Encoding::Identity
+	{
+		return;
+	}
+	let level_key = if encoding == Encoding::Brotli {"brotli_level"} else {"gzip_level"};
+	//clamp_compression_levels has already corrected the setting at startup, this only guards against embedders that skip it
+	let level = compression::clamp_level(encoding, settings.get::<i64>(level_key).unwrap_or(6));
+	match compression::compress(&response.body, encoding, level)
+	{
+		Ok(body) => {
+			response.body = body;
+			response.headers.insert(String::from("Content-Encoding"), String::from(encoding.token()));
+			if let Some(etag) = response.headers.get_mut("ETag")
+			{
+				*etag = conditional::etag_for_coding(etag, encoding.token());
+			}
+		},
+		Err(e) => {error!(target: LOG_SERVER, "Sending uncompressed body because {} compression failed: {}", encoding.token(), e);}
+	}
+}
+
+/**
+Build the response for a resource that couldn't be found. Normally this is a
+plain 404 using the error page, but if `not_found_page` is configured then
+that file is served as-is, with `not_found_status` as the status code.
+
+# Parameters
+- `settings`: The server configuration to apply
+- `reason`: Description of why the resource wasn't found, used when falling back to the error page
+
+# Returns
+The Response to send for the missing resource
+*/
+fn not_found(settings: &Config, reason: String) -> Response
+{
+	let page = settings.get::<String>("not_found_page").unwrap_or_default();
+	if page.is_empty()
+	{
+		return Response::new(404, reason);
+	}
+	let code = match settings.get::<u16>("not_found_status"){
+		Ok(r) => r,
+		Err(e) => {warn!(target: LOG_CONTENT, "Using status 404 for not_found_page because not_found_status is invalid: {}",e); 404}
+	};
+	match fs::read(&page)
+	{
+		Ok(bytes) => {
+			let mime = ResourcePath{resource: page}.get_mime().to_owned();
+			Response{code, mime, body: bytes, headers: Headers::new(), use_error_page: false, stream: None}
+		},
+		Err(e) => {
+			warn!(target: LOG_CONTENT, "Using the error page because we couldn't read not_found_page {} - {}", page, e);
+			Response::new(404, reason)
+		}
+	}
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use log::Level;
+	use statics::DEFAULT_CONFIG;
+
+	/// Default config with the given TOML merged over it, and the webroot pointed at the bundled test files
+	fn settings_with(toml: &str) -> Config
+	{
+		let mut settings = Config::default();
+		settings.merge(config::File::from_str(&DEFAULT_CONFIG, config::FileFormat::Toml)).unwrap();
+		settings.merge(config::File::from_str("webroot = \"data/webroot\"", config::FileFormat::Toml)).unwrap();
+		settings.merge(config::File::from_str(toml, config::FileFormat::Toml)).unwrap();
+		settings
+	}
+
+	/// Empty scratch directory for a test to put files in
+	fn scratch_dir(name: &str) -> PathBuf
+	{
+		let dir = std::env::temp_dir().join(format!("c20web-test-{}-{}", std::process::id(), name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Every log record emitted while testing, as (target, level, message)
+	static CAPTURED_LOGS: std::sync::Mutex<Vec<(String, Level, String)>> = std::sync::Mutex::new(Vec::new());
+	static INSTALL_LOGGER: std::sync::Once = std::sync::Once::new();
+
+	struct CaptureLogger;
+
+	impl log::Log for CaptureLogger
+	{
+		fn enabled(&self, _metadata: &log::Metadata) -> bool {true}
+		fn log(&self, record: &log::Record)
+		{
+			let entry = (String::from(record.target()), record.level(), format!("{}", record.args()));
+			CAPTURED_LOGS.lock().unwrap_or_else(|e| e.into_inner()).push(entry);
+		}
+		fn flush(&self) {}
+	}
+
+	/// Start capturing log records, if we aren't already
+	fn capture_logs()
+	{
+		INSTALL_LOGGER.call_once(|| {
+			log::set_logger(&CaptureLogger).unwrap();
+			log::set_max_level(log::LevelFilter::Trace);
+		});
+	}
+
+	/// Targets and levels of the captured log records whose message contains `needle`
+	fn logs_containing(needle: &str) -> Vec<(String, Level)>
+	{
+		CAPTURED_LOGS.lock().unwrap_or_else(|e| e.into_inner()).iter()
+			.filter(|(_, _, message)| message.contains(needle))
+			.map(|(target, level, _)| (target.clone(), *level))
+			.collect()
+	}
+
+	/// Serve a single connection with the given settings on a background thread, and return the client end of it
+	fn connect_to_server(settings: Config) -> TcpStream
+	{
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			serve_connection(stream, &settings);
+		});
+		let client = TcpStream::connect(addr).unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		client
+	}
+
+	/// Everything the server sends until it closes the connection
+	fn read_until_closed(client: &mut TcpStream) -> String
+	{
+		let mut received = Vec::new();
+		client.read_to_end(&mut received).unwrap();
+		String::from_utf8_lossy(&received).into_owned()
+	}
+
+	fn get(resource: &str) -> Request
+	{
+		get_with(resource, "")
+	}
+
+	/// GET request for the resource, with extra header lines (each ending in CRLF)
+	fn get_with(resource: &str, headers: &str) -> Request
+	{
+		let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", resource, headers);
+		match Request::parse(raw.into_bytes().into_boxed_slice())
+		{
+			Ok(req) => req,
+			Err(_) => panic!("Request failed to parse")
+		}
+	}
+
+	// Request::parse
+	#[test]
+	fn parse_request()
+	{
+		let req_string = Box::new(b"GET /hello.htm HTTP/1.1\r\nUser-Agent: Mozilla/4.0 (compatible; MSIE5.01; Windows NT)\r\nHost: 127.0.0.1:8000\r\n\r\n".to_owned());
+		let request = Request::parse(req_string);
+		match request
+		{
+			Ok(req) =>{
+				assert_eq!(req.method, "GET");
+				assert_eq!(req.resource, "/hello.htm");
+				assert_eq!(req.http_version, "HTTP/1.1");
+			}
+			Err(_) => panic!("Request failed to parse")
+		}
+	}
+
+	#[test]
+	fn ambiguous_body_length_rejected()
+	{
+		let parse = |raw: &[u8]| Request::parse(raw.to_vec().into_boxed_slice());
+
+		assert!(parse(b"GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n").is_ok());
+		let duplicate = parse(b"GET / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 10\r\n\r\nhello").err().unwrap();
+		assert_eq!(duplicate.code, 400);
+		let repeated = parse(b"GET / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\nhello").err().unwrap();
+		assert_eq!(repeated.code, 400);
+		let listed = parse(b"GET / HTTP/1.1\r\nContent-Length: 5, 10\r\n\r\nhello").err().unwrap();
+		assert_eq!(listed.code, 400);
+		let both = parse(b"GET / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nhello").err().unwrap();
+		assert_eq!(both.code, 400);
+	}
+
+	// Response.to_vec
+	#[test]
+	fn response_to_vec()
+	{
+		let body = String::from("<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>Hello</title></head><body><h1>Hello</h1><p>Greetings from Rust</p></body></html>").as_bytes().to_vec();
+		let res = Response{code: 200, mime: String::from("text/html"), body, headers: Headers::new(), use_error_page: false, stream: None};
+		let out_vec = res.to_vec();
+
+		let out_expected = b"HTTP/1.1 200 OK\r\nContent-Type: text/html;\r\nContent-Length: 146;\r\n\r\n<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>Hello</title></head><body><h1>Hello</h1><p>Greetings from Rust</p></body></html>".to_vec();
+		assert_eq!(out_vec, out_expected);
+	}
+
+	#[test]
+	fn bodiless_statuses_have_no_length_or_body()
+	{
+		let empty = Response{code: 200, mime: String::from("text/plain"), body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: None};
+		assert_eq!(empty.to_vec(), b"HTTP/1.1 200 OK\r\nContent-Type: text/plain;\r\nContent-Length: 0;\r\n\r\n".to_vec());
+
+		let no_content = Response{code: 204, mime: String::from("text/plain"), body: b"stray".to_vec(), headers: Headers::new(), use_error_page: false, stream: None};
+		assert_eq!(no_content.to_vec(), b"HTTP/1.1 204 No Content\r\nContent-Type: text/plain;\r\n\r\n".to_vec());
+
+		let mut not_modified = Response::new(304, String::new());
+		not_modified.headers.insert(String::from("ETag"), String::from("\"abc\""));
+		not_modified.stream = Some(Box::new(ChunkedStream::new(Box::new(vec![b"stray".to_vec()].into_iter()))));
+		let mut out = Vec::new();
+		not_modified.send(&mut out);
+		assert_eq!(out, b"HTTP/1.1 304 Not Modified\r\nContent-Type: text/html;\r\nETag: \"abc\"\r\n\r\n".to_vec());
+	}
+
+	/// A connection that fails every write with the given error
+	struct FailingWriter(ErrorKind, &'static str);
+
+	impl Write for FailingWriter
+	{
+		fn write(&mut self, _buf: &[u8]) -> io::Result<usize>
+		{
+			Err(io::Error::new(self.0, self.1))
+		}
+
+		fn flush(&mut self) -> io::Result<()>
+		{
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn disconnected_clients_are_not_errors()
+	{
+		capture_logs();
+		let response = Response::streamed(200, "text/plain", Box::new(vec![b"chunk".to_vec()].into_iter()));
+		assert_eq!(response.send(FailingWriter(ErrorKind::BrokenPipe, "broken-pipe-test")), 0);
+		assert_eq!(Response::new(200, String::from("hi")).send(FailingWriter(ErrorKind::ConnectionReset, "connection-reset-test")), 0);
+		let levels: Vec<Level> = logs_containing("broken-pipe-test").into_iter().chain(logs_containing("connection-reset-test")).map(|(_, level)| level).collect();
+		assert!(!levels.is_empty());
+		assert!(levels.iter().all(|level| *level == Level::Debug), "logged at {:?}", levels);
+
+		Response::new(200, String::from("hi")).send(FailingWriter(ErrorKind::Other, "disk-on-fire-test"));
+		assert_eq!(logs_containing("disk-on-fire-test"), vec![(String::from(LOG_SERVER), Level::Error)]);
+	}
+
+	// respond
+	#[test]
+	fn respond_serves_existing_file()
+	{
+		let response = respond(get("/hello.html"), &settings_with(""));
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, fs::read("data/webroot/hello.html").unwrap());
+	}
+
+	#[test]
+	fn not_found_page_keeps_configured_status()
+	{
+		let dir = scratch_dir("not_found_page");
+		let page = dir.join("notfound.html");
+		fs::write(&page, "<p>Nothing here</p>").unwrap();
+		let settings = settings_with(&format!("not_found_page = {:?}\nnot_found_status = 404", page.to_str().unwrap()));
+
+		let response = respond(get("/no/such/file.html"), &settings);
+		assert_eq!(response.code, 404);
+		assert_eq!(response.mime, "text/html");
+		assert_eq!(response.body, b"<p>Nothing here</p>".to_vec());
+		assert!(response.to_vec().ends_with(b"\r\n\r\n<p>Nothing here</p>"));
+	}
+
+	#[test]
+	fn not_found_without_page_uses_error_page()
+	{
+		let response = respond(get("/no/such/file.html"), &settings_with(""));
+		assert_eq!(response.code, 404);
+		assert!(response.use_error_page);
+	}
+
+	#[test]
+	fn traversal_is_rejected_and_logged_as_security()
+	{
+		capture_logs();
+		let response = respond(get("/images/%2e%2e/../traversal-test-secret.txt"), &settings_with(""));
+		assert_eq!(response.code, 403);
+		assert_eq!(logs_containing("traversal-test-secret.txt"), vec![(String::from(LOG_SECURITY), Level::Warn)]);
+	}
+
+	#[test]
+	fn parse_request_headers()
+	{
+		let buffer = Box::new(b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept:  text/html \r\naccept: */*\r\n\r\n".to_owned());
+		let request = Request::parse(buffer).ok().unwrap();
+		assert_eq!(request.header("host"), Some("example.com"));
+		assert_eq!(request.header("Accept"), Some("text/html, */*"));
+
+		let buffer = Box::new(b"GET / HTTP/1.1\r\nHost example.com\r\n\r\n".to_owned());
+		assert_eq!(Request::parse(buffer).err().unwrap().code, 400);
+	}
+
+	#[test]
+	fn brotli_preferred_over_gzip()
+	{
+		let settings = settings_with("brotli = true\ngzip = true");
+		let original = fs::read("data/webroot/hello.html").unwrap();
+
+		let response = respond(get_with("/hello.html", "Accept-Encoding: gzip, br\r\n"), &settings);
+		assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("br"));
+		let mut decoded = Vec::new();
+		brotli::Decompressor::new(&response.body[..], 4096).read_to_end(&mut decoded).unwrap();
+		assert_eq!(decoded, original);
+
+		let response = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings);
+		assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("gzip"));
+		let mut decoded = Vec::new();
+		flate2::read::GzDecoder::new(&response.body[..]).read_to_end(&mut decoded).unwrap();
+		assert_eq!(decoded, original);
+	}
+
+	#[test]
+	fn compression_levels_clamped_once_at_startup()
+	{
+		capture_logs();
+		let mut settings = settings_with("gzip_level = 4321\nbrotli_level = -8765");
+		clamp_compression_levels(&mut settings).unwrap();
+		assert_eq!(settings.get::<i64>("gzip_level").unwrap(), 9);
+		assert_eq!(settings.get::<i64>("brotli_level").unwrap(), 0);
+		assert_eq!(logs_containing("gzip_level 4321 is outside 0-9"), vec![(String::from(LOG_SERVER), Level::Warn)]);
+		assert_eq!(logs_containing("brotli_level -8765 is outside 0-11"), vec![(String::from(LOG_SERVER), Level::Warn)]);
+
+		for _ in 0..3
+		{
+			let response = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings);
+			assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("gzip"));
+		}
+		assert_eq!(logs_containing("gzip_level 4321").len(), 1);
+	}
+
+	#[test]
+	fn compressed_copies_get_their_own_etag()
+	{
+		let settings = settings_with("brotli = true\ngzip = true");
+		let plain = respond(get("/hello.html"), &settings).headers["ETag"].clone();
+		let gzipped = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings).headers["ETag"].clone();
+		let brotlied = respond(get_with("/hello.html", "Accept-Encoding: br\r\n"), &settings).headers["ETag"].clone();
+		assert_eq!(gzipped, conditional::etag_for_coding(&plain, "gzip"));
+		assert_eq!(brotlied, conditional::etag_for_coding(&plain, "br"));
+
+		//a compressed copy is still the current version of the file
+		let response = respond(get_with("/hello.html", &format!("Accept-Encoding: gzip\r\nIf-None-Match: {}\r\n", gzipped)), &settings);
+		assert_eq!(response.code, 304);
+		assert_eq!(response.headers["ETag"], gzipped);
+		let response = respond(get_with("/hello.html", &format!("If-None-Match: {}\r\n", gzipped)), &settings);
+		assert_eq!(response.code, 304);
+		assert_eq!(response.headers["ETag"], plain);
+		assert_eq!(respond(get_with("/hello.html", &format!("Accept-Encoding: br\r\nIf-Match: {}\r\n", gzipped)), &settings).code, 200);
+
+		//types that aren't compressed keep the file's tag
+		let jpg = respond(get("/hello.jpg"), &settings).headers["ETag"].clone();
+		assert_eq!(respond(get_with("/hello.jpg", "Accept-Encoding: gzip\r\n"), &settings).headers["ETag"], jpg);
+	}
+
+	#[test]
+	fn uncompressible_types_sent_as_is()
+	{
+		let response = respond(get_with("/hello.jpg", "Accept-Encoding: gzip, br\r\n"), &settings_with(""));
+		assert!(!response.headers.contains_key("Content-Encoding"));
+		assert!(!response.headers.contains_key("Vary"));
+		assert_eq!(response.body, fs::read("data/webroot/hello.jpg").unwrap());
+	}
+
+	#[test]
+	fn compress_mime_types_overrides_heuristic()
+	{
+		let settings = settings_with("compress_mime_types = [\"image/jpeg\"]");
+		let response = respond(get_with("/hello.jpg", "Accept-Encoding: gzip\r\n"), &settings);
+		assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("gzip"));
+
+		let response = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings);
+		assert!(!response.headers.contains_key("Content-Encoding"));
+		assert_eq!(response.body, fs::read("data/webroot/hello.html").unwrap());
+	}
+
+	#[test]
+	fn compressed_responses_vary_on_accept_encoding()
+	{
+		let response = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings_with("brotli = false"));
+		assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("gzip"));
+		assert_eq!(response.headers.get("Vary").map(|v| v.as_str()), Some("Accept-Encoding"));
+
+		let out = response.to_vec();
+		let head = String::from_utf8_lossy(&out[..out.len() - response.body.len()]).into_owned();
+		assert!(head.contains("\r\nVary: Accept-Encoding\r\n"));
+
+		let response = respond(get_with("/hello.html", "Accept-Encoding: gzip\r\n"), &settings_with("brotli = false\ngzip = false"));
+		assert!(!response.headers.contains_key("Vary"));
+
+		//sent uncompressed because of what this client accepts, so a cache mustn't give it to one that accepts gzip
+		for accepted in &["", "Accept-Encoding: identity\r\n", "Accept-Encoding: gzip;q=0\r\n"]
+		{
+			let response = respond(get_with("/hello.html", accepted), &settings_with("brotli = false"));
+			assert!(!response.headers.contains_key("Content-Encoding"), "compressed for {:?}", accepted);
+			assert_eq!(response.headers.get("Vary").map(|v| v.as_str()), Some("Accept-Encoding"), "no Vary for {:?}", accepted);
+		}
+
+		//a type that's never compressed is the same whatever the client accepts
+		let response = respond(get_with("/hello.jpg", "Accept-Encoding: gzip\r\n"), &settings_with("brotli = false"));
+		assert!(!response.headers.contains_key("Content-Encoding"));
+		assert!(!response.headers.contains_key("Vary"));
+	}
+
+	// validate_settings
+	#[test]
+	fn missing_webroot_fails_validation()
+	{
+		let settings = settings_with("webroot = \"data/no-such-webroot\"");
+		let err = validate_settings(&settings).err().unwrap();
+		assert!(err.contains("data/no-such-webroot"), "unexpected error: {}", err);
+
+		let settings = settings_with("webroot = \"data/error.html\"");
+		let err = validate_settings(&settings).err().unwrap();
+		assert!(err.contains("not a directory"), "unexpected error: {}", err);
+	}
+
+	#[test]
+	fn working_dir_is_the_first_with_a_config()
+	{
+		let dir = scratch_dir("working_dir");
+		let candidates = vec![dir.join("missing"), dir.join("empty"), dir.join("etc"), dir.join("later")];
+		for found in &candidates[1..]
+		{
+			fs::create_dir_all(found).unwrap();
+		}
+		assert_eq!(find_working_dir(&candidates), None);
+
+		fs::write(dir.join("etc").join("web.toml"), "webroot = \"webroot\"").unwrap();
+		fs::write(dir.join("later").join("web.toml"), "").unwrap();
+		fs::create_dir_all(dir.join("empty").join("web.toml")).unwrap();
+		assert_eq!(find_working_dir(&candidates), Some(dir.join("etc")));
+	}
+
+	#[test]
+	fn included_files_merge_in_order()
+	{
+		let dir = scratch_dir("include");
+		let first = dir.join("vhosts.toml");
+		let second = dir.join("overrides.toml");
+		fs::write(&first, "gzip_level = 3\nrobots_txt = \"User-agent: *\"\ninclude = [\"ignored\"]").unwrap();
+		fs::write(&second, "gzip_level = 9").unwrap();
+		let mut settings = settings_with(&format!("include = [{:?}, {:?}]", first.to_str().unwrap(), dir.join("overrides").to_str().unwrap()));
+
+		merge_includes(&mut settings).unwrap();
+		assert_eq!(settings.get::<String>("robots_txt").unwrap(), "User-agent: *");
+		assert_eq!(settings.get::<i64>("gzip_level").unwrap(), 9);
+		assert_eq!(settings.get::<Vec<String>>("include").unwrap().len(), 2);
+
+		let mut settings = settings_with(&format!("include = [{:?}]", dir.join("missing.toml").to_str().unwrap()));
+		assert!(merge_includes(&mut settings).is_err());
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn captured_webroot_survives_a_swap()
+	{
+		let dir = scratch_dir("snapshot");
+		let live = dir.join("live");
+		fs::create_dir_all(&live).unwrap();
+		fs::write(live.join("page.html"), "first deploy").unwrap();
+		let mut settings = settings_with(&format!("webroot = {:?}\nwebroot_snapshot = true", live.to_str().unwrap()));
+		capture_webroot(&mut settings).unwrap();
+
+		fs::rename(&live, dir.join("retired")).unwrap();
+		fs::create_dir_all(&live).unwrap();
+		fs::write(live.join("page.html"), "second deploy").unwrap();
+		fs::write(live.join("new.html"), "only in the second").unwrap();
+
+		let response = respond(get("/page.html"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"first deploy".to_vec());
+		assert_eq!(respond(get("/new.html"), &settings).code, 404);
+
+		let mut recaptured = settings_with(&format!("webroot = {:?}\nwebroot_snapshot = true", live.to_str().unwrap()));
+		capture_webroot(&mut recaptured).unwrap();
+		assert_eq!(respond(get("/page.html"), &recaptured).body, b"second deploy".to_vec());
+	}
+
+	#[test]
+	fn redirect_loops_fail_validation()
+	{
+		let settings = settings_with("[redirects]\n\"/a\" = \"/b\"\n\"/b\" = \"/a?from=b\"\n\"/c\" = \"/a\"");
+		match validate_settings(&settings)
+		{
+			Err(e) => assert_eq!(e, "redirects loop back on themselves: /a -> /b -> /a"),
+			Ok(_) => panic!("a redirect loop passed validation")
+		}
+
+		let settings = settings_with("[redirects]\n\"/a\" = \"/b\"\n\"/b\" = \"https://example.com/a\"");
+		assert!(validate_settings(&settings).is_ok());
+	}
+
+	#[test]
+	fn unknown_settings_fail_strict_validation()
+	{
+		let settings = settings_with("webroot = \"data/webroot\"\nblock_dotfile = true");
+		assert_eq!(unknown_settings(&settings), vec![String::from("block_dotfile")]);
+		assert!(validate_settings(&settings).is_ok());
+
+		let settings = settings_with("webroot = \"data/webroot\"\nblock_dotfile = true\nstrict_config = true");
+		match validate_settings(&settings)
+		{
+			Err(e) => assert!(e.contains("block_dotfile"), "unexpected error: {}", e),
+			Ok(_) => panic!("unknown setting passed strict validation")
+		}
+		assert!(validate_settings(&settings_with("strict_config = true")).is_ok());
+	}
+
+	#[test]
+	fn attachment_extensions_force_download()
+	{
+		let settings = settings_with("attachment_extensions = [\"jpg\"]");
+		let response = respond(get("/hello.jpg"), &settings);
+		assert_eq!(response.headers.get("Content-Disposition").map(|v| v.as_str()), Some("attachment; filename=\"hello.jpg\""));
+
+		let response = respond(get("/hello.html"), &settings);
+		assert!(!response.headers.contains_key("Content-Disposition"));
+
+		let response = respond(get("/hello.html"), &settings_with("attachment_paths = [\"/hello.html\"]"));
+		assert_eq!(response.headers.get("Content-Disposition").map(|v| v.as_str()), Some("attachment; filename=\"hello.html\""));
+		//a path prefix covers whole segments, not every name that happens to start the same way
+		let response = respond(get("/hello.html"), &settings_with("attachment_paths = [\"/hello\"]"));
+		assert!(!response.headers.contains_key("Content-Disposition"));
+
+		let webroot = scratch_dir("attachment_paths");
+		fs::create_dir_all(webroot.join("downloads")).unwrap();
+		fs::write(webroot.join("downloads/report.txt"), "report").unwrap();
+		fs::write(webroot.join("downloads-old.txt"), "old").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nattachment_paths = [\"/downloads\"]", webroot.to_str().unwrap()));
+		let response = respond(get("/downloads/report.txt"), &settings);
+		assert_eq!(response.headers.get("Content-Disposition").map(|v| v.as_str()), Some("attachment; filename=\"report.txt\""));
+		assert!(!respond(get("/downloads-old.txt"), &settings).headers.contains_key("Content-Disposition"));
+	}
+
+	#[test]
+	fn root_without_index_serves_welcome()
+	{
+		let response = respond(get("/"), &settings_with("root_html = \"<h1>Welcome</h1>\""));
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"<h1>Welcome</h1>".to_vec());
+
+		let dir = scratch_dir("root_page");
+		let page = dir.join("welcome.html");
+		fs::write(&page, "<h1>Welcome from a file</h1>").unwrap();
+		let response = respond(get("/"), &settings_with(&format!("root_page = {:?}\nroot_html = \"unused\"", page.to_str().unwrap())));
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"<h1>Welcome from a file</h1>".to_vec());
+
+		let response = respond(get("/"), &settings_with(""));
+		assert_eq!(response.code, 404);
+	}
+
+	#[test]
+	fn index_file_takes_precedence_over_welcome()
+	{
+		let dir = scratch_dir("index_file");
+		fs::write(dir.join("index.html"), "<h1>Index</h1>").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nroot_html = \"<h1>Welcome</h1>\"", dir.to_str().unwrap()));
+		let response = respond(get("/"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.mime, "text/html");
+		assert_eq!(response.body, b"<h1>Index</h1>".to_vec());
+	}
+
+	#[test]
+	fn directory_index_files_override_the_global_list()
+	{
+		let dir = scratch_dir("directory_index_files");
+		fs::create_dir_all(dir.join("docs/api")).unwrap();
+		fs::create_dir_all(dir.join("blog")).unwrap();
+		fs::write(dir.join("docs/README.html"), "<h1>Docs</h1>").unwrap();
+		fs::write(dir.join("docs/index.html"), "<h1>Docs index</h1>").unwrap();
+		fs::write(dir.join("docs/api/index.html"), "<h1>API index</h1>").unwrap();
+		fs::write(dir.join("blog/README.html"), "<h1>Blog readme</h1>").unwrap();
+		fs::write(dir.join("blog/index.html"), "<h1>Blog</h1>").unwrap();
+		let settings = settings_with(&format!(r#"
+			webroot = {:?}
+
+			[[directory_index_files]]
+			path = "/docs"
+			index = "README.html"
+		"#, dir.to_str().unwrap()));
+
+		assert_eq!(respond(get("/docs/"), &settings).body, b"<h1>Docs</h1>".to_vec());
+		//the override isn't there, so the global list still applies
+		assert_eq!(respond(get("/docs/api/"), &settings).body, b"<h1>API index</h1>".to_vec());
+		assert_eq!(respond(get("/blog/"), &settings).body, b"<h1>Blog</h1>".to_vec());
+	}
+
+	#[test]
+	fn unknown_expectations_fail()
+	{
+		let settings = settings_with("");
+		let response = respond(get_with("/hello.html", "Expect: something-weird\r\n"), &settings);
+		assert_eq!(response.code, 417);
+		assert!(String::from_utf8_lossy(&response.to_vec()).starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+		assert_eq!(respond(get_with("/hello.html", "Expect: 100-continue, weird\r\n"), &settings).code, 417);
+		assert_eq!(respond(get_with("/hello.html", "Expect: 100-Continue\r\n"), &settings).code, 200);
+	}
+
+	#[test]
+	fn force_https_redirects()
+	{
+		let request = |resource: &str, host: &str| Request::parse(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", resource, host).into_bytes().into_boxed_slice()).ok().unwrap();
+		let settings = settings_with("force_https = true");
+		let response = respond(request("/docs/page.html?lang=en&x=1", "example.com:8080"), &settings);
+		assert_eq!(response.code, 301);
+		assert_eq!(response.headers.get("Location").map(|v| v.as_str()), Some("https://example.com/docs/page.html?lang=en&x=1"));
+		assert!(String::from_utf8_lossy(&response.to_vec()).starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+
+		let settings = settings_with("force_https = true\nhttps_port = 8443");
+		let response = respond(request("/", "[::1]:8080"), &settings);
+		assert_eq!(response.headers.get("Location").map(|v| v.as_str()), Some("https://[::1]:8443/"));
+
+		let no_host = Request::parse(Box::new(b"GET / HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+		assert_eq!(respond(no_host, &settings).code, 400);
+
+		//behind a proxy the public name is used instead of whatever Host the proxy sent
+		let settings = settings_with("force_https = true\npublic_host = \"www.example.com\"");
+		let response = respond(request("/docs/", "10.0.0.5:8000"), &settings);
+		assert_eq!(response.headers.get("Location").map(|v| v.as_str()), Some("https://www.example.com/docs/"));
+		let no_host = Request::parse(Box::new(b"GET / HTTP/1.1\r\n\r\n".to_owned())).ok().unwrap();
+		assert_eq!(respond(no_host, &settings).headers.get("Location").map(|v| v.as_str()), Some("https://www.example.com/"));
+		assert_eq!(respond(get("/hello.html"), &settings_with("")).code, 200);
+	}
+
+	#[test]
+	fn blocked_extensions_are_hidden()
+	{
+		let dir = scratch_dir("blocked_extensions");
+		fs::write(dir.join(".env"), "SECRET=1").unwrap();
+		fs::write(dir.join("site.env"), "SECRET=2").unwrap();
+		fs::write(dir.join("page.html"), "<p>fine</p>").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nblocked_extensions = [\"env\", \".key\"]", dir.to_str().unwrap()));
+
+		assert_eq!(respond(get("/page.html"), &settings).code, 200);
+		for resource in &["/.env", "/site.env", "/site.ENV", "/site.%65nv", "/%2eenv", "/site.env.", "/site.env?download=1"]
+		{
+			let response = respond(get(resource), &settings);
+			assert_eq!(response.code, 404, "{} was not blocked", resource);
+			assert!(!response.body.starts_with(b"SECRET"));
+		}
+	}
+
+	#[test]
+	fn retry_after_on_rate_limit_and_unavailable()
+	{
+		let settings = settings_with("retry_after_429 = \"120\"\nretry_after_503 = \"Wed, 21 Oct 2015 07:28:00 GMT\"");
+
+		let mut limited = Response::new(429, String::from(""));
+		add_retry_after(&mut limited, &settings);
+		assert_eq!(limited.headers.get("Retry-After").map(|v| v.as_str()), Some("120"));
+
+		let mut unavailable = Response::new(503, String::from(""));
+		add_retry_after(&mut unavailable, &settings);
+		assert_eq!(unavailable.headers.get("Retry-After").map(|v| v.as_str()), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+
+		let mut not_found = Response::new(404, String::from(""));
+		add_retry_after(&mut not_found, &settings);
+		assert!(!not_found.headers.contains_key("Retry-After"));
+
+		let mut invalid = Response::new(429, String::from(""));
+		add_retry_after(&mut invalid, &settings_with("retry_after_429 = \"soon\""));
+		assert!(!invalid.headers.contains_key("Retry-After"));
+	}
+
+	// serve_connection
+	#[test]
+	fn keepalive_closes_after_max_requests()
+	{
+		let mut client = connect_to_server(settings_with("keepalive_max_requests = 2"));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\nGET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+		let received = read_until_closed(&mut client);
+		let responses: Vec<&str> = received.split("HTTP/1.1 ").skip(1).collect();
+		assert_eq!(responses.len(), 2);
+		assert!(responses[0].starts_with("200 OK"));
+		assert!(!responses[0].contains("Connection: close"));
+		assert!(responses[1].starts_with("200 OK"));
+		assert!(responses[1].contains("\r\nConnection: close\r\n"));
+	}
+
+	#[test]
+	fn keepalive_closes_after_idle_timeout()
+	{
+		let mut client = connect_to_server(settings_with("keepalive_timeout_ms = 200"));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+		let started = std::time::Instant::now();
+		let received = read_until_closed(&mut client);
+		let waited = started.elapsed();
+		assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 1);
+		assert!(!received.contains("Connection: close"));
+		assert!(waited >= Duration::from_millis(200), "closed after only {:?}", waited);
+		assert!(waited < Duration::from_secs(4), "stayed open for {:?}", waited);
+	}
+
+	#[test]
+	fn keepalive_connections_are_capped()
+	{
+		let settings = settings_with("max_keepalive_connections = 1");
+		let request = b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+		let mut first = connect_to_server(settings.clone());
+		first.write_all(request).unwrap();
+		let mut received = Vec::new();
+		let mut chunk = [0u8; 4096];
+		while !String::from_utf8_lossy(&received).contains("</html>")
+		{
+			let n = first.read(&mut chunk).unwrap();
+			assert!(n > 0, "connection closed under the cap");
+			received.extend_from_slice(&chunk[..n]);
+		}
+		assert!(!String::from_utf8_lossy(&received).contains("Connection: close"));
+
+		//the first connection is still waiting for another request, so there's no room for this one
+		let mut second = connect_to_server(settings);
+		second.write_all(request).unwrap();
+		let received = read_until_closed(&mut second);
+		assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 1);
+		assert!(received.contains("\r\nConnection: close\r\n"));
+	}
+
+	#[test]
+	fn connection_close_is_honored()
+	{
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 1);
+		assert!(received.contains("\r\nConnection: close\r\n"));
+	}
+
+	#[test]
+	fn connection_header_tokens()
+	{
+		let request = |connection: &str| get_with("/hello.html", &format!("Connection: {}\r\n", connection));
+		assert!(wants_keep_alive(&request("keep-alive, foo")));
+		assert!(wants_keep_alive(&request("Upgrade")));
+		assert!(!wants_keep_alive(&request("keep-alive, close")));
+		assert!(!wants_keep_alive(&request("foo,CLOSE")));
+
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: te, close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 1);
+		assert!(received.contains("\r\nConnection: close\r\n"));
+	}
+
+	#[test]
+	fn slow_request_cut_off_at_deadline()
+	{
+		let mut client = connect_to_server(settings_with("request_timeout_ms = 300"));
+		let started = std::time::Instant::now();
+		for piece in &["GET /hel", "lo.html HT", "TP/1.1\r\nHost: loc", "alhost\r\n", "\r\n"]
+		{
+			//the server may already have given up on us, which is the point
+			let _ = client.write_all(piece.as_bytes());
+			std::thread::sleep(Duration::from_millis(100));
+		}
+
+		let mut received = Vec::new();
+		let _ = client.read_to_end(&mut received);
+		let waited = started.elapsed();
+		assert!(received.is_empty(), "got a response: {}", String::from_utf8_lossy(&received));
+		assert!(waited < Duration::from_secs(2), "connection stayed open for {:?}", waited);
+	}
+
+	#[test]
+	fn slow_headers_get_408()
+	{
+		let settings = settings_with("header_timeout_ms = 300");
+		let mut client = connect_to_server(settings.clone());
+		for piece in &["GET /hello.html HTTP/1.1\r\n", "Host: localhost\r\n", "X-Slow: 1\r\n", "X-Slow: 2\r\n", "\r\n"]
+		{
+			let _ = client.write_all(piece.as_bytes());
+			std::thread::sleep(Duration::from_millis(100));
+		}
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "unexpected response: {}", received);
+
+		//a client that never sends anything is cut off too
+		let mut silent = connect_to_server(settings.clone());
+		assert!(read_until_closed(&mut silent).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+		let mut prompt = connect_to_server(settings);
+		prompt.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert!(read_until_closed(&mut prompt).starts_with("HTTP/1.1 200 OK\r\n"));
+	}
+
+	#[test]
+	fn silent_connections_time_out()
+	{
+		//with no header timeout, the request timeout still bounds the wait for a first request
+		let mut silent = connect_to_server(settings_with("request_timeout_ms = 300"));
+		let waited = Instant::now();
+		assert_eq!(read_until_closed(&mut silent), "");
+		assert!(waited.elapsed() < Duration::from_secs(3));
+	}
+
+	#[test]
+	fn stream_threshold_can_depend_on_the_type()
+	{
+		let dir = scratch_dir("mime_stream_threshold");
+		fs::write(dir.join("clip.mp4"), vec![7u8; 2000]).unwrap();
+		fs::write(dir.join("photo.jpg"), vec![7u8; 2000]).unwrap();
+		fs::write(dir.join("notes.txt"), vec![b'x'; 2000]).unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nstream_threshold_bytes = 1000000\n[stream_threshold_mime_types]\n\"video/*\" = 0\n\"image/jpeg\" = 100\n\"image/*\" = 5000", dir.to_str().unwrap()));
+
+		let clip = respond(get("/clip.mp4"), &settings);
+		assert_eq!(clip.code, 200);
+		assert!(clip.stream.is_some(), "video under the global threshold should still stream");
+		//the exact type wins over its family
+		assert!(respond(get("/photo.jpg"), &settings).stream.is_some());
+		let notes = respond(get("/notes.txt"), &settings);
+		assert!(notes.stream.is_none());
+		assert_eq!(notes.body.len(), 2000);
+	}
+
+	#[test]
+	fn cache_hits_say_how_old_they_are()
+	{
+		let settings = settings_with("file_cache_max_bytes = 100000\nfile_cache_max_file_bytes = 100000");
+		let cache = FileCache::new();
+
+		let miss = respond_using_cache(get("/hello.html"), &settings, &cache);
+		assert_eq!(miss.code, 200);
+		assert!(!miss.headers.contains_key("Age"));
+
+		std::thread::sleep(Duration::from_millis(1100));
+		let hit = respond_using_cache(get("/hello.html"), &settings, &cache);
+		let age: u64 = hit.headers.get("Age").expect("no Age on a cache hit").parse().unwrap();
+		assert!(age >= 1);
+
+		assert!(!respond_using_cache(get("/hello.html"), &settings_with(""), &cache).headers.contains_key("Age"));
+	}
+
+	#[test]
+	fn ranges_served_from_cache()
+	{
+		use std::sync::atomic::{AtomicUsize, Ordering};
+
+		let dir = scratch_dir("ranges_from_cache");
+		fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nranges = true\nfile_cache_max_bytes = 1000\nfile_cache_max_file_bytes = 100", dir.to_str().unwrap()));
+		let reads = Arc::new(AtomicUsize::new(0));
+		let counter = Arc::clone(&reads);
+		let cache = FileCache::with_loader(Box::new(move |path: &Path| {
+			counter.fetch_add(1, Ordering::SeqCst);
+			fs::read(path)
+		}));
+
+		let full = respond_using_cache(get("/digits.txt"), &settings, &cache);
+		assert_eq!(full.code, 200);
+		assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+		let partial = respond_using_cache(get_with("/digits.txt", "Range: bytes=2-5\r\n"), &settings, &cache);
+		assert_eq!(partial.code, 206);
+		assert_eq!(partial.body, b"2345".to_vec());
+		assert_eq!(partial.headers.get("Content-Range").map(|v| v.as_str()), Some("bytes 2-5/10"));
+		assert!(!partial.headers.contains_key("Content-Encoding"));
+		assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+		let unsatisfiable = respond_using_cache(get_with("/digits.txt", "Range: bytes=20-\r\n"), &settings, &cache);
+		assert_eq!(unsatisfiable.code, 416);
+		assert_eq!(unsatisfiable.headers.get("Content-Range").map(|v| v.as_str()), Some("bytes */10"));
+		assert_eq!(reads.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn legal_blocks_return_451()
+	{
+		let settings = settings_with(r#"
+			[[legal_blocks]]
+			path = "/hello.jpg"
+			reason = "Removed following a court order."
+			authority = "https://authority.example/orders/123"
+
+			[[legal_blocks]]
+			path = "/restricted/"
+		"#);
+
+		let response = respond(get("/hello.jpg"), &settings);
+		assert_eq!(response.code, 451);
+		assert_eq!(response.body, b"Removed following a court order.".to_vec());
+		assert_eq!(response.headers.get("Link").map(|v| v.as_str()), Some("<https://authority.example/orders/123>; rel=\"blocked-by\""));
+		let out = String::from_utf8_lossy(&response.to_vec()).into_owned();
+		assert!(out.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons\r\n"));
+
+		let response = respond(get("/restricted/anything.html"), &settings);
+		assert_eq!(response.code, 451);
+		assert!(!response.headers.contains_key("Link"));
+
+		assert_eq!(respond(get("/hello.html"), &settings).code, 200);
+	}
+
+	#[test]
+	fn directory_mime_types_override_extensions()
+	{
+		let webroot = scratch_dir("directory_mime_types");
+		fs::create_dir_all(webroot.join("feeds/raw")).unwrap();
+		fs::write(webroot.join("feeds/latest"), "{}").unwrap();
+		fs::write(webroot.join("feeds/raw/latest"), "{}").unwrap();
+		fs::write(webroot.join("feeds/page.html"), "<p></p>").unwrap();
+		fs::write(webroot.join("page.html"), "<p></p>").unwrap();
+		let settings = settings_with(&format!(r#"
+			webroot = {:?}
+
+			[[directory_mime_types]]
+			path = "/feeds"
+			mime = "application/json"
+
+			[[directory_mime_types]]
+			path = "/feeds/raw/"
+			mime = "application/octet-stream"
+		"#, webroot.to_str().unwrap()));
+
+		assert_eq!(respond(get("/feeds/latest"), &settings).mime, "application/json");
+		assert_eq!(respond(get("/feeds/page.html"), &settings).mime, "application/json");
+		assert_eq!(respond(get("/feeds/raw/latest"), &settings).mime, "application/octet-stream");
+		assert_eq!(respond(get("/page.html"), &settings).mime, "text/html");
+	}
+
+	#[test]
+	fn charsets_come_from_the_directory_or_a_bom()
+	{
+		let webroot = scratch_dir("directory_charsets");
+		fs::create_dir_all(webroot.join("legacy")).unwrap();
+		fs::write(webroot.join("legacy/notes.txt"), b"caf\xE9").unwrap();
+		fs::write(webroot.join("legacy/modern.txt"), b"\xEF\xBB\xBFcaf\xC3\xA9").unwrap();
+		fs::write(webroot.join("legacy/logo.png"), b"\x89PNG").unwrap();
+		let config = format!(r#"
+			webroot = {:?}
+			detect_utf8_bom = true
+			strip_utf8_bom = true
+
+			[[directory_charsets]]
+			path = "/legacy"
+			charset = "iso-8859-1"
+		"#, webroot.to_str().unwrap());
+		let settings = settings_with(&config);
+
+		let response = respond(get("/legacy/notes.txt"), &settings);
+		assert_eq!(response.mime, "text/plain; charset=iso-8859-1");
+		assert_eq!(response.body, b"caf\xE9".to_vec());
+		let response = respond(get("/legacy/modern.txt"), &settings);
+		assert_eq!(response.mime, "text/plain; charset=utf-8");
+		assert_eq!(response.body, "café".as_bytes().to_vec());
+		assert_eq!(respond(get("/legacy/logo.png"), &settings).mime, "image/png");
+
+		let stripped_etag = response.headers.get("ETag").cloned().unwrap();
+
+		let settings = settings_with(&config.replace("strip_utf8_bom = true", ""));
+		let response = respond(get("/legacy/modern.txt"), &settings);
+		assert!(response.body.starts_with(UTF8_BOM));
+		//the two bodies differ, so a copy of one mustn't be confirmed as the other
+		assert_ne!(response.headers.get("ETag"), Some(&stripped_etag));
+		assert_eq!(respond(get_with("/legacy/modern.txt", &format!("If-None-Match: {}\r\n", stripped_etag)), &settings).code, 200);
+
+		//a streamed file keeps its BOM, so it keeps the ETag of the unstripped body too
+		let streaming = settings_with(&format!("stream_threshold_bytes = 1\n{}", config));
+		let streamed = respond(get("/legacy/modern.txt"), &streaming);
+		assert!(streamed.stream.is_some());
+		assert_eq!(streamed.headers.get("ETag"), response.headers.get("ETag"));
+	}
+
+	#[test]
+	fn health_checks_pass_during_maintenance()
+	{
+		let settings = settings_with("maintenance_mode = true\nhealth_check_path = \"/healthz\"");
+		let response = respond(get("/healthz"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"OK".to_vec());
+		assert_eq!(respond(get("/hello.html"), &settings).code, 503);
+
+		assert_eq!(respond(get("/hello.html"), &settings_with("health_check_path = \"/healthz\"")).code, 200);
+	}
+
+	#[test]
+	fn repeated_headers_get_a_line_each()
+	{
+		let mut response = Response::new(200, String::from("hi"));
+		response.headers.append(String::from("Set-Cookie"), String::from("session=abc; HttpOnly"));
+		response.headers.append(String::from("Set-Cookie"), String::from("theme=dark"));
+		response.headers.insert(String::from("Cache-Control"), String::from("no-store"));
+		let sent = String::from_utf8(response.to_vec()).unwrap();
+		assert!(sent.contains("\r\nCache-Control: no-store\r\nSet-Cookie: session=abc; HttpOnly\r\nSet-Cookie: theme=dark\r\n"), "unexpected response: {}", sent);
+	}
+
+	#[test]
+	fn configured_headers_go_on_every_response()
+	{
+		let settings = settings_with("[headers]\nX-Frame-Options = \"DENY\"\nx-unused = \"\"\naccept-ranges = \"none\"");
+		let response = respond(get("/hello.html"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.headers.get("X-Frame-Options"), Some(&String::from("DENY")));
+		assert!(!response.headers.contains_key("x-unused"));
+		//the file response's own header wins
+		assert_eq!(response.headers.get_all("Accept-Ranges"), vec!["bytes"]);
+
+		let response = respond(get("/missing.html"), &settings);
+		assert_eq!(response.code, 404);
+		assert_eq!(response.headers.get("X-Frame-Options"), Some(&String::from("DENY")));
+	}
+
+	#[test]
+	fn configured_status_codes_get_their_phrase()
+	{
+		configure_status_codes(&settings_with("[status_codes]\n418 = \"I'm a teapot\"\n522 = \"Origin Timed Out\"\n42 = \"Too short\"\n523 = \"Two\\nlines\""));
+		let out = String::from_utf8_lossy(&Response::new(418, String::from("Short and stout")).to_vec()).into_owned();
+		assert!(out.starts_with("HTTP/1.1 418 I'm a teapot\r\n"), "unexpected response: {}", out);
+		assert!(out.contains("<title>418 I'm a teapot</title>"));
+		assert_eq!(reason_phrase(522), Some(String::from("Origin Timed Out")));
+		assert_eq!(reason_phrase(42), None);
+		assert_eq!(reason_phrase(523), None);
+		assert_eq!(reason_phrase(404), Some(String::from("Not Found")));
+	}
+
+	#[test]
+	fn error_pages_are_compressed()
+	{
+		let settings = settings_with("compress_error_pages = true\nbrotli = false");
+		let response = respond(get_with("/missing.html", "Accept-Encoding: gzip\r\n"), &settings);
+		assert_eq!(response.code, 404);
+		assert_eq!(response.headers.get("Content-Encoding").map(|v| v.as_str()), Some("gzip"));
+		let mut page = String::new();
+		flate2::read::GzDecoder::new(&response.body[..]).read_to_string(&mut page).unwrap();
+		assert!(page.contains("<title>404 Not Found</title>"));
+		let out = response.to_vec();
+		assert!(String::from_utf8_lossy(&out).contains(&format!("\r\nContent-Length: {};\r\n", response.body.len())));
+		assert!(out.ends_with(&response.body));
+
+		let plain = respond(get("/missing.html"), &settings);
+		assert!(!plain.headers.contains_key("Content-Encoding"));
+		assert!(String::from_utf8_lossy(&plain.to_vec()).contains("<title>404 Not Found</title>"));
+		let off = respond(get_with("/missing.html", "Accept-Encoding: gzip\r\n"), &settings_with("compress_error_pages = false"));
+		assert!(!off.headers.contains_key("Content-Encoding"));
+	}
+
+	#[test]
+	fn named_error_placeholders_in_any_order()
+	{
+		let template = "{{message}}|{{code}}|{{status}}|{{code}}";
+		assert_eq!(render_error_page(template, 403, "403 Forbidden", "Go away"), "Go away|403|403 Forbidden|403");
+
+		let template = "<p>{{code}}</p>{}<p>{{message}}</p>";
+		assert_eq!(render_error_page(template, 404, "404 Not Found", "{{status}}"), "<p>404</p>{}<p>{{status}}</p>");
+
+		let out = String::from_utf8_lossy(&Response::new(404, String::from("No such page")).to_vec()).into_owned();
+		assert!(out.contains("<title>404 Not Found</title>"));
+		assert!(out.contains("<p>No such page</p>"));
+		assert!(!out.contains("{{"));
+	}
+
+	#[test]
+	fn failed_preconditions_return_412()
+	{
+		let settings = settings_with("");
+		let response = respond(get("/hello.html"), &settings);
+		assert_eq!(response.code, 200);
+		let etag = response.headers["ETag"].clone();
+		assert!(response.headers.contains_key("Last-Modified"));
+
+		let response = respond(get_with("/hello.html", "If-Match: \"not-the-etag\"\r\n"), &settings);
+		assert_eq!(response.code, 412);
+		assert_eq!(respond(get_with("/hello.html", &format!("If-Match: {}\r\n", etag)), &settings).code, 200);
+		assert_eq!(respond(get_with("/hello.html", "If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n"), &settings).code, 412);
+
+		let response = respond(get_with("/hello.html", &format!("If-None-Match: {}\r\n", etag)), &settings);
+		assert_eq!(response.code, 304);
+		assert!(response.body.is_empty());
+		assert_eq!(response.headers["ETag"], etag);
+	}
+
+	#[test]
+	fn future_modification_times_are_served_in_full()
+	{
+		let dir = scratch_dir("future_mtime");
+		fs::write(dir.join("past.html"), "<p>old</p>").unwrap();
+		fs::write(dir.join("future.html"), "<p>new</p>").unwrap();
+		let future = std::time::SystemTime::now() + Duration::from_secs(3600);
+		fs::File::options().write(true).open(dir.join("future.html")).unwrap().set_modified(future).unwrap();
+		let settings = settings_with(&format!("webroot = {:?}", dir.to_str().unwrap()));
+
+		let tomorrow = format!("If-Modified-Since: {}\r\n", httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(86400)));
+		assert_eq!(respond(get_with("/past.html", &tomorrow), &settings).code, 304);
+		let response = respond(get_with("/future.html", &tomorrow), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"<p>new</p>".to_vec());
+	}
+
+	#[test]
+	fn content_language_from_filename()
+	{
+		let dir = scratch_dir("content_language");
+		fs::write(dir.join("about.fr.html"), "<p>Bonjour</p>").unwrap();
+		fs::write(dir.join("about.html"), "<p>Hello</p>").unwrap();
+		let webroot = format!("webroot = {:?}\n", dir.to_str().unwrap());
+
+		let settings = settings_with(&format!("{}content_language_from_filename = true", webroot));
+		let response = respond(get("/about.fr.html"), &settings);
+		assert_eq!(response.headers.get("Content-Language").map(|v| v.as_str()), Some("fr"));
+		assert!(!respond(get("/about.html"), &settings).headers.contains_key("Content-Language"));
+
+		let settings = settings_with(&webroot);
+		assert!(!respond(get("/about.fr.html"), &settings).headers.contains_key("Content-Language"));
+	}
+
+	#[test]
+	fn accept_language_picks_variant()
+	{
+		let dir = scratch_dir("accept_language");
+		fs::write(dir.join("about.fr.html"), "<p>Bonjour</p>").unwrap();
+		fs::write(dir.join("about.html"), "<p>Hello</p>").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\nlanguage_negotiation = true", dir.to_str().unwrap()));
+
+		let response = respond(get_with("/about.html", "Accept-Language: de;q=0.9, fr-CA;q=0.95\r\n"), &settings);
+		assert_eq!(response.body, b"<p>Bonjour</p>".to_vec());
+		assert!(response.headers["Vary"].contains("Accept-Language"));
+
+		let response = respond(get_with("/about.html", "Accept-Language: ja\r\n"), &settings);
+		assert_eq!(response.body, b"<p>Hello</p>".to_vec());
+		assert!(response.headers["Vary"].contains("Accept-Language"));
+
+		let response = respond(get_with("/about.html", "Accept-Language: fr;q=0, en\r\n"), &settings);
+		assert_eq!(response.body, b"<p>Hello</p>".to_vec());
+	}
+
+	#[test]
+	fn control_characters_in_paths_are_rejected()
+	{
+		let settings = settings_with("");
+		assert_eq!(respond(get("/foo%00bar"), &settings).code, 400);
+		assert_eq!(respond(get("/hello.html%00.jpg"), &settings).code, 400);
+		let literal = Request::parse(Box::new(b"GET /foo\x01bar HTTP/1.1\r\nHost: localhost\r\n\r\n".to_owned())).ok().unwrap();
+		assert_eq!(respond(literal, &settings).code, 400);
+		assert_eq!(respond(get("/foo%00bar"), &settings_with("block_control_characters = false")).code, 404);
+	}
+
+	#[test]
+	fn windows_style_paths_are_rejected()
+	{
+		let settings = settings_with("");
+		assert_eq!(respond(get("/C:/secret"), &settings).code, 403);
+		assert_eq!(respond(get("/c%3a/Windows/win.ini"), &settings).code, 403);
+		assert_eq!(respond(get("/\\..\\.."), &settings).code, 403);
+		assert_eq!(respond(get("/%5C%5Cserver/share/file.txt"), &settings).code, 403);
+		assert_eq!(respond(get("/images\\hello.jpg"), &settings).code, 403);
+		assert_eq!(respond(get("/hello.html"), &settings).code, 200);
+
+		let allowed = settings_with("block_windows_paths = false");
+		assert_eq!(respond(get("/C:/secret"), &allowed).code, 404);
+		//a backslash still can't be used to climb out
+		assert_eq!(respond(get("/a\\..\\..\\etc"), &allowed).code, 403);
+	}
+
+	#[test]
+	fn double_encoded_traversal_is_not_collapsed()
+	{
+		capture_logs();
+		let settings = settings_with("");
+		assert!(!ResourcePath{resource: String::from("/%252e%252e%252fhello.html")}.is_traversal());
+		assert_eq!(respond(get("/%252e%252e%252fdouble-encoding-test"), &settings).code, 400);
+		assert_eq!(logs_containing("double-encoding-test"), vec![(String::from(LOG_SECURITY), Level::Warn)]);
+		assert_eq!(respond(get("/images/%252E%252E/%252E%252E/hello.html"), &settings).code, 400);
+
+		//let through, the escapes are just part of a file name that doesn't exist
+		let allowed = settings_with("block_double_encoding = false");
+		assert_eq!(respond(get("/%252e%252e%252fhello.html"), &allowed).code, 404);
+	}
+
+	#[test]
+	fn max_path_segments_limit()
+	{
+		let settings = settings_with("max_path_segments = 3");
+		assert_eq!(respond(get("/a/b/hello.html"), &settings).code, 404);
+		assert_eq!(respond(get("/a/b/c/hello.html"), &settings).code, 400);
+		//normalization removes the empty and dot segments before counting
+		assert_eq!(respond(get("//a/./b//hello.html"), &settings).code, 404);
+		assert_eq!(respond(get("/a/b/c/hello.html"), &settings_with("max_path_segments = 0")).code, 404);
+	}
+
+	#[test]
+	fn immediately_closed_connection_gets_no_response()
+	{
+		capture_logs();
+		let mut client = connect_to_server(settings_with(""));
+		client.shutdown(Shutdown::Write).unwrap();
+		assert_eq!(read_until_closed(&mut client), "");
+		assert!(logs_containing("without sending a request").contains(&(String::from(LOG_SERVER), Level::Trace)));
+	}
+
+	#[test]
+	fn metrics_sink_sees_each_request()
+	{
+		struct RecordingSink(std::sync::Mutex<Vec<(String, u16, usize, Duration)>>);
+		impl MetricsSink for RecordingSink
+		{
+			fn record_request(&self, method: &str, status: u16, bytes: usize, duration: Duration)
+			{
+				self.0.lock().unwrap().push((String::from(method), status, bytes, duration));
+			}
+		}
+
+		let sink = Arc::new(RecordingSink(std::sync::Mutex::new(Vec::new())));
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server_sink = Arc::clone(&sink);
+		let server = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			serve_connection_with_metrics(stream, &settings_with(""), &*server_sink);
+		});
+		let mut client = TcpStream::connect(addr).unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		server.join().unwrap();
+
+		let recorded = sink.0.lock().unwrap();
+		assert_eq!(recorded.len(), 1);
+		let (method, status, bytes, duration) = &recorded[0];
+		assert_eq!((method.as_str(), *status, *bytes), ("GET", 200, received.len()));
+		assert!(*duration < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn rejected_post_body_is_drained()
+	{
+		let mut client = connect_to_server(settings_with(""));
+		let body = "x".repeat(20000);
+		client.write_all(format!("POST /hello.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "unexpected response: {}", received);
+		assert!(received.contains("\r\nAllow: GET, HEAD\r\n"));
+		//the body was drained, so the request after it was understood
+		assert_eq!(received.matches("HTTP/1.1 ").count(), 2);
+		assert!(received.contains("HTTP/1.1 200 OK\r\n"));
+
+		let mut client = connect_to_server(settings_with("request_body_drain_max_bytes = 10"));
+		client.write_all(b"POST /hello.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello world").unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+		assert!(received.contains("\r\nConnection: close\r\n"));
+	}
+
+	#[test]
+	fn chunked_bodies_need_a_length()
+	{
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"POST /hello.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").unwrap();
+		client.write_all(b"GET /hello.jpg HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 411 Length Required\r\n"), "unexpected response: {}", received);
+		assert!(received.contains("\r\nConnection: close\r\n"));
+		//the undrained body ends the connection, so nothing after it is taken for a request
+		assert_eq!(received.matches("HTTP/1.1 ").count(), 1);
+	}
+
+	#[test]
+	fn get_body_is_drained_or_rejected()
+	{
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert_eq!(received.matches("HTTP/1.1 200 OK\r\n").count(), 2, "unexpected response: {}", received);
+
+		let mut client = connect_to_server(settings_with("reject_get_bodies = true"));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 400 Bad Request\r\n"), "unexpected response: {}", received);
+		assert!(received.contains("HTTP/1.1 200 OK\r\n"));
+	}
+
+	#[test]
+	fn trailing_bytes_after_a_closing_request_are_rejected()
+	{
+		let smuggled = b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\nGET /hello.jpg HTTP/1.1\r\nHost: localhost\r\n\r\n";
+		let mut client = connect_to_server(settings_with("reject_trailing_bytes = true"));
+		client.write_all(smuggled).unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 400 Bad Request\r\n"), "unexpected response: {}", received);
+		assert!(!received.contains("200 OK"));
+
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(smuggled).unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+		//on a connection that stays open, the extra bytes are just the next request
+		let mut client = connect_to_server(settings_with("reject_trailing_bytes = true"));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\nGET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert_eq!(read_until_closed(&mut client).matches("HTTP/1.1 200 OK\r\n").count(), 2);
+	}
+
+	#[test]
+	fn uploads_create_then_overwrite()
+	{
+		let webroot = scratch_dir("uploads");
+		let settings = settings_with(&format!("webroot = {:?}\nupload_path = \"/incoming\"\nupload_token = \"s3cret\"\nblock_dotfiles = true\nblocked_extensions = [\"env\", \"php\"]", webroot.to_str().unwrap()));
+		let put = |path: &str, token: &str, body: &str| {
+			let mut client = connect_to_server(settings.clone());
+			client.write_all(format!("PUT {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", path, token, body.len(), body).as_bytes()).unwrap();
+			read_until_closed(&mut client)
+		};
 
-	// Request::parse
+		let created = put("/incoming/reports/today.txt", "s3cret", "first draft");
+		assert!(created.starts_with("HTTP/1.1 201 Created\r\n"), "unexpected response: {}", created);
+		assert_eq!(fs::read_to_string(webroot.join("incoming/reports/today.txt")).unwrap(), "first draft");
+
+		let replaced = put("/incoming/reports/today.txt", "s3cret", "final");
+		assert!(replaced.starts_with("HTTP/1.1 204 No Content\r\n"), "unexpected response: {}", replaced);
+		assert_eq!(fs::read_to_string(webroot.join("incoming/reports/today.txt")).unwrap(), "final");
+
+		assert!(put("/incoming/other.txt", "wrong", "x").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+		assert!(!webroot.join("incoming/other.txt").exists());
+		assert!(put("/elsewhere.txt", "s3cret", "x").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+		assert!(put("/incoming/%2e%2e/escape.txt", "s3cret", "x").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(put("/incoming/other.txt", "s3cre", "x").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+		//nothing GET would refuse to serve can be written
+		assert!(put("/incoming/.htaccess", "s3cret", "x").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(put("/incoming/config/.env", "s3cret", "x").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(put("/incoming/shell.php", "s3cret", "x").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(put("/incoming/%252e%252e%252fescape.txt", "s3cret", "x").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(!webroot.join("incoming/.htaccess").exists());
+		assert!(!webroot.join("incoming/config/.env").exists());
+		assert!(!webroot.join("incoming/shell.php").exists());
+	}
+
+	#[cfg(unix)]
 	#[test]
-	fn parse_request()
+	fn uploads_never_follow_symlinks()
 	{
-		let req_string = Box::new(b"GET /hello.htm HTTP/1.1\r\nUser-Agent: Mozilla/4.0 (compatible; MSIE5.01; Windows NT)\r\nHost: 127.0.0.1:8000\r\n\r\n".to_owned());
-		let request = Request::parse(req_string);
-		match request
+		let webroot = scratch_dir("upload_symlinks");
+		let outside = scratch_dir("upload_symlinks_outside").join("target.txt");
+		fs::write(&outside, "untouched").unwrap();
+		fs::create_dir_all(webroot.join("incoming")).unwrap();
+		std::os::unix::fs::symlink(&outside, webroot.join("incoming/link.txt")).unwrap();
+		for atomic in &[false, true]
 		{
-			Ok(req) =>{
-				assert_eq!(req.method, "GET");
-				assert_eq!(req.resource, "/hello.htm");
-				assert_eq!(req.http_version, "HTTP/1.1");
+			let settings = settings_with(&format!("webroot = {:?}\nupload_path = \"/incoming\"\nupload_token = \"s3cret\"\natomic_uploads = {}", webroot.to_str().unwrap(), atomic));
+			let mut client = connect_to_server(settings);
+			client.write_all(b"PUT /incoming/link.txt HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\nContent-Length: 5\r\nConnection: close\r\n\r\npwned").unwrap();
+			let received = read_until_closed(&mut client);
+			assert!(received.starts_with("HTTP/1.1 403 Forbidden\r\n"), "unexpected response: {}", received);
+			assert_eq!(fs::read_to_string(&outside).unwrap(), "untouched");
+			assert!(fs::symlink_metadata(webroot.join("incoming/link.txt")).unwrap().file_type().is_symlink());
+		}
+	}
+
+	/// Counts the allocations each thread makes, so tests can check how much parsing allocates
+	struct CountingAllocator;
+
+	thread_local!
+	{
+		static ALLOCATIONS: std::cell::Cell<usize> = const {std::cell::Cell::new(0)};
+	}
+
+	unsafe impl std::alloc::GlobalAlloc for CountingAllocator
+	{
+		unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8
+		{
+			//the thread's counter may already be gone while it's shutting down
+			let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+			unsafe {std::alloc::System.alloc(layout)}
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout)
+		{
+			unsafe {std::alloc::System.dealloc(ptr, layout)}
+		}
+	}
+
+	#[global_allocator]
+	static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+	fn allocations_during<F: FnOnce()>(f: F) -> usize
+	{
+		let before = ALLOCATIONS.with(|count| count.get());
+		f();
+		ALLOCATIONS.with(|count| count.get()) - before
+	}
+
+	#[test]
+	fn parsing_allocates_only_the_owned_strings()
+	{
+		let raw: &[u8] = b"GET /articles/2020/index.html?page=2 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: Mozilla/5.0 (X11; Linux x86_64)\r\nAccept: text/html,application/xhtml+xml\r\nAccept-Language: en-US,en;q=0.5\r\nAccept-Encoding: gzip, deflate, br\r\nReferer: https://example.com/articles/\r\nCookie: session=abc123\r\nConnection: keep-alive\r\n\r\n";
+		assert_eq!(allocations_during(|| {RequestLine::parse(raw).ok().unwrap();}), 0);
+
+		let mut request = None;
+		let allocations = allocations_during(|| {request = Request::parse_bytes(raw).ok();});
+		let request = request.unwrap();
+		assert_eq!(request.headers.len(), 8);
+		//method, resource and version, one table for the headers, and a name and value for each of them
+		assert!(allocations <= 3 + 1 + 2 * 8, "parsing took {} allocations", allocations);
+	}
+
+	#[test]
+	fn readers_never_see_half_an_upload()
+	{
+		let webroot = scratch_dir("atomic-uploads");
+		let settings = settings_with(&format!("webroot = {:?}\nupload_path = \"/incoming\"\nupload_token = \"s3cret\"", webroot.to_str().unwrap()));
+		let target = webroot.join("incoming/big.bin");
+		fs::create_dir_all(target.parent().unwrap()).unwrap();
+		fs::write(&target, vec![b'a'; 1 << 20]).unwrap();
+
+		let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+		let reader = {
+			let (target, done) = (target.clone(), done.clone());
+			std::thread::spawn(move || {
+				while !done.load(Ordering::Relaxed)
+				{
+					let seen = fs::read(&target).unwrap();
+					assert_eq!(seen.len(), 1 << 20);
+					assert!(seen.iter().all(|b| *b == seen[0]), "read a mix of two uploads");
+				}
+			})
+		};
+		for round in 0..20
+		{
+			let mut request = Request::parse(Box::new(*b"PUT /incoming/big.bin HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\r\n")).ok().unwrap();
+			request.body = vec![if round % 2 == 0 {b'b'} else {b'a'}; 1 << 20];
+			assert_eq!(respond(request, &settings).code, 204);
+		}
+		done.store(true, Ordering::Relaxed);
+		reader.join().unwrap();
+		assert_eq!(fs::read_dir(target.parent().unwrap()).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn expect_continue_is_answered_before_the_body()
+	{
+		let webroot = scratch_dir("expect-continue");
+		let settings = settings_with(&format!("webroot = {:?}\nupload_path = \"/incoming\"\nupload_token = \"s3cret\"\nexpect_continue_max_bytes = 1000", webroot.to_str().unwrap()));
+
+		let mut client = connect_to_server(settings.clone());
+		client.write_all(b"PUT /incoming/huge.bin HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\nExpect: 100-continue\r\nContent-Length: 1000000\r\n\r\n").unwrap();
+		let refused = read_until_closed(&mut client);
+		assert!(refused.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "unexpected response: {}", refused);
+		assert!(!refused.contains("100 Continue"));
+
+		let mut client = connect_to_server(settings);
+		client.write_all(b"PUT /incoming/small.txt HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+		let mut interim = [0u8; 25];
+		client.read_exact(&mut interim).unwrap();
+		assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+		client.write_all(b"hello").unwrap();
+		let created = read_until_closed(&mut client);
+		assert!(created.starts_with("HTTP/1.1 201 Created\r\n"), "unexpected response: {}", created);
+		assert_eq!(fs::read_to_string(webroot.join("incoming/small.txt")).unwrap(), "hello");
+
+		//an expectation the server can't meet is refused before the client is told to send anything
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"POST /hello.html HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue, something-else\r\nContent-Length: 5\r\n\r\n").unwrap();
+		let refused = read_until_closed(&mut client);
+		assert!(refused.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "unexpected response: {}", refused);
+		assert!(!refused.contains("100 Continue"));
+	}
+
+	#[test]
+	fn deletes_remove_files()
+	{
+		let webroot = scratch_dir("deletes");
+		fs::create_dir_all(webroot.join("incoming/old")).unwrap();
+		fs::write(webroot.join("incoming/stale.txt"), "bye").unwrap();
+		let config = format!("webroot = {:?}\nupload_path = \"/incoming\"\nupload_token = \"s3cret\"", webroot.to_str().unwrap());
+		let delete = |path: &str, settings: &Config| {
+			let request = Request::parse(format!("DELETE {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\r\n", path).into_bytes().into_boxed_slice()).ok().unwrap();
+			respond(request, settings).code
+		};
+		let settings = settings_with(&config);
+
+		assert_eq!(delete("/incoming/stale.txt", &settings), 204);
+		assert!(!webroot.join("incoming/stale.txt").exists());
+		assert_eq!(delete("/incoming/stale.txt", &settings), 404);
+		assert_eq!(delete("/incoming/old", &settings), 403);
+		assert!(webroot.join("incoming/old").is_dir());
+		assert_eq!(delete("/incoming/old", &settings_with(&format!("{}\ndelete_directories = true", config))), 204);
+		assert!(!webroot.join("incoming/old").exists());
+		assert_eq!(delete("/hello.html", &settings), 405);
+
+		let settings = settings_with(&format!("{}\ndelete_directories = true", config));
+		for root in &["/incoming", "/incoming/", "/incoming//", "/incoming/."]
+		{
+			assert_eq!(delete(root, &settings), 403, "deleted {}", root);
+		}
+		assert!(webroot.join("incoming").is_dir());
+	}
+
+	#[test]
+	fn oversized_requests_are_told_the_limit()
+	{
+		let mut client = connect_to_server(settings_with("request_max_bytes = 200"));
+		client.write_all(format!("GET /hello.html HTTP/1.1\r\nHost: localhost\r\nX-Padding: {}", "x".repeat(300)).as_bytes()).unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "unexpected response: {}", received);
+		assert!(received.contains("Request exceeded the maximum allowed size of 200 bytes."), "unexpected response: {}", received);
+		//shown in the error page like any other error
+		assert!(received.contains("<title>413 Payload Too Large</title>"), "unexpected response: {}", received);
+	}
+
+	#[test]
+	fn body_limit_is_separate_from_request_max_bytes()
+	{
+		let request = |body_len: usize| format!("GET /hello.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body_len, "x".repeat(body_len));
+
+		let mut client = connect_to_server(settings_with("max_body_bytes = 100"));
+		client.write_all(request(500).as_bytes()).unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "unexpected response: {}", received);
+
+		let mut client = connect_to_server(settings_with("max_body_bytes = 100"));
+		client.write_all(request(50).as_bytes()).unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+		//a body bigger than request_max_bytes is fine as long as the head fits
+		let mut client = connect_to_server(settings_with("max_body_bytes = 4096\nrequest_max_bytes = 200"));
+		client.write_all(request(3000).as_bytes()).unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+		//a chunked body has no length to check against the limit, so it doesn't get past it either
+		let mut client = connect_to_server(settings_with("max_body_bytes = 100"));
+		let chunked = format!("GET /hello.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n1f4\r\n{}\r\n0\r\n\r\n", "x".repeat(500));
+		client.write_all(chunked.as_bytes()).unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 411 Length Required\r\n"), "unexpected response: {}", received);
+	}
+
+	#[test]
+	fn allowed_methods_reach_static_serving()
+	{
+		let settings = settings_with("allowed_methods = [\"GET\", \"HEAD\", \"FETCH\"]");
+		let request = |method: &str| Request::parse(format!("{} /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n", method).into_bytes().into_boxed_slice()).ok().unwrap();
+
+		assert_eq!(respond(request("FETCH"), &settings).code, 200);
+		let response = respond(request("DELETE"), &settings);
+		assert_eq!(response.code, 405);
+		assert_eq!(response.headers.get("Allow").map(|v| v.as_str()), Some("GET, HEAD, FETCH"));
+		assert_eq!(respond(request("BREW"), &settings).code, 501);
+		assert_eq!(respond(request("FETCH"), &settings_with("")).code, 501);
+	}
+
+	#[test]
+	fn head_sends_headers_only()
+	{
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"HEAD /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		let length = fs::metadata("data/webroot/hello.html").unwrap().len();
+		assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(received.contains(&format!("\r\nContent-Length: {};\r\n", length)));
+		assert!(received.ends_with("\r\n\r\n"));
+	}
+
+	#[test]
+	fn malformed_versions_are_bad_requests()
+	{
+		let parse = |version: &str| Request::parse(format!("GET /hello.html {}\r\nHost: localhost\r\n\r\n", version).into_bytes().into_boxed_slice());
+		for version in &["HTTP/banana", "HTTP/", "HTTP/1.x", "HTTP/1.10", "http/1.1", "HTTP/1,1"]
+		{
+			assert_eq!(parse(version).err().map(|e| e.code), Some(400), "{} was accepted", version);
+		}
+		let settings = settings_with("");
+		assert_eq!(respond(parse("HTTP/2.0").ok().unwrap(), &settings).code, 505);
+		assert_eq!(respond(parse("HTTP/1.0").ok().unwrap(), &settings).code, 505);
+	}
+
+	#[test]
+	fn trailing_whitespace_after_version_is_ignored()
+	{
+		let parse = |line: &str| Request::parse(format!("{}\r\nHost: localhost\r\n\r\n", line).into_bytes().into_boxed_slice());
+		for line in &["GET /hello.html HTTP/1.1 ", "GET /hello.html HTTP/1.1\t", "GET /hello.html HTTP/1.1 \t "]
+		{
+			match parse(line)
+			{
+				Ok(request) => {
+					assert_eq!(request.http_version, "HTTP/1.1");
+					assert_eq!(request.resource, "/hello.html");
+					assert_eq!(request.header("host"), Some("localhost"));
+				},
+				Err(e) => panic!("{:?} was rejected with {}", line, e.code)
 			}
-			Err(_) => assert!(false)
+		}
+		for line in &["GET /hello.html HTTP/1.1 x", "GET /hello.html HTTP/1.1\tHTTP/1.1", "GET /hello.html \tHTTP/1.1"]
+		{
+			assert_eq!(parse(line).err().map(|e| e.code), Some(400), "{:?} was accepted", line);
 		}
 	}
 
-	// Response.to_vec
 	#[test]
-	fn response_to_vec()
+	fn trace_log_times_each_phase()
 	{
-		let body = String::from("<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>Hello</title></head><body><h1>Hello</h1><p>Greetings from Rust</p></body></html>").as_bytes().to_vec();
-		let res = Response{code: 200, mime: String::from("text/html"), body};
-		let out_vec = res.to_vec();
+		capture_logs();
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+		for phase in &["Request read in ", "Request parsed in ", "Response built in ", "Request analyzed in ", "Response sent in "]
+		{
+			assert!(logs_containing(phase).contains(&(String::from(LOG_SERVER), Level::Trace)), "no timing logged for {}", phase);
+		}
+	}
 
-		let out_expected = b"HTTP/1.1 200 OK\r\nContent-Type: text/html;\r\nContent-Length: 146;\r\n\r\n<!DOCTYPE html><html lang='en'><head><meta charset='utf-8'><title>Hello</title></head><body><h1>Hello</h1><p>Greetings from Rust</p></body></html>".to_vec();
-		assert_eq!(out_vec, out_expected);
+	#[test]
+	fn redirects_are_relative_unless_configured()
+	{
+		let table = "[redirects]\n\"/old.html\" = \"/new.html\"\n\"/moved\" = \"https://elsewhere.example/\"";
+		let relative = settings_with(table);
+		let response = respond(get("/old.html"), &relative);
+		assert_eq!(response.code, 301);
+		assert_eq!(response.headers["Location"], "/new.html");
+		assert_eq!(respond(get("/moved"), &relative).headers["Location"], "https://elsewhere.example/");
+		assert_eq!(respond(get("/hello.html"), &relative).code, 200);
+
+		let absolute = settings_with(&format!("absolute_redirects = true\npublic_scheme = \"https\"\npublic_host = \"www.example.com\"\n{}", table));
+		assert_eq!(respond(get("/old.html"), &absolute).headers["Location"], "https://www.example.com/new.html");
+		assert_eq!(respond(get("/moved"), &absolute).headers["Location"], "https://elsewhere.example/");
+		//without a public host, the one the client asked for is used
+		let from_host = settings_with(&format!("absolute_redirects = true\n{}", table));
+		assert_eq!(respond(get("/old.html"), &from_host).headers["Location"], "http://localhost/new.html");
+	}
+
+	#[test]
+	fn redirects_use_the_public_url()
+	{
+		let redirect = |public: &str| {
+			let settings = settings_with(&format!("absolute_redirects = true\n{}\n[redirects]\n\"/old.html\" = \"/new.html\"", public));
+			let request = Request::parse(b"GET /old.html HTTP/1.1\r\nHost: 10.0.0.5:8000\r\n\r\n".to_vec().into_boxed_slice()).ok().unwrap();
+			let response = respond(request, &settings);
+			assert_eq!(response.code, 301);
+			response.headers["Location"].clone()
+		};
+
+		assert_eq!(redirect("public_scheme = \"https\"\npublic_host = \"www.example.com\"\npublic_port = 8443"), "https://www.example.com:8443/new.html");
+		//the scheme's own port is left out
+		assert_eq!(redirect("public_scheme = \"https\"\npublic_host = \"www.example.com\"\npublic_port = 443"), "https://www.example.com/new.html");
+		assert_eq!(redirect("public_scheme = \"HTTP\"\npublic_host = \"www.example.com\"\npublic_port = 80"), "http://www.example.com/new.html");
+		//whatever isn't configured comes from the request
+		assert_eq!(redirect("public_scheme = \"https\""), "https://10.0.0.5:8000/new.html");
+		assert_eq!(redirect(""), "http://10.0.0.5:8000/new.html");
+	}
+
+	#[test]
+	fn combined_access_log_has_referer_and_user_agent()
+	{
+		capture_logs();
+		let mut client = connect_to_server(settings_with("access_log_format = \"combined\""));
+		client.write_all(b"GET /hello.html?combined=1 HTTP/1.1\r\nHost: localhost\r\nReferer: https://example.com/start\r\nUser-Agent: TestBrowser/1.0\r\n\r\n").unwrap();
+		client.write_all(b"GET /hello.html?combined=2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+
+		let lines: Vec<String> = CAPTURED_LOGS.lock().unwrap_or_else(|e| e.into_inner()).iter()
+			.filter(|(target, _, message)| target == LOG_ACCESS && message.contains("/hello.html?combined="))
+			.map(|(_, _, message)| message.clone())
+			.collect();
+		assert_eq!(lines.len(), 2);
+		assert!(lines[0].contains("\"GET /hello.html?combined=1 HTTP/1.1\" 200 "), "unexpected entry: {}", lines[0]);
+		assert!(lines[0].ends_with(" \"https://example.com/start\" \"TestBrowser/1.0\""), "unexpected entry: {}", lines[0]);
+		assert!(lines[1].ends_with(" \"-\" \"-\""), "unexpected entry: {}", lines[1]);
+		//the size logged is what was sent
+		let sizes: usize = lines.iter().map(|line| line.split("\" 200 ").nth(1).unwrap().split(' ').next().unwrap().parse::<usize>().unwrap()).sum();
+		assert_eq!(sizes, received.len());
+	}
+
+	#[test]
+	fn access_log_level_follows_status()
+	{
+		capture_logs();
+		accesslog::log_access(500, String::from("From: level-test Response code: 500"));
+		accesslog::log_access(200, String::from("From: level-test Response code: 200"));
+		assert_eq!(logs_containing("From: level-test Response code: 500"), vec![(String::from(LOG_ACCESS), Level::Error)]);
+		assert_eq!(logs_containing("From: level-test Response code: 200"), vec![(String::from(LOG_ACCESS), Level::Info)]);
+	}
+
+	#[test]
+	fn slow_requests_are_logged()
+	{
+		capture_logs();
+		let settings = "slow_request_threshold_ms = 150";
+
+		let mut client = connect_to_server(settings_with(settings));
+		client.write_all(b"GET /hello.html?slow-request-test HTTP/1.1\r\n").unwrap();
+		std::thread::sleep(Duration::from_millis(300));
+		client.write_all(b"Host: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+		let mut client = connect_to_server(settings_with(settings));
+		client.write_all(b"GET /hello.html?fast-request-test HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+		assert_eq!(logs_containing("GET /hello.html?slow-request-test answered 200"), vec![(String::from(LOG_SLOW), Level::Warn)]);
+		assert!(logs_containing("fast-request-test").is_empty());
+	}
+
+	#[test]
+	fn case_insensitive_paths_find_the_real_file()
+	{
+		let dir = scratch_dir("case_insensitive");
+		fs::create_dir_all(dir.join("docs")).unwrap();
+		fs::write(dir.join("docs").join("about.html"), "<p>About</p>").unwrap();
+		fs::write(dir.join("README"), "upper").unwrap();
+		fs::write(dir.join("Readme"), "mixed").unwrap();
+		let webroot = format!("webroot = {:?}", dir.to_str().unwrap());
+
+		let settings = settings_with(&format!("{}\ncase_insensitive_paths = true", webroot));
+		let response = respond(get("/Docs/About.HTML"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.mime, "text/html");
+		assert_eq!(response.body, b"<p>About</p>".to_vec());
+		assert_eq!(respond(get("/README"), &settings).body, b"upper".to_vec());
+		assert_eq!(respond(get("/readme"), &settings).code, 404);
+
+		assert_eq!(respond(get("/Docs/About.HTML"), &settings_with(&webroot)).code, 404);
+	}
+
+	#[test]
+	fn html_snippet_is_injected_into_pages_only()
+	{
+		let original = fs::read("data/webroot/hello.html").unwrap();
+		let snippet = "<script src=\"/stats.js\"></script>";
+		let settings = settings_with(&format!("html_snippet = {:?}", snippet));
+
+		let response = respond(get("/hello.html"), &settings);
+		let page = String::from_utf8(response.body.clone()).unwrap();
+		assert!(page.contains("<script src=\"/stats.js\"></script></head>"), "snippet missing from {}", page);
+		assert_eq!(response.body.len(), original.len() + snippet.len());
+		let sent = String::from_utf8_lossy(&response.to_vec()).into_owned();
+		assert!(sent.contains(&format!("\r\nContent-Length: {}", original.len() + snippet.len())), "wrong length in {}", sent);
+
+		//a copy cached before the snippet changed mustn't be confirmed as current
+		let plain_etag = respond(get("/hello.html"), &settings_with("")).headers.get("ETag").cloned().unwrap();
+		let etag = response.headers.get("ETag").cloned().unwrap();
+		assert_ne!(etag, plain_etag);
+		assert_eq!(respond(get_with("/hello.html", &format!("If-None-Match: {}\r\n", plain_etag)), &settings).code, 200);
+		assert_eq!(respond(get_with("/hello.html", &format!("If-None-Match: {}\r\n", etag)), &settings).code, 304);
+		assert_eq!(respond(get("/hello.jpg"), &settings).headers.get("ETag"), respond(get("/hello.jpg"), &settings_with("")).headers.get("ETag"));
+		//a streamed page goes out without the snippet, so its validators don't change with it either
+		let streaming = settings_with(&format!("html_snippet = {:?}\nstream_threshold_bytes = 10", snippet));
+		let response = respond(get("/hello.html"), &streaming);
+		assert!(response.stream.is_some());
+		assert_eq!(response.headers.get("ETag"), Some(&plain_etag));
+
+		let settings = settings_with(&format!("html_snippet = {:?}\nhtml_snippet_before = \"</BODY>\"", snippet));
+		assert!(String::from_utf8(respond(get("/hello.html"), &settings).body).unwrap().contains("<script src=\"/stats.js\"></script></body>"));
+		assert_eq!(respond(get("/hello.jpg"), &settings).body, fs::read("data/webroot/hello.jpg").unwrap());
+	}
+
+	#[test]
+	fn well_known_files_from_config()
+	{
+		let dir = scratch_dir("well_known");
+		fs::write(dir.join("security.txt"), "Contact: mailto:security@example.com\n").unwrap();
+		let settings = settings_with(&format!("robots_txt = \"User-agent: *\\nDisallow: /private/\\n\"\nsecurity_txt_path = {:?}\nblock_dotfiles = true",
+			dir.join("security.txt").to_str().unwrap()));
+
+		let response = respond(get("/robots.txt"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.mime, "text/plain");
+		assert_eq!(response.body, b"User-agent: *\nDisallow: /private/\n".to_vec());
+
+		let response = respond(get("/.well-known/security.txt"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"Contact: mailto:security@example.com\n".to_vec());
+
+		assert_eq!(respond(get("/robots.txt"), &settings_with("")).code, 404);
+
+		fs::write(dir.join(".hidden.txt"), "secret").unwrap();
+		let webroot = format!("webroot = {:?}", dir.to_str().unwrap());
+		assert_eq!(respond(get("/.hidden.txt"), &settings_with(&format!("{}\nblock_dotfiles = true", webroot))).code, 404);
+		assert_eq!(respond(get("/.hidden.txt"), &settings_with(&webroot)).code, 200);
+	}
+
+	#[test]
+	fn acme_challenges_get_past_https_and_dotfile_blocking()
+	{
+		let dir = scratch_dir("acme");
+		fs::write(dir.join("Xk3_9-abc"), "Xk3_9-abc.thumbprint").unwrap();
+		let settings = settings_with(&format!("acme_challenge_dir = {:?}\nforce_https = true\nblock_dotfiles = true\nmaintenance_mode = true", dir.to_str().unwrap()));
+
+		let response = respond(get("/.well-known/acme-challenge/Xk3_9-abc"), &settings);
+		assert_eq!(response.code, 200);
+		assert_eq!(response.body, b"Xk3_9-abc.thumbprint".to_vec());
+		assert_eq!(respond(get("/.well-known/acme-challenge/missing"), &settings).code, 404);
+		assert_eq!(respond(get("/.well-known/acme-challenge/..%2fsecret"), &settings).code, 404);
+		assert_eq!(respond(get("/index.html"), &settings).code, 503);
+
+		let settings = settings_with("force_https = true\nblock_dotfiles = true");
+		assert_eq!(respond(get("/.well-known/acme-challenge/Xk3_9-abc"), &settings).code, 301);
+	}
+
+	#[test]
+	fn patch_reaches_its_handler_with_the_body()
+	{
+		handlers::register("PATCH", "/handler-test/profile", Arc::new(|request: &Request| {
+			Response::new(200, format!("patched with {}", String::from_utf8_lossy(&request.body)))
+		}));
+		let send = |method: &str, resource: &str| {
+			let mut client = connect_to_server(settings_with("handler_body_max_bytes = 100"));
+			client.write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 14\r\nConnection: close\r\n\r\n{{\"name\":\"Ada\"}}", method, resource).as_bytes()).unwrap();
+			read_until_closed(&mut client)
+		};
+
+		assert!(send("PATCH", "/handler-test/profile").ends_with("\r\n\r\npatched with {\"name\":\"Ada\"}"));
+		assert!(send("POST", "/handler-test/profile").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+		assert!(send("PATCH", "/hello.html").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+		let mut client = connect_to_server(settings_with("handler_body_max_bytes = 10"));
+		client.write_all(b"PATCH /handler-test/profile HTTP/1.1\r\nHost: localhost\r\nContent-Length: 14\r\n\r\n{\"name\":\"Ada\"}").unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+	}
+
+	#[test]
+	fn head_of_a_directory_describes_its_index()
+	{
+		let dir = scratch_dir("head_directory");
+		fs::create_dir_all(dir.join("docs")).unwrap();
+		fs::write(dir.join("docs").join("index.html"), "<h1>Docs</h1>").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}", dir.to_str().unwrap()));
+		let fetch = |method: &str, resource: &str| {
+			let mut client = connect_to_server(settings.clone());
+			client.write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", method, resource).as_bytes()).unwrap();
+			read_until_closed(&mut client)
+		};
+
+		for resource in &["/docs/", "/docs"]
+		{
+			let head = fetch("HEAD", resource);
+			assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected response: {}", head);
+			assert!(head.contains("\r\nContent-Type: text/html"));
+			assert!(head.contains("\r\nContent-Length: 13"));
+			assert!(head.ends_with("\r\n\r\n"));
+			assert_eq!(head, fetch("GET", resource).trim_end_matches("<h1>Docs</h1>"));
+		}
+	}
+
+	#[test]
+	fn large_bodies_are_spooled_to_disk()
+	{
+		handlers::register("POST", "/handler-test/spool", Arc::new(|request: &Request| {
+			let mut body = Vec::new();
+			request.body_reader().unwrap().read_to_end(&mut body).unwrap();
+			let mut response = Response::new(200, String::from_utf8(body).unwrap());
+			response.headers.insert(String::from("X-Spooled"), request.spooled_body.is_some().to_string());
+			response
+		}));
+		let spool_dir = scratch_dir("spool");
+		let settings = settings_with(&format!("request_body_spool_bytes = 100\nrequest_body_spool_dir = {:?}", spool_dir.to_str().unwrap()));
+		let send = |body: &str| {
+			let mut client = connect_to_server(settings.clone());
+			client.write_all(format!("POST /handler-test/spool HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+			read_until_closed(&mut client)
+		};
+
+		let large = "0123456789".repeat(1000);
+		let received = send(&large);
+		assert!(received.contains("\r\nX-Spooled: true\r\n"));
+		assert!(received.ends_with(&format!("\r\n\r\n{}", large)));
+		assert_eq!(fs::read_dir(&spool_dir).unwrap().count(), 0, "spooled body wasn't removed");
+
+		let received = send("small");
+		assert!(received.contains("\r\nX-Spooled: false\r\n"));
+		assert!(received.ends_with("\r\n\r\nsmall"));
+	}
+
+	#[test]
+	fn get_handler_also_answers_head_and_options()
+	{
+		handlers::register("GET", "/handler-test/greeting", Arc::new(|_: &Request| {
+			let mut response = Response::new(200, String::from("<p>Hi</p>"));
+			response.headers.insert(String::from("X-Greeting"), String::from("yes"));
+			response
+		}));
+		let fetch = |method: &str| {
+			let mut client = connect_to_server(settings_with(""));
+			client.write_all(format!("{} /handler-test/greeting HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", method).as_bytes()).unwrap();
+			read_until_closed(&mut client)
+		};
+
+		let get = fetch("GET");
+		let head = fetch("HEAD");
+		assert!(get.ends_with("\r\n\r\n<p>Hi</p>"));
+		assert!(get.contains("\r\nX-Greeting: yes\r\n"));
+		assert_eq!(head, get.trim_end_matches("<p>Hi</p>"));
+
+		let options = fetch("OPTIONS");
+		assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"));
+		assert!(options.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+		assert!(fetch("POST").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+		//explicit registrations win over the derived ones
+		handlers::register("OPTIONS", "/handler-test/greeting", Arc::new(|_: &Request| Response::new(200, String::from("custom"))));
+		assert!(fetch("OPTIONS").ends_with("\r\n\r\ncustom"));
+	}
+
+	#[test]
+	fn only_preflights_get_cors_headers()
+	{
+		handlers::register("GET", "/handler-test/cors", Arc::new(|_: &Request| Response::new(200, String::from("data"))));
+		let settings = settings_with("cors_allowed_origins = [\"https://app.example.com\"]\ncors_max_age = 600");
+		let options = |headers: &str| {
+			let raw = format!("OPTIONS /handler-test/cors HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+			respond(Request::parse(raw.into_bytes().into_boxed_slice()).ok().unwrap(), &settings)
+		};
+
+		let plain = options("");
+		assert_eq!(plain.code, 204);
+		assert_eq!(plain.headers["Allow"], "GET, HEAD, OPTIONS");
+		assert!(plain.headers.iter().all(|(name, _)| !name.starts_with("Access-Control-")));
+		//an Origin alone is a cross-origin OPTIONS request, but not a preflight
+		assert!(!options("Origin: https://app.example.com\r\n").headers.contains_key("Access-Control-Allow-Origin"));
+
+		let preflight = options("Origin: https://app.example.com\r\nAccess-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: X-Token\r\n");
+		assert_eq!(preflight.code, 204);
+		assert_eq!(preflight.headers["Access-Control-Allow-Origin"], "https://app.example.com");
+		assert_eq!(preflight.headers["Access-Control-Allow-Methods"], "GET, HEAD, OPTIONS");
+		assert_eq!(preflight.headers["Access-Control-Allow-Headers"], "X-Token");
+		assert_eq!(preflight.headers["Access-Control-Max-Age"], "600");
+		assert_eq!(preflight.headers["Vary"], "Origin");
+
+		let stranger = options("Origin: https://evil.example\r\nAccess-Control-Request-Method: GET\r\n");
+		assert!(!stranger.headers.contains_key("Access-Control-Allow-Origin"));
+	}
+
+	#[test]
+	fn streaming_handler_sends_chunks()
+	{
+		handlers::register_streaming("GET", "/handler-test/stream", "text/plain", Arc::new(|_: &Request| {
+			Box::new(vec![b"first ".to_vec(), Vec::new(), b"second ".to_vec(), b"third".to_vec()].into_iter()) as Box<dyn Iterator<Item = Vec<u8>> + Send>
+		}));
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"GET /handler-test/stream HTTP/1.1\r\nHost: localhost\r\n\r\nGET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		let (head, rest) = received.split_at(received.find("\r\n\r\n").unwrap() + 4);
+		assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"));
+		assert!(!head.contains("Content-Length"));
+
+		//put the chunks back together, which also finds where the body ends
+		let mut body = String::new();
+		let mut rest = rest;
+		loop
+		{
+			let line_end = rest.find("\r\n").unwrap();
+			let size = usize::from_str_radix(&rest[..line_end], 16).unwrap();
+			rest = &rest[(line_end + 2)..];
+			if size == 0
+			{
+				rest = &rest[2..];
+				break;
+			}
+			body.push_str(&rest[..size]);
+			rest = &rest[(size + 2)..];
+		}
+		assert_eq!(body, "first second third");
+		//the connection was still usable afterward
+		assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"));
+	}
+
+	#[test]
+	fn bind_retries_until_the_port_is_free()
+	{
+		let occupant = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = occupant.local_addr().unwrap().to_string();
+		assert!(bind_listener(&addr, Default::default(), 0, Duration::from_millis(10)).is_err());
+
+		let leaving = std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_millis(150));
+			drop(occupant);
+		});
+		let listener = bind_listener(&addr, Default::default(), 6, Duration::from_millis(50)).unwrap();
+		leaving.join().unwrap();
+
+		//and it's a working listener
+		let mut client = TcpStream::connect(&addr).unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		let (stream, _) = listener.accept().unwrap();
+		std::thread::spawn(move || serve_connection(stream, &settings_with("")));
+		client.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+	}
+
+	#[test]
+	fn overloaded_pool_sheds_with_503()
+	{
+		let pool = workers::build_pool(1);
+		let (release, blocked) = std::sync::mpsc::channel::<()>();
+		let (busy, started) = std::sync::mpsc::channel::<()>();
+		pool.execute(move || {let _ = busy.send(()); let _ = blocked.recv();});
+		//until the worker has taken that job, it's the one waiting in the queue
+		started.recv().unwrap();
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let mut clients = Vec::new();
+		for _ in 0..3
+		{
+			let client = TcpStream::connect(addr).unwrap();
+			client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+			let (stream, _) = listener.accept().unwrap();
+			dispatch_connection(Some(&pool), stream, 1);
+			clients.push(client);
+		}
+		assert_eq!(pool.queued_count(), 1);
+		for client in clients.iter_mut().skip(1)
+		{
+			assert!(read_until_closed(client).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+		}
+
+		//without a pool at all, every connection is turned away
+		let mut client = TcpStream::connect(addr).unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		let (stream, _) = listener.accept().unwrap();
+		dispatch_connection(None, stream, 0);
+		assert!(read_until_closed(&mut client).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+		release.send(()).unwrap();
+	}
+
+	#[test]
+	fn extension_roots_pick_the_directory()
+	{
+		let main_root = scratch_dir("extension_roots_main");
+		let asset_root = scratch_dir("extension_roots_assets");
+		fs::write(main_root.join("page.html"), "<p>main</p>").unwrap();
+		fs::write(main_root.join("logo.png"), "main png").unwrap();
+		fs::write(asset_root.join("logo.png"), "asset png").unwrap();
+		fs::write(asset_root.join("page.html"), "<p>asset</p>").unwrap();
+		let settings = settings_with(&format!("webroot = {:?}\n[extension_roots]\nPNG = {:?}", main_root.to_str().unwrap(), asset_root.to_str().unwrap()));
+
+		assert_eq!(respond(get("/logo.png"), &settings).body, b"asset png".to_vec());
+		assert_eq!(respond(get("/page.html"), &settings).body, b"<p>main</p>".to_vec());
+		assert_eq!(respond(get("/../extension_roots_main/logo.png"), &settings).code, 403);
+	}
+
+	#[test]
+	fn large_files_are_streamed_within_the_open_file_limit()
+	{
+		let original = fs::read("data/webroot/hello.jpg").unwrap();
+		let settings = settings_with("stream_threshold_bytes = 1\nmax_open_files = 1\nopen_file_wait_ms = 0");
+
+		let response = respond(get("/hello.jpg"), &settings);
+		assert_eq!(response.code, 200);
+		assert!(response.body.is_empty());
+		let mut sent = Vec::new();
+		response.send(&mut sent);
+		assert!(sent.ends_with(&original));
+		assert!(String::from_utf8_lossy(&sent).contains(&format!("\r\nContent-Length: {};\r\n", original.len())));
+		//the file stays open, holding its slot, until the response is gone
+		assert_eq!(respond(get("/hello.jpg"), &settings).code, 503);
+		drop(response);
+
+		let response = respond(get_with("/hello.jpg", "Range: bytes=10-19\r\n"), &settings);
+		assert_eq!(response.code, 206);
+		let mut sent = Vec::new();
+		response.send(&mut sent);
+		assert!(sent.ends_with(&original[10..20]));
+	}
+
+	#[test]
+	fn file_responses_advertise_ranges()
+	{
+		let response = respond(get("/hello.html"), &settings_with("ranges = true"));
+		assert_eq!(response.code, 200);
+		assert_eq!(response.headers.get("Accept-Ranges").map(|v| v.as_str()), Some("bytes"));
+		assert!(!respond(get("/missing.html"), &settings_with("ranges = true")).headers.contains_key("Accept-Ranges"));
+	}
+
+	#[test]
+	fn missing_files_are_proxied_upstream()
+	{
+		let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let settings = settings_with(&format!("proxy_upstream = \"{}\"", upstream.local_addr().unwrap()));
+		let backend = std::thread::spawn(move || {
+			let (mut conn, _) = upstream.accept().unwrap();
+			let mut received = Vec::new();
+			let mut chunk = [0u8; 1024];
+			while find_head_end(&received).is_none()
+			{
+				let n = conn.read(&mut chunk).unwrap();
+				received.extend_from_slice(&chunk[..n]);
+			}
+			conn.write_all(b"HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nContent-Length: 15\r\nX-Backend: yes\r\nConnection: close\r\n\r\n{\"from\":\"app\"}").unwrap();
+			String::from_utf8(received).unwrap()
+		});
+
+		let response = respond(get("/api/items?page=2"), &settings);
+		let mut sent = Vec::new();
+		response.send(&mut sent);
+		let sent = String::from_utf8(sent).unwrap();
+		assert!(sent.starts_with("HTTP/1.1 201 Created\r\n"), "unexpected response: {}", sent);
+		assert!(sent.contains("\r\nContent-Type: application/json;\r\n"));
+		assert!(sent.contains("\r\nX-Backend: yes\r\n"));
+		assert!(!sent.contains("\r\nConnection: close\r\n"));
+		assert!(sent.ends_with("\r\n\r\n{\"from\":\"app\"}"));
+		let forwarded = backend.join().unwrap();
+		assert!(forwarded.starts_with("GET /api/items?page=2 HTTP/1.1\r\n"));
+		assert!(forwarded.contains("\r\nhost: localhost\r\n"));
+
+		//files that exist are still served from the webroot
+		assert_eq!(respond(get("/hello.html"), &settings).code, 200);
+	}
+
+	#[test]
+	fn unreachable_upstream_is_a_bad_gateway()
+	{
+		let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = unused.local_addr().unwrap();
+		drop(unused);
+		let settings = settings_with(&format!("proxy_upstream = \"{}\"", addr));
+		assert_eq!(respond(get("/nowhere"), &settings).code, 502);
+	}
+
+	#[test]
+	fn head_with_range_reports_partial_content()
+	{
+		let len = fs::metadata("data/webroot/hello.jpg").unwrap().len();
+		let mut client = connect_to_server(settings_with(""));
+		client.write_all(b"HEAD /hello.jpg HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-9\r\nConnection: close\r\n\r\n").unwrap();
+		let received = read_until_closed(&mut client);
+		assert!(received.starts_with("HTTP/1.1 206 Partial Content\r\n"), "unexpected response: {}", received);
+		assert!(received.contains(&format!("\r\nContent-Range: bytes 0-9/{}\r\n", len)));
+		assert!(received.contains("\r\nContent-Length: 10;\r\n"));
+		assert!(received.contains("\r\nAccept-Ranges: bytes\r\n"));
+		assert!(received.ends_with("\r\n\r\n"));
+
+		let response = respond(get("/hello.jpg"), &settings_with("ranges = false"));
+		assert_eq!(response.headers.get("Accept-Ranges").map(|v| v.as_str()), Some("none"));
 	}
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/**
+A counting semaphore with a separate count for each key, shared by all the
+workers. The server uses one keyed by peer IP so a single client can't tie
+up the whole thread pool, one for the files held open while they're
+streamed so a burst of large downloads can't use up the file descriptors,
+and one for connections waiting between requests so idle keep-alive clients
+can't occupy every connection. The last two have a single count, keyed by `()`.
+*/
+pub struct CountingLimiter<K: Eq + Hash>
+{
+	counts: Mutex<HashMap<K, usize>>,
+	released: Condvar
+}
+
+impl<K: Eq + Hash + Clone> CountingLimiter<K>
+{
+	pub fn new() -> CountingLimiter<K>
+	{
+		CountingLimiter{counts: Mutex::new(HashMap::new()), released: Condvar::new()}
+	}
+
+	/**
+	Claim a slot for the given key, waiting for one to be released if they're
+	all in use.
+
+	# Parameters
+	- `key`: What the slot is counted against
+	- `max`: Maximum number of slots claimed at once for the key. Zero means no limit.
+	- `wait`: How long to wait for a slot before giving up. Zero doesn't wait at all.
+
+	# Returns
+	- `Some`: a guard holding the slot, which is released when the guard is dropped
+	- `None`: no slot became free in time
+
+	# Examples
+	```
+	use std::net::IpAddr;
+	use std::time::Duration;
+	use c20web::limits::CountingLimiter;
+
+	let limiter = CountingLimiter::new();
+	let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+	let first = limiter.acquire(ip, 1, Duration::ZERO);
+	assert!(first.is_some());
+	assert!(limiter.acquire(ip, 1, Duration::from_millis(10)).is_none());
+	assert_eq!(limiter.count(&ip), 1);
+
+	drop(first);
+	assert!(limiter.acquire(ip, 1, Duration::ZERO).is_some());
+	```
+	*/
+	pub fn acquire(&self, key: K, max: usize, wait: Duration) -> Option<LimiterSlot<'_, K>>
+	{
+		let give_up = Instant::now() + wait;
+		//the counts are plain integers, so they're still consistent even if another thread panicked holding the lock
+		let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+		while max != 0 && counts.get(&key).is_some_and(|count| *count >= max)
+		{
+			let now = Instant::now();
+			if now >= give_up
+			{
+				return None;
+			}
+			counts = self.released.wait_timeout(counts, give_up - now).unwrap_or_else(|e| e.into_inner()).0;
+		}
+		*counts.entry(key.clone()).or_insert(0) += 1;
+		Some(LimiterSlot{limiter: self, key})
+	}
+
+	/**
+	# Returns
+	The number of slots currently claimed for the given key.
+	*/
+	pub fn count(&self, key: &K) -> usize
+	{
+		let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+		*counts.get(key).unwrap_or(&0)
+	}
+}
+
+impl<K: Eq + Hash + Clone> Default for CountingLimiter<K>
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+/**
+A slot claimed from a `CountingLimiter`. Hold onto it for as long as the
+connection or file it stands for; dropping it gives the slot back.
+*/
+pub struct LimiterSlot<'a, K: Eq + Hash>
+{
+	limiter: &'a CountingLimiter<K>,
+	key: K
+}
+
+impl<K: Eq + Hash> Drop for LimiterSlot<'_, K>
+{
+	fn drop(&mut self)
+	{
+		let mut counts = self.limiter.counts.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(count) = counts.get_mut(&self.key)
+		{
+			*count -= 1;
+			if *count == 0
+			{
+				counts.remove(&self.key);
+			}
+		}
+		//waiters may be after different keys, so wake them all to check their own
+		self.limiter.released.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::net::IpAddr;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::thread;
+
+	#[test]
+	fn per_ip_limit_only_affects_that_ip()
+	{
+		let limiter = CountingLimiter::new();
+		let busy: IpAddr = "192.168.1.10".parse().unwrap();
+		let other: IpAddr = "192.168.1.11".parse().unwrap();
+
+		let first = limiter.acquire(busy, 2, Duration::ZERO);
+		let second = limiter.acquire(busy, 2, Duration::ZERO);
+		assert!(first.is_some());
+		assert!(second.is_some());
+		assert!(limiter.acquire(busy, 2, Duration::ZERO).is_none());
+		assert_eq!(limiter.count(&busy), 2);
+
+		let unaffected = limiter.acquire(other, 2, Duration::ZERO);
+		assert!(unaffected.is_some());
+		assert_eq!(limiter.count(&other), 1);
+
+		drop(second);
+		assert_eq!(limiter.count(&busy), 1);
+		assert!(limiter.acquire(busy, 2, Duration::ZERO).is_some());
+	}
+
+	#[test]
+	fn zero_means_unlimited()
+	{
+		let limiter = CountingLimiter::new();
+		let ip: IpAddr = "::1".parse().unwrap();
+		let guards: Vec<_> = (0..50).map(|_| limiter.acquire(ip, 0, Duration::ZERO)).collect();
+		assert!(guards.iter().all(|g| g.is_some()));
+		assert_eq!(limiter.count(&ip), 50);
+		drop(guards);
+		assert_eq!(limiter.count(&ip), 0);
+	}
+
+	#[test]
+	fn open_files_beyond_the_cap_wait_their_turn()
+	{
+		let limiter = Arc::new(CountingLimiter::new());
+		let busiest = Arc::new(AtomicUsize::new(0));
+		let workers: Vec<_> = (0..8).map(|_| {
+			let limiter = Arc::clone(&limiter);
+			let busiest = Arc::clone(&busiest);
+			thread::spawn(move || {
+				let slot = limiter.acquire((), 2, Duration::from_secs(10));
+				assert!(slot.is_some());
+				busiest.fetch_max(limiter.count(&()), Ordering::SeqCst);
+				thread::sleep(Duration::from_millis(20));
+			})
+		}).collect();
+		for worker in workers
+		{
+			worker.join().unwrap();
+		}
+		assert_eq!(busiest.load(Ordering::SeqCst), 2);
+		assert_eq!(limiter.count(&()), 0);
+	}
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::error;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::LOG_SERVER;
+
+/// Length of the queue of connections the OS accepts before the server gets to them
+const BACKLOG: i32 = 128;
+
+/**
+Socket options applied to the listening socket before it's bound.
+
+Platforms differ in what these do. On Linux, Android and the BSDs, including
+macOS, `reuse_address` lets the server bind while old connections to the port
+are still in TIME_WAIT, and `reuse_port` lets several listeners share one
+port. Linux spreads incoming connections across them, so processes can share
+the load; on the BSDs the most recently bound listener gets them all. Other
+Unix-like systems don't have `reuse_port`, and asking for it makes binding
+fail. On Windows `reuse_address` is ignored, since SO_REUSEADDR there would
+let another program take over the port, and `reuse_port` makes binding fail.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListenOptions
+{
+	/// Set SO_REUSEADDR, which the standard library does too on Unix-like systems
+	pub reuse_address: bool,
+	/// Set SO_REUSEPORT
+	pub reuse_port: bool
+}
+
+impl Default for ListenOptions
+{
+	fn default() -> ListenOptions
+	{
+		ListenOptions{reuse_address: true, reuse_port: false}
+	}
+}
+
+/**
+Open a listening socket with the given options. Like `TcpListener::bind`,
+each address the name resolves to is tried until one works.
+
+# Parameters
+- `listen_addr`: The address to listen on
+- `options`: Socket options to apply before binding
+
+# Returns
+The listener, or the error from the last address tried
+
+# Examples
+```
+use c20web::listener::{bind, ListenOptions};
+
+let listener = bind("127.0.0.1:0", ListenOptions::default()).unwrap();
+assert!(listener.local_addr().unwrap().port() != 0);
+```
+*/
+pub fn bind(listen_addr: &str, options: ListenOptions) -> io::Result<TcpListener>
+{
+	let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "address doesn't resolve");
+	for addr in listen_addr.to_socket_addrs()?
+	{
+		match bind_addr(addr, options)
+		{
+			Ok(listener) => {return Ok(listener);},
+			Err(e) => {last_error = e;}
+		}
+	}
+	Err(last_error)
+}
+
+fn bind_addr(addr: SocketAddr, options: ListenOptions) -> io::Result<TcpListener>
+{
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+	//SO_REUSEADDR on Windows would let another program take over the port
+	if options.reuse_address && cfg!(unix)
+	{
+		socket.set_reuse_address(true)?;
+	}
+	if options.reuse_port
+	{
+		set_reuse_port(&socket)?;
+	}
+	socket.bind(&addr.into())?;
+	socket.listen(BACKLOG)?;
+	Ok(socket.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()>
+{
+	socket.set_reuse_port(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly")))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()>
+{
+	Err(io::Error::new(io::ErrorKind::Other, "reuse_port isn't supported on this platform"))
+}
+
+/**
+Hand each new connection to `handle` until `stop` is set. Accepting blocks,
+so whoever sets `stop` should call `wake` afterward for the loop to notice.
+
+# Parameters
+- `listener`: The listening socket
+- `stop`: Set to make the loop return instead of handling the next connection
+- `handle`: What to do with each connection
+*/
+pub fn accept_until_stopped<F: FnMut(TcpStream)>(listener: &TcpListener, stop: &AtomicBool, mut handle: F)
+{
+	for stream in listener.incoming()
+	{
+		//the connection that woke us up, or a real one arriving just as we stop; either way it isn't served
+		if stop.load(Ordering::SeqCst)
+		{
+			return;
+		}
+		match stream
+		{
+			Ok(stream) => handle(stream),
+			Err(e) => {error!(target: LOG_SERVER, "Listener gave us an invalid TCPStream!: {}",e);}
+		}
+	}
+}
+
+/**
+Connect to a listener and hang up, so a thread blocked accepting on it gets
+a connection and can check whether it should stop. A listener on the
+unspecified address is reached through loopback.
+
+# Parameters
+- `addr`: The address the listener is bound to
+*/
+pub fn wake(addr: SocketAddr)
+{
+	let mut target = addr;
+	if addr.ip().is_unspecified()
+	{
+		target.set_ip(if addr.is_ipv4() {IpAddr::V4(Ipv4Addr::LOCALHOST)} else {IpAddr::V6(Ipv6Addr::LOCALHOST)});
+	}
+	if let Err(e) = TcpStream::connect_timeout(&target, Duration::from_secs(1))
+	{
+		error!(target: LOG_SERVER, "Couldn't wake the listener on {} to stop it: {}", addr, e);
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn reuse_port_lets_listeners_share_a_port()
+	{
+		let shared = ListenOptions{reuse_address: true, reuse_port: true};
+		let first = bind("127.0.0.1:0", shared).unwrap();
+		let addr = first.local_addr().unwrap().to_string();
+		let second = bind(&addr, shared).unwrap();
+		assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+
+		assert!(bind(&addr, ListenOptions::default()).is_err());
+	}
+
+	#[test]
+	fn accepting_stops_when_woken()
+	{
+		use std::sync::Arc;
+		use std::sync::mpsc::channel;
+
+		let listener = bind("127.0.0.1:0", ListenOptions::default()).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let stop = Arc::new(AtomicBool::new(false));
+		let stopping = Arc::clone(&stop);
+		let (handled, accepted) = channel();
+		let accepting = std::thread::spawn(move || {
+			accept_until_stopped(&listener, &stopping, |stream| handled.send(stream).unwrap());
+		});
+
+		let _client = TcpStream::connect(addr).unwrap();
+		accepted.recv_timeout(Duration::from_secs(5)).unwrap();
+		stop.store(true, Ordering::SeqCst);
+		wake(("0.0.0.0", addr.port()).to_socket_addrs().unwrap().next().unwrap());
+		accepting.join().unwrap();
+		assert!(accepted.try_recv().is_err());
+	}
+
+	#[test]
+	fn ipv6_listeners_accept_connections()
+	{
+		let listener = match bind("[::1]:0", ListenOptions::default())
+		{
+			Ok(listener) => listener,
+			Err(_) => {return;} //no IPv6 loopback here
+		};
+		let addr = listener.local_addr().unwrap();
+		assert!(std::net::TcpStream::connect(addr).is_ok());
+		assert!(listener.accept().is_ok());
+	}
+}
@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::statics::{METRICS_SINK, REQUEST_COUNTERS};
+
+/**
+Somewhere to report each request the server answers, like a statsd client or
+the built-in counters. Implementations are called from every worker thread,
+so they should be quick and must not block for long.
+*/
+pub trait MetricsSink: Send + Sync
+{
+	/**
+	Record a request that was answered.
+
+	# Parameters
+	- `method`: The request method, or "-" if the request couldn't be parsed
+	- `status`: The response code sent
+	- `bytes`: Number of bytes written to the client, headers included
+	- `duration`: Time from receiving the request to finishing the response
+	*/
+	fn record_request(&self, method: &str, status: u16, bytes: usize, duration: Duration);
+}
+
+/**
+Totals of everything recorded by an `InMemoryMetrics`.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot
+{
+	pub requests: u64,
+	pub bytes_sent: u64,
+	/// Number of responses in each status class, from 1xx at index 0 to 5xx at index 4
+	pub responses_by_class: [u64; 5],
+	pub total_duration: Duration
+}
+
+/**
+The default metrics sink, which keeps running totals in memory.
+*/
+#[derive(Default)]
+pub struct InMemoryMetrics
+{
+	requests: AtomicU64,
+	bytes_sent: AtomicU64,
+	responses_by_class: [AtomicU64; 5],
+	total_micros: AtomicU64
+}
+
+impl InMemoryMetrics
+{
+	pub fn new() -> InMemoryMetrics
+	{
+		InMemoryMetrics::default()
+	}
+
+	/**
+	# Returns
+	The totals recorded so far.
+
+	# Examples
+	```
+	use std::time::Duration;
+	use c20web::metrics::{InMemoryMetrics, MetricsSink};
+
+	let metrics = InMemoryMetrics::new();
+	metrics.record_request("GET", 200, 1500, Duration::from_millis(3));
+	metrics.record_request("GET", 404, 500, Duration::from_millis(1));
+	let snapshot = metrics.snapshot();
+	assert_eq!(snapshot.requests, 2);
+	assert_eq!(snapshot.bytes_sent, 2000);
+	assert_eq!(snapshot.responses_by_class, [0, 1, 0, 1, 0]);
+	assert_eq!(snapshot.total_duration, Duration::from_millis(4));
+	```
+	*/
+	pub fn snapshot(&self) -> MetricsSnapshot
+	{
+		let mut responses_by_class = [0; 5];
+		for (total, counter) in responses_by_class.iter_mut().zip(self.responses_by_class.iter())
+		{
+			*total = counter.load(Ordering::Relaxed);
+		}
+		MetricsSnapshot{
+			requests: self.requests.load(Ordering::Relaxed),
+			bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+			responses_by_class,
+			total_duration: Duration::from_micros(self.total_micros.load(Ordering::Relaxed))
+		}
+	}
+}
+
+impl MetricsSink for InMemoryMetrics
+{
+	fn record_request(&self, _method: &str, status: u16, bytes: usize, duration: Duration)
+	{
+		self.requests.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+		if (100..600).contains(&status)
+		{
+			self.responses_by_class[(status / 100 - 1) as usize].fetch_add(1, Ordering::Relaxed);
+		}
+		self.total_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+	}
+}
+
+/**
+Send every request's metrics to the given sink from now on, in place of the
+built-in counters.
+
+# Parameters
+- `sink`: The sink to report to
+*/
+pub fn install_sink(sink: Arc<dyn MetricsSink>)
+{
+	*METRICS_SINK.write().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+/**
+# Returns
+The sink requests are currently reported to
+*/
+pub fn sink() -> Arc<dyn MetricsSink>
+{
+	Arc::clone(&METRICS_SINK.read().unwrap_or_else(|e| e.into_inner()))
+}
+
+/**
+# Returns
+The totals kept by the built-in counters, which only count requests while they're the installed sink
+*/
+pub fn builtin_snapshot() -> MetricsSnapshot
+{
+	REQUEST_COUNTERS.snapshot()
+}
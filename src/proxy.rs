@@ -0,0 +1,333 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use config::Config;
+use log::warn;
+
+use crate::{LOG_SERVER, Request, Response, find_head_end};
+use crate::headers::Headers;
+use crate::streaming::BodyStream;
+
+/// Headers that only describe a single connection, so they're never passed through the proxy
+const HOP_BY_HOP: [&str; 8] = ["connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade"];
+/// The most an upstream response head may be before it's rejected
+const MAX_HEAD_BYTES: usize = 65536;
+
+/**
+The body of an upstream response, copied from the upstream connection while
+the response is sent to the client.
+*/
+pub struct UpstreamStream
+{
+	upstream: TcpStream,
+	/// Body bytes that arrived along with the upstream response head
+	received: Vec<u8>,
+	len: u64
+}
+
+impl BodyStream for UpstreamStream
+{
+	fn len(&self) -> u64
+	{
+		self.len
+	}
+
+	fn write_to(&self, out: &mut dyn Write) -> io::Result<u64>
+	{
+		let already = self.received.len().min(self.len as usize);
+		out.write_all(&self.received[..already])?;
+		let mut rest = (&self.upstream).take(self.len - already as u64);
+		let copied = io::copy(&mut rest, out)?;
+		if copied < self.len - already as u64
+		{
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection before sending the whole body"));
+		}
+		Ok(self.len)
+	}
+}
+
+/**
+Pass a request on to the upstream server in `proxy_upstream` and relay its
+answer. Upstream responses with a Content-Length are streamed to the client
+as they arrive; chunked or unframed ones are read in full first, up to
+`proxy_max_buffered_bytes`. Request bodies aren't forwarded, since only the
+methods in `allowed_methods` get this far and their bodies are discarded.
+
+# Parameters
+- `request`: The request to forward
+- `upstream`: Address of the upstream server, like "127.0.0.1:8080"
+- `settings`: The server configuration to apply
+
+# Returns
+The upstream server's response, or 502 or 504 if it couldn't be had
+*/
+pub fn forward(request: &Request, upstream: &str, settings: &Config) -> Response
+{
+	let timeout = match settings.get::<u64>("proxy_timeout_ms").unwrap_or(0)
+	{
+		0 => None,
+		ms => Some(Duration::from_millis(ms))
+	};
+	let max_buffered_bytes = settings.get::<usize>("proxy_max_buffered_bytes").unwrap_or(0);
+
+	let mut connection = match connect(upstream, timeout)
+	{
+		Ok(connection) => connection,
+		Err(e) => {
+			warn!(target: LOG_SERVER, "Couldn't reach upstream {} for {}: {}", upstream, request.resource, e);
+			return Response::new(502, String::from("The upstream server couldn't be reached."));
+		}
+	};
+	if let Err(e) = connection.write_all(&request_head(request, upstream))
+	{
+		warn!(target: LOG_SERVER, "Couldn't send {} to upstream {}: {}", request.resource, upstream, e);
+		return Response::new(502, String::from("The upstream server couldn't be reached."));
+	}
+
+	let (head, received) = match read_head(&mut connection, MAX_HEAD_BYTES)
+	{
+		Ok(read) => read,
+		Err(e) => {
+			warn!(target: LOG_SERVER, "Bad response from upstream {} for {}: {}", upstream, request.resource, e);
+			let code = if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {504} else {502};
+			return Response::new(code, String::from("The upstream server didn't answer properly."));
+		}
+	};
+	let (code, headers) = match parse_head(&head)
+	{
+		Some(parsed) => parsed,
+		None => {
+			warn!(target: LOG_SERVER, "Malformed response head from upstream {} for {}", upstream, request.resource);
+			return Response::new(502, String::from("The upstream server didn't answer properly."));
+		}
+	};
+
+	let mut response = Response{code, mime: String::from("application/octet-stream"), body: Vec::new(), headers: Headers::new(), use_error_page: false, stream: None};
+	let mut content_length: Option<u64> = None;
+	let mut chunked = false;
+	for (name, value) in headers
+	{
+		let lower = name.to_ascii_lowercase();
+		if lower == "content-type"
+		{
+			response.mime = value;
+		}else if lower == "content-length"{
+			content_length = value.parse().ok();
+		}else if lower == "transfer-encoding"{
+			chunked = value.to_ascii_lowercase().contains("chunked");
+		}else if !HOP_BY_HOP.contains(&lower.as_str()){
+			response.headers.append(name, value);
+		}
+	}
+
+	//these never have a body, whatever their headers say
+	if request.method == "HEAD" || code < 200 || code == 204 || code == 304
+	{
+		if let Some(len) = content_length.filter(|_| request.method == "HEAD")
+		{
+			//describes the body a GET would get, and send_head leaves it out
+			response.stream = Some(Box::new(UpstreamStream{upstream: connection, received: Vec::new(), len}));
+		}
+		return response;
+	}
+	match content_length.filter(|_| !chunked)
+	{
+		Some(len) => {response.stream = Some(Box::new(UpstreamStream{upstream: connection, received, len}));},
+		None => match read_rest(connection, received, max_buffered_bytes).and_then(|raw| if chunked {decode_chunked(&raw)} else {Ok(raw)})
+		{
+			Ok(body) => {response.body = body;},
+			Err(e) => {
+				warn!(target: LOG_SERVER, "Couldn't read the body from upstream {} for {}: {}", upstream, request.resource, e);
+				return Response::new(502, String::from("The upstream server didn't answer properly."));
+			}
+		}
+	}
+	response
+}
+
+/**
+Open a connection to the upstream server.
+
+# Parameters
+- `upstream`: Address of the upstream server
+- `timeout`: Limit on connecting and on each read and write, or None to wait indefinitely
+
+# Returns
+The connection, or the error from the last address tried
+*/
+fn connect(upstream: &str, timeout: Option<Duration>) -> io::Result<TcpStream>
+{
+	let mut last_error = io::Error::new(io::ErrorKind::NotFound, "address doesn't resolve");
+	for addr in upstream.to_socket_addrs()?
+	{
+		let attempt = match timeout
+		{
+			Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+			None => TcpStream::connect(addr)
+		};
+		match attempt
+		{
+			Ok(connection) => {
+				connection.set_read_timeout(timeout)?;
+				connection.set_write_timeout(timeout)?;
+				return Ok(connection);
+			},
+			Err(e) => {last_error = e;}
+		}
+	}
+	Err(last_error)
+}
+
+/**
+# Returns
+The head of the request to send upstream: the client's request line and
+end-to-end headers, asking for the connection to be closed afterward. Headers
+the client listed in `Connection` are hop-by-hop too, so they're left out.
+*/
+fn request_head(request: &Request, upstream: &str) -> Vec<u8>
+{
+	let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.resource);
+	let connection_specific = request.connection_tokens();
+	let mut names: Vec<&String> = request.headers.keys().collect();
+	names.sort();
+	for name in names
+	{
+		if !HOP_BY_HOP.contains(&name.as_str()) && !connection_specific.contains(name) && name != "content-length"
+		{
+			head.push_str(&format!("{}: {}\r\n", name, request.headers[name]));
+		}
+	}
+	if !request.headers.contains_key("host")
+	{
+		head.push_str(&format!("host: {}\r\n", upstream));
+	}
+	head.push_str("connection: close\r\n\r\n");
+	head.into_bytes()
+}
+
+/**
+Read the upstream response head.
+
+# Returns
+The head, and whatever part of the body arrived along with it
+*/
+fn read_head(connection: &mut TcpStream, max_bytes: usize) -> io::Result<(Vec<u8>, Vec<u8>)>
+{
+	let mut data = Vec::new();
+	let mut chunk = vec![0u8; 4096];
+	loop
+	{
+		if let Some(end) = find_head_end(&data)
+		{
+			let body = data.split_off(end);
+			return Ok((data, body));
+		}
+		if data.len() >= max_bytes
+		{
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too large"));
+		}
+		match connection.read(&mut chunk)
+		{
+			Ok(0) => {return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the response head ended"));},
+			Ok(n) => data.extend_from_slice(&chunk[..n]),
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+			Err(e) => {return Err(e);}
+		}
+	}
+}
+
+/**
+Parse the status line and headers of an upstream response.
+
+# Returns
+The status code and the headers in the order they came, or None if the head is malformed
+*/
+fn parse_head(head: &[u8]) -> Option<(u16, Vec<(String, String)>)>
+{
+	let text = std::str::from_utf8(head).ok()?;
+	let mut lines = text.split('\n').map(|l| l.trim_end_matches('\r'));
+	let mut status = lines.next()?.splitn(3, ' ');
+	if !status.next()?.starts_with("HTTP/")
+	{
+		return None;
+	}
+	let code = status.next()?.parse::<u16>().ok().filter(|code| (100..600).contains(code))?;
+	let mut headers = Vec::new();
+	for line in lines.take_while(|l| !l.is_empty())
+	{
+		let colon = line.find(':')?;
+		headers.push((String::from(line[..colon].trim()), String::from(line[(colon+1)..].trim())));
+	}
+	Some((code, headers))
+}
+
+/**
+Read everything the upstream server sends until it closes the connection.
+
+# Parameters
+- `connection`: The upstream connection
+- `received`: Body bytes already read
+- `max_bytes`: The most to hold in memory, or 0 for no limit
+*/
+fn read_rest(connection: TcpStream, mut received: Vec<u8>, max_bytes: usize) -> io::Result<Vec<u8>>
+{
+	let limit = if max_bytes == 0 {u64::MAX} else {max_bytes as u64 + 1};
+	connection.take(limit.saturating_sub(received.len() as u64)).read_to_end(&mut received)?;
+	if max_bytes != 0 && received.len() > max_bytes
+	{
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("body larger than proxy_max_buffered_bytes ({})", max_bytes)));
+	}
+	Ok(received)
+}
+
+/**
+Undo chunked transfer coding, ignoring any chunk extensions and trailers.
+*/
+fn decode_chunked(raw: &[u8]) -> io::Result<Vec<u8>>
+{
+	let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body");
+	let mut body = Vec::new();
+	let mut pos = 0;
+	loop
+	{
+		let line_end = raw[pos..].windows(2).position(|w| w == b"\r\n").ok_or_else(malformed)? + pos;
+		let size_text = std::str::from_utf8(&raw[pos..line_end]).map_err(|_| malformed())?;
+		let size = usize::from_str_radix(size_text.split(';').next().unwrap_or("").trim(), 16).map_err(|_| malformed())?;
+		pos = line_end + 2;
+		if size == 0
+		{
+			return Ok(body);
+		}
+		if raw.len() < pos + size + 2
+		{
+			return Err(malformed());
+		}
+		body.extend_from_slice(&raw[pos..(pos + size)]);
+		pos += size + 2;
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn connection_specific_headers_stay_behind()
+	{
+		let raw = b"GET /app HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Session-Hint\r\nX-Session-Hint: abc\r\nAccept: */*\r\n\r\n";
+		let request = Request::parse(Box::new(raw.to_owned())).ok().unwrap();
+		let head = String::from_utf8(request_head(&request, "127.0.0.1:9000")).unwrap();
+		assert_eq!(head, "GET /app HTTP/1.1\r\naccept: */*\r\nhost: example.com\r\nconnection: close\r\n\r\n");
+	}
+
+	#[test]
+	fn chunked_bodies_are_decoded()
+	{
+		assert_eq!(decode_chunked(b"5\r\nhello\r\n6;x=y\r\n world\r\n0\r\n\r\n").unwrap(), b"hello world".to_vec());
+		assert!(decode_chunked(b"5\r\nhel").is_err());
+		assert!(decode_chunked(b"zz\r\n").is_err());
+	}
+}
@@ -0,0 +1,122 @@
+/**
+What a `Range` request header asks for, relative to a body of known length.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange
+{
+	/// The header can't be honored as a single byte range, so the whole body should be sent
+	Full,
+	/// The first and last byte positions (inclusive) to send
+	Partial(usize, usize),
+	/// The range lies entirely outside the body
+	Unsatisfiable
+}
+
+impl ByteRange
+{
+	/**
+	Interpret a `Range` header against a body of the given length. Only a
+	single range in bytes is supported; anything else, including malformed
+	headers and multiple ranges, is answered with the full body as RFC 7233
+	allows.
+
+	# Parameters
+	- `header`: Value of the request's `Range` header
+	- `len`: Length of the complete body in bytes
+
+	# Returns
+	The part of the body to send
+
+	# Examples
+	```
+	use c20web::range::ByteRange;
+
+	assert_eq!(ByteRange::parse("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+	assert_eq!(ByteRange::parse("bytes=900-", 1000), ByteRange::Partial(900, 999));
+	assert_eq!(ByteRange::parse("bytes=-100", 1000), ByteRange::Partial(900, 999));
+	assert_eq!(ByteRange::parse("bytes=950-2000", 1000), ByteRange::Partial(950, 999));
+	assert_eq!(ByteRange::parse("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+	assert_eq!(ByteRange::parse("bytes=0-1,5-9", 1000), ByteRange::Full);
+	assert_eq!(ByteRange::parse("lines=1-2", 1000), ByteRange::Full);
+	```
+	*/
+	pub fn parse(header: &str, len: usize) -> ByteRange
+	{
+		let header = header.trim();
+		//compared as bytes, since a header that isn't ASCII may not split at a character boundary there
+		if header.len() < 6 || !header.as_bytes()[..6].eq_ignore_ascii_case(b"bytes=")
+		{
+			return ByteRange::Full;
+		}
+		let spec = header[6..].trim();
+		if spec.contains(',')
+		{
+			return ByteRange::Full;
+		}
+		let (first, last) = match spec.find('-')
+		{
+			Some(index) => (spec[..index].trim(), spec[(index+1)..].trim()),
+			None => {return ByteRange::Full;}
+		};
+
+		if first.is_empty()
+		{
+			//suffix range: the last N bytes
+			let suffix: usize = match last.parse()
+			{
+				Ok(n) => n,
+				Err(_) => {return ByteRange::Full;}
+			};
+			if suffix == 0 || len == 0
+			{
+				return ByteRange::Unsatisfiable;
+			}
+			return ByteRange::Partial(len.saturating_sub(suffix), len - 1);
+		}
+
+		let start: usize = match first.parse()
+		{
+			Ok(n) => n,
+			Err(_) => {return ByteRange::Full;}
+		};
+		let end: usize = if last.is_empty()
+		{
+			usize::MAX
+		}else{
+			match last.parse()
+			{
+				Ok(n) => n,
+				Err(_) => {return ByteRange::Full;}
+			}
+		};
+		if end < start
+		{
+			return ByteRange::Full;
+		}
+		if start >= len
+		{
+			return ByteRange::Unsatisfiable;
+		}
+		ByteRange::Partial(start, end.min(len - 1))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn malformed_ranges_fall_back_to_full()
+	{
+		assert_eq!(ByteRange::parse("bytes=abc-def", 10), ByteRange::Full);
+		assert_eq!(ByteRange::parse("bytes=5-2", 10), ByteRange::Full);
+		assert_eq!(ByteRange::parse("bytes=", 10), ByteRange::Full);
+		assert_eq!(ByteRange::parse("BYTES=2-3", 10), ByteRange::Partial(2, 3));
+		assert_eq!(ByteRange::parse("bytes=-0", 10), ByteRange::Unsatisfiable);
+		assert_eq!(ByteRange::parse("bytes=-20", 10), ByteRange::Partial(0, 9));
+		assert_eq!(ByteRange::parse("bytes=0-", 0), ByteRange::Unsatisfiable);
+		assert_eq!(ByteRange::parse("bytesé=0-1", 10), ByteRange::Full);
+		assert_eq!(ByteRange::parse("byté=0-1", 10), ByteRange::Full);
+	}
+}
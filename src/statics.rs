@@ -1,13 +1,43 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
 use config::Config;
 
+use crate::buffers::BufferPool;
+use crate::cache::FileCache;
+use crate::handlers::HandlerRegistry;
+use crate::limits::CountingLimiter;
+use crate::metrics::{InMemoryMetrics, MetricsSink};
+
 lazy_static!
 {
-	pub static ref DEFAULT_CONFIG: String = String::from("listen_addr = \"127.0.0.1:7878\"\nworking_dir = \"data\"\nwebroot = \"webroot\"\nthreads_max = 100\nrequest_max_bytes = 1000");
+	pub static ref DEFAULT_CONFIG: String = String::from("include = []\nlisten_addr = \"127.0.0.1:7878\"\nreuse_address = true\nreuse_port = false\nbind_retries = 0\nbind_retry_delay_ms = 500\nworking_dir = \"data\"\nwebroot = \"webroot\"\nwebroot_snapshot = false\nstrict_config = false\nextension_roots = {}\nhealth_check_path = \"\"\nmaintenance_mode = false\npublic_scheme = \"\"\npublic_host = \"\"\npublic_port = 0\nforce_https = false\nhttps_port = 443\nredirects = {}\nabsolute_redirects = false\nthreads_max = 100\nmax_queued_connections = 0\nrequest_max_bytes = 1000\nread_buffer_bytes = 4096\nread_buffer_pool_size = 0\nrequest_body_drain_max_bytes = 65536\nmax_body_bytes = 0\nreject_get_bodies = false\nreject_trailing_bytes = false\nupload_path = \"\"\nupload_token = \"\"\nupload_max_bytes = 10485760\nhandler_body_max_bytes = 1048576\nrequest_body_spool_bytes = 0\nrequest_body_spool_dir = \"\"\ndelete_directories = false\natomic_uploads = true\nexpect_continue_max_bytes = 0\nmax_connections_per_ip = 0\nmax_path_segments = 0\nallowed_methods = [\"GET\", \"HEAD\"]\ncors_allowed_origins = []\ncors_max_age = 0\nnot_found_page = \"\"\nnot_found_status = 404\ngzip = true\nbrotli = true\ngzip_level = 6\nbrotli_level = 6\ncompress_mime_types = []\ncompress_error_pages = false\nattachment_extensions = []\nattachment_paths = []\nindex_files = [\"index.html\"]\nroot_page = \"\"\nroot_html = \"\"\nblocked_extensions = []\nblock_dotfiles = false\ncase_insensitive_paths = false\nblock_control_characters = true\nblock_windows_paths = true\nblock_double_encoding = true\nrobots_txt = \"\"\nrobots_txt_path = \"\"\nsecurity_txt = \"\"\nsecurity_txt_path = \"\"\nhtml_snippet = \"\"\nhtml_snippet_before = \"</head>\"\nacme_challenge_dir = \"\"\nretry_after_503 = \"\"\nretry_after_429 = \"\"\nkeepalive_timeout_ms = 5000\nkeepalive_max_requests = 100\nmax_keepalive_connections = 0\nrequest_timeout_ms = 30000\nheader_timeout_ms = 0\nslow_request_threshold_ms = 0\ncontent_language_from_filename = false\nlanguage_negotiation = false\nranges = true\nfile_cache_max_bytes = 0\nfile_cache_max_file_bytes = 1048576\nstream_threshold_bytes = 0\nstream_threshold_mime_types = {}\nstream_chunk_bytes = 65536\nmax_open_files = 0\nopen_file_wait_ms = 100\ndirectory_index_files = []\ndirectory_mime_types = []\ndirectory_charsets = []\ndetect_utf8_bom = false\nstrip_utf8_bom = false\nlegal_blocks = []\nheaders = {}\nstatus_codes = {}\naccess_log_format = \"simple\"\nlog_sample_rate = 1\naccess_log_buffer_entries = 0\naccess_log_flush_ms = 1000\naccess_log_levels = {1xx = \"info\", 2xx = \"info\", 3xx = \"info\", 4xx = \"warn\", 5xx = \"error\"}\nproxy_upstream = \"\"\nproxy_timeout_ms = 10000\nproxy_max_buffered_bytes = 8388608");
 
 	pub static ref SETTINGS: RwLock<Config> = RwLock::new(Config::default());
 
+	pub static ref CONNECTIONS_PER_IP: CountingLimiter<IpAddr> = CountingLimiter::new();
+
+	pub static ref FILE_CACHE: FileCache = FileCache::new();
+
+	pub static ref OPEN_FILES: CountingLimiter<()> = CountingLimiter::new();
+
+	pub static ref KEEPALIVE_CONNECTIONS: CountingLimiter<()> = CountingLimiter::new();
+
+	/// Webroot directories opened by `capture_webroot`, kept open while files are served from them
+	pub static ref CAPTURED_WEBROOTS: Mutex<Vec<std::fs::File>> = Mutex::new(Vec::new());
+
+	/// Read buffers kept for reuse, when `read_buffer_pool_size` is nonzero
+	pub static ref READ_BUFFERS: BufferPool = BufferPool::new();
+
+	/// Reason phrases from the `status_codes` setting, which take precedence over HTTP_RESPONSE_TABLE
+	pub static ref CUSTOM_STATUS_CODES: RwLock<HashMap<u16, String>> = RwLock::new(HashMap::new());
+
+	pub static ref HANDLERS: RwLock<HandlerRegistry> = RwLock::new(HandlerRegistry::new());
+
+	pub static ref REQUEST_COUNTERS: Arc<InMemoryMetrics> = Arc::new(InMemoryMetrics::new());
+
+	pub static ref METRICS_SINK: RwLock<Arc<dyn MetricsSink>> = RwLock::new(Arc::clone(&REQUEST_COUNTERS) as Arc<dyn MetricsSink>);
+
 	pub static ref MIME_BY_EXTENSION: HashMap<String, String> = {
 		let mut types = HashMap::<String, String>::new();
 		types.insert(String::from("323"), String::from("text/h323"));
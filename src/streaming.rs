@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::Mutex;
+
+use crate::limits::LimiterSlot;
+
+/// Size of the buffer each streamed file is copied through, unless `stream_chunk_bytes` says otherwise
+pub const STREAM_CHUNK_BYTES: usize = 65536;
+
+/**
+A response body that's copied to the client while the response is being
+sent, instead of being held in memory first.
+*/
+pub trait BodyStream
+{
+	/**
+	# Returns
+	The number of bytes this body will send, which goes in the Content-Length header.
+	*/
+	fn len(&self) -> u64;
+
+	/**
+	# Returns
+	true if this body sends nothing at all.
+	*/
+	fn is_empty(&self) -> bool
+	{
+		self.len() == 0
+	}
+
+	/**
+	# Returns
+	true if the length isn't known until the body has been sent, so it goes
+	out with chunked transfer coding and `len` is ignored.
+	*/
+	fn is_chunked(&self) -> bool
+	{
+		false
+	}
+
+	/**
+	Copy the body to a stream.
+
+	# Parameters
+	- `out`: Where to write the body
+
+	# Returns
+	Number of bytes written, or the error that stopped the copy
+	*/
+	fn write_to(&self, out: &mut dyn Write) -> io::Result<u64>;
+}
+
+/**
+A response body that's copied from an open file while the response is being
+sent, instead of being read into memory first.
+*/
+pub struct FileStream
+{
+	file: File,
+	start: u64,
+	len: u64,
+	/// Size of the buffer the file is copied through
+	chunk_bytes: usize,
+	/// Slot in the open file limit, held until the body has been sent
+	_slot: Option<LimiterSlot<'static, ()>>
+}
+
+impl FileStream
+{
+	/**
+	# Parameters
+	- `file`: The open file
+	- `start`: Offset of the first byte to send
+	- `len`: Number of bytes to send
+	- `slot`: Slot in the open file limit to hold for as long as the file is open
+
+	# Returns
+	A body that sends `len` bytes of the file starting at `start`
+	*/
+	pub fn new(file: File, start: u64, len: u64, slot: Option<LimiterSlot<'static, ()>>) -> FileStream
+	{
+		FileStream{file, start, len, chunk_bytes: STREAM_CHUNK_BYTES, _slot: slot}
+	}
+
+	/**
+	Copy the file through a buffer of a different size than STREAM_CHUNK_BYTES.
+	Bigger buffers take fewer system calls, smaller ones less memory per stream.
+
+	# Parameters
+	- `chunk_bytes`: Size of the buffer, at least 1
+
+	# Returns
+	The same body, using the new buffer size
+	*/
+	pub fn with_chunk_bytes(mut self, chunk_bytes: usize) -> FileStream
+	{
+		self.chunk_bytes = chunk_bytes.max(1);
+		self
+	}
+
+	/**
+	# Returns
+	The number of bytes this body will send.
+	*/
+	pub fn len(&self) -> u64
+	{
+		self.len
+	}
+
+	/**
+	# Returns
+	true if this body sends nothing at all.
+	*/
+	pub fn is_empty(&self) -> bool
+	{
+		self.len == 0
+	}
+
+	/**
+	Copy the body to a stream.
+
+	# Parameters
+	- `out`: Where to write the body
+
+	# Returns
+	Number of bytes written, or the error that stopped the copy. A file that
+	got shorter since it was opened is an error, since the Content-Length
+	already promised the client more.
+
+	# Examples
+	```
+	use std::fs::File;
+	use c20web::streaming::FileStream;
+
+	let body = FileStream::new(File::open("data/webroot/hello.html").unwrap(), 0, 10, None);
+	let mut out = Vec::new();
+	assert_eq!(body.write_to(&mut out).unwrap(), 10);
+	assert_eq!(out, std::fs::read("data/webroot/hello.html").unwrap()[..10].to_vec());
+	```
+	*/
+	pub fn write_to<W: Write>(&self, mut out: W) -> io::Result<u64>
+	{
+		let mut file = &self.file;
+		file.seek(SeekFrom::Start(self.start))?;
+		//one buffer for the whole transfer, no bigger than the part being sent
+		let mut chunk = vec![0u8; (self.chunk_bytes as u64).min(self.len) as usize];
+		let mut remaining = self.len;
+		while remaining > 0
+		{
+			let want = remaining.min(chunk.len() as u64) as usize;
+			let num_bytes = match file.read(&mut chunk[..want])
+			{
+				Ok(0) => {return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ended before the promised length"));},
+				Ok(n) => n,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {continue;},
+				Err(e) => {return Err(e);}
+			};
+			out.write_all(&chunk[..num_bytes])?;
+			remaining -= num_bytes as u64;
+		}
+		Ok(self.len)
+	}
+}
+
+impl BodyStream for FileStream
+{
+	fn len(&self) -> u64
+	{
+		FileStream::len(self)
+	}
+
+	fn write_to(&self, out: &mut dyn Write) -> io::Result<u64>
+	{
+		FileStream::write_to(self, out)
+	}
+}
+
+/**
+A response body made as it's sent, from an iterator of chunks, like the
+output of a handler generating a large page. The size isn't known ahead of
+time, so it's sent with chunked transfer coding.
+*/
+pub struct ChunkedStream
+{
+	/// Taken by the first `write_to`, since the chunks can only be produced once
+	chunks: Mutex<Option<Box<dyn Iterator<Item = Vec<u8>> + Send>>>
+}
+
+impl ChunkedStream
+{
+	/**
+	# Parameters
+	- `chunks`: The pieces of the body, in order, produced only as they're needed
+
+	# Returns
+	A body that sends each chunk as it's produced
+	*/
+	pub fn new(chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>) -> ChunkedStream
+	{
+		ChunkedStream{chunks: Mutex::new(Some(chunks))}
+	}
+}
+
+impl BodyStream for ChunkedStream
+{
+	fn len(&self) -> u64
+	{
+		0
+	}
+
+	fn is_chunked(&self) -> bool
+	{
+		true
+	}
+
+	/**
+	Write the chunks' bytes, without any transfer coding. A second call writes nothing.
+	*/
+	fn write_to(&self, out: &mut dyn Write) -> io::Result<u64>
+	{
+		let chunks = self.chunks.lock().unwrap_or_else(|e| e.into_inner()).take();
+		let mut written = 0;
+		for chunk in chunks.into_iter().flatten()
+		{
+			out.write_all(&chunk)?;
+			written += chunk.len() as u64;
+		}
+		Ok(written)
+	}
+}
+
+/**
+Send a body with chunked transfer coding: each write the body makes becomes
+one chunk, followed by the empty chunk that ends the body.
+
+# Parameters
+- `body`: The body to send
+- `out`: Where to write it
+
+# Returns
+Number of bytes written, framing included
+
+# Examples
+```
+use c20web::streaming::{write_chunked, ChunkedStream};
+
+let body = ChunkedStream::new(Box::new(vec![b"Hello".to_vec(), Vec::new(), b", world".to_vec()].into_iter()));
+let mut out = Vec::new();
+write_chunked(&body, &mut out).unwrap();
+assert_eq!(out, b"5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n".to_vec());
+```
+*/
+pub fn write_chunked(body: &dyn BodyStream, out: &mut dyn Write) -> io::Result<u64>
+{
+	let mut framed = ChunkWriter{out, written: 0};
+	body.write_to(&mut framed)?;
+	framed.out.write_all(b"0\r\n\r\n")?;
+	Ok(framed.written + 5)
+}
+
+/// Frames everything written through it as chunks
+struct ChunkWriter<'a>
+{
+	out: &'a mut dyn Write,
+	written: u64
+}
+
+impl Write for ChunkWriter<'_>
+{
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+	{
+		//an empty chunk would end the body early
+		if buf.is_empty()
+		{
+			return Ok(0);
+		}
+		let size = format!("{:x}\r\n", buf.len());
+		self.out.write_all(size.as_bytes())?;
+		self.out.write_all(buf)?;
+		self.out.write_all(b"\r\n")?;
+		self.written += (size.len() + buf.len() + 2) as u64;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()>
+	{
+		self.out.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn small_chunks_send_the_whole_range()
+	{
+		let original = std::fs::read("data/webroot/hello.jpg").unwrap();
+		let body = FileStream::new(File::open("data/webroot/hello.jpg").unwrap(), 3, original.len() as u64 - 3, None).with_chunk_bytes(7);
+		let mut out = Vec::new();
+		assert_eq!(body.write_to(&mut out).unwrap(), original.len() as u64 - 3);
+		assert_eq!(out, original[3..].to_vec());
+
+		let mut out = Vec::new();
+		FileStream::new(File::open("data/webroot/hello.jpg").unwrap(), 0, 10, None).with_chunk_bytes(0).write_to(&mut out).unwrap();
+		assert_eq!(out, original[..10].to_vec());
+	}
+}
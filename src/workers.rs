@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+use std::panic;
+use std::thread;
+
+use log::error;
+use threadpool::ThreadPool;
+
+use crate::LOG_SERVER;
+
+/// Name given to every thread in the connection pool
+pub const WORKER_THREAD_NAME: &str = "c20web-worker";
+
+thread_local!
+{
+	/// Description of the request the current thread is working on, for panic messages
+	static REQUEST_CONTEXT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/**
+Create the pool of threads that connections are handed off to. The threads
+are named so that logs and panic messages show they're request workers.
+
+# Parameters
+- `threads_max`: Size of the thread pool
+
+# Returns
+The new thread pool
+
+# Examples
+```
+use std::sync::mpsc::channel;
+use c20web::workers::{build_pool, WORKER_THREAD_NAME};
+
+let pool = build_pool(2);
+let (tx, rx) = channel();
+pool.execute(move || {tx.send(std::thread::current().name().map(String::from)).unwrap();});
+assert_eq!(rx.recv().unwrap(), Some(String::from(WORKER_THREAD_NAME)));
+```
+*/
+pub fn build_pool(threads_max: usize) -> ThreadPool
+{
+	threadpool::Builder::new()
+		.num_threads(threads_max)
+		.thread_name(String::from(WORKER_THREAD_NAME))
+		.build()
+}
+
+/**
+Create the pool of threads like `build_pool`, but survive the operating
+system refusing to create the threads, which the pool itself treats as a
+panic.
+
+# Parameters
+- `threads_max`: Size of the thread pool
+
+# Returns
+The new thread pool, or None if its threads couldn't be started
+*/
+pub fn try_build_pool(threads_max: usize) -> Option<ThreadPool>
+{
+	match panic::catch_unwind(|| build_pool(threads_max))
+	{
+		Ok(pool) => Some(pool),
+		Err(_) => {
+			error!(target: LOG_SERVER, "Couldn't start the {} worker threads; connections will be refused with 503", threads_max);
+			None
+		}
+	}
+}
+
+/**
+Route panics through the server log, naming the thread that panicked and the
+request it was working on, instead of only printing to stderr.
+*/
+pub fn install_panic_logger()
+{
+	let default_hook = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		let thread = thread::current();
+		let context = request_context();
+		let context = if context.is_empty() {String::from("no request")} else {context};
+		error!(target: LOG_SERVER, "Thread '{}' panicked while handling {}: {}", thread.name().unwrap_or("unnamed"), context, info);
+		default_hook(info);
+	}));
+}
+
+/**
+Record which request the current thread is about to process, so a panic
+while processing it can say so.
+
+# Parameters
+- `context`: Short description of the request, like its peer and request line
+*/
+pub fn set_request_context(context: String)
+{
+	REQUEST_CONTEXT.with(|c| *c.borrow_mut() = context);
+}
+
+/**
+Forget the request recorded by `set_request_context` once it's finished.
+*/
+pub fn clear_request_context()
+{
+	REQUEST_CONTEXT.with(|c| c.borrow_mut().clear());
+}
+
+/**
+# Returns
+The description of the request the current thread is working on, or the empty string if it isn't working on one
+*/
+pub fn request_context() -> String
+{
+	REQUEST_CONTEXT.with(|c| c.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::sync::mpsc::channel;
+
+	#[test]
+	fn request_context_is_per_thread()
+	{
+		let pool = build_pool(1);
+		let (tx, rx) = channel();
+		set_request_context(String::from("GET /outer"));
+		pool.execute(move || {
+			let before = request_context();
+			set_request_context(String::from("GET /inner"));
+			let during = request_context();
+			clear_request_context();
+			tx.send((before, during, request_context())).unwrap();
+		});
+		assert_eq!(rx.recv().unwrap(), (String::new(), String::from("GET /inner"), String::new()));
+		assert_eq!(request_context(), "GET /outer");
+		clear_request_context();
+	}
+}
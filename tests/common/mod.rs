@@ -0,0 +1,53 @@
+//helpers for tests that run the real binary; not every test file uses all of them
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Start the server with the given extra config in a fresh working directory, and connect to it once it's listening
+pub fn start_server(name: &str, config: &str) -> (Child, TcpStream)
+{
+	start_server_with_files(name, config, &[])
+}
+
+/// Same as `start_server`, with other files written to the working directory first
+pub fn start_server_with_files(name: &str, config: &str, files: &[(&str, &str)]) -> (Child, TcpStream)
+{
+	let dir = std::env::temp_dir().join(format!("c20web-test-{}-{}", std::process::id(), name));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+	let webroot = concat!(env!("CARGO_MANIFEST_DIR"), "/data/webroot");
+	fs::write(dir.join("web.toml"), format!("listen_addr = \"127.0.0.1:{}\"\nwebroot = {:?}\n{}", port, webroot, config)).unwrap();
+	for (name, contents) in files
+	{
+		fs::write(dir.join(name), contents).unwrap();
+	}
+
+	let mut server = Command::new(env!("CARGO_BIN_EXE_main"))
+		.arg("-d").arg(&dir)
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap();
+
+	let started = Instant::now();
+	loop
+	{
+		match TcpStream::connect(("127.0.0.1", port))
+		{
+			Ok(client) => return (server, client),
+			Err(_) if started.elapsed() < Duration::from_secs(10) => thread::sleep(Duration::from_millis(50)),
+			Err(e) => {let _ = server.kill(); panic!("server never started listening: {}", e);}
+		}
+	}
+}
+
+/// Send the server SIGTERM, as a service manager stopping it would
+pub fn terminate(server: &Child)
+{
+	assert!(Command::new("kill").arg("-TERM").arg(server.id().to_string()).status().unwrap().success());
+}
@@ -0,0 +1,26 @@
+//runs the real binary, since logging can only be set up once per process
+#![cfg(unix)]
+
+mod common;
+
+use std::io::prelude::*;
+
+use common::{start_server_with_files, terminate};
+
+#[test]
+fn broken_log4rs_yml_falls_back_to_the_console()
+{
+	let (server, mut client) = start_server_with_files("fallback-logging", "", &[("log4rs.yml", "appenders: [this isn't\n")]);
+	client.write_all(b"GET /hello.html?fallback HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+	let mut response = String::new();
+	client.read_to_string(&mut response).unwrap();
+	assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected response: {}", response);
+
+	terminate(&server);
+	let output = server.wait_with_output().unwrap();
+	let logged = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "server exited with {}", output.status);
+	assert!(logged.contains(" WARN c20web::server - Logging to the console with default settings because log4rs.yml couldn't be used"), "no fallback warning in: {}", logged);
+	assert!(logged.contains(" INFO c20web::server - Starting up."));
+	assert!(logged.contains(" INFO c20web::access - "), "access log not on the console: {}", logged);
+}
@@ -0,0 +1,52 @@
+//runs the real binary, since what's being tested is how the process reacts to a signal
+#![cfg(unix)]
+
+mod common;
+
+use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{start_server, terminate};
+
+#[test]
+fn sigterm_flushes_buffered_access_log()
+{
+	//a buffer big enough and a flush interval long enough that only shutting down writes the entry
+	let (server, mut client) = start_server("graceful-shutdown", "access_log_buffer_entries = 1000\naccess_log_flush_ms = 3600000");
+	client.write_all(b"GET /hello.html?graceful HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+	let mut response = String::new();
+	client.read_to_string(&mut response).unwrap();
+	assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected response: {}", response);
+
+	terminate(&server);
+	let output = server.wait_with_output().unwrap();
+	let logged = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "server exited with {}", output.status);
+	assert!(logged.contains("Response code: 200"), "access log entry lost: {}", logged);
+	assert!(logged.contains("Shutting down."));
+}
+
+#[test]
+fn sigterm_with_a_silent_client_still_stops()
+{
+	//the client connects and never sends anything, so only the request timeout frees its worker
+	let (mut server, _silent) = start_server("silent-client-shutdown", "request_timeout_ms = 500");
+	thread::sleep(Duration::from_millis(100));
+	terminate(&server);
+	let stopping = Instant::now();
+	let status = loop
+	{
+		if let Some(status) = server.try_wait().unwrap()
+		{
+			break status;
+		}
+		if stopping.elapsed() > Duration::from_secs(10)
+		{
+			let _ = server.kill();
+			panic!("server didn't stop while a silent client was connected");
+		}
+		thread::sleep(Duration::from_millis(50));
+	};
+	assert!(status.success(), "server exited with {}", status);
+}